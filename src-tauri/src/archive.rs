@@ -0,0 +1,662 @@
+//! Zip and tar archives used as file trees, read in place and written back as a whole.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{remove_file, rename, File},
+    io::{copy, empty, BufWriter, Cursor, Read, Seek, Write},
+    ops::{
+        Bound::{Included, Unbounded},
+        Range,
+    },
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use flate2::read::DeflateDecoder;
+use memmap2::Mmap;
+use tar::{EntryType, Header};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    error::{PatchError, PatchResult},
+    metadata::FileMetadata,
+    open_file,
+    tree::{prune_listing, FileTree, MemoryTree, NodeKind},
+};
+
+/// Kind of archive a tree is read from and saved as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+/// Where an entry of the archive comes from.
+#[derive(Clone, Debug)]
+enum EntrySource {
+    Zip {
+        index: usize,                   // Index of the entry in the zip file
+        compression: CompressionMethod, // How the contents are stored
+        encrypted: bool,                // Whether the contents are encrypted
+    },
+    Tar {
+        header: Box<Header>, // Header of the entry, kept when the entry is saved unchanged
+        link: Option<PathBuf>, // Target of a symlink or hard link
+    },
+    Implied, // Parent directory without an entry of its own
+}
+
+/// A file, directory or link stored in the archive.
+#[derive(Clone, Debug)]
+struct ArchiveNode {
+    kind: Option<NodeKind>, // `None` for links, which are kept as they are but not part of the tree
+    data: Range<usize>,     // Position of the stored contents in the archive file
+    size: u64,              // Size of the contents once decompressed
+    metadata: Option<FileMetadata>, // Permissions and modification time stored with the entry
+    source: EntrySource,
+}
+
+/// A zip or tar archive, read in place from a memory-mapped file.
+///
+/// Changes are held in memory on top of the archive and written by [`ArchiveTree::save`], the
+/// archive file itself is never modified. Entries left unchanged are copied as they are stored.
+/// Zip entries keep their permissions but not their modification time, tar entries keep both.
+/// Symlinks and hard links in the archive are kept but can't be read or changed.
+pub struct ArchiveTree {
+    path: PathBuf,                         // Archive file the tree is read from
+    format: ArchiveFormat,                 // Format of the archive, also used when saving
+    map: Mmap,                             // Contents of the archive file
+    nodes: BTreeMap<PathBuf, ArchiveNode>, // Entries of the archive and their implied parents
+    changes: MemoryTree, // Files and directories written since the archive was opened
+    removed: BTreeSet<PathBuf>, // Entries of the archive removed since it was opened
+}
+
+/// Turns the path of an archive entry into a relative path, refusing paths that could point
+/// outside of the archive. Returns `None` for the root.
+fn entry_path(name: &str) -> anyhow::Result<Option<PathBuf>> {
+    let mut rel_path = PathBuf::new();
+    for component in Path::new(&name.replace("\\", "/")).components() {
+        match component {
+            Component::Normal(part) => rel_path.push(part),
+            Component::CurDir => {}
+            _ => bail!("Refusing to read archive entry with unsafe path: {}", name),
+        }
+    }
+    Ok((!rel_path.as_os_str().is_empty()).then_some(rel_path))
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+/// Reads the entries of a zip file.
+fn read_zip_nodes(map: &[u8]) -> anyhow::Result<Vec<(PathBuf, ArchiveNode)>> {
+    let mut archive = ZipArchive::new(Cursor::new(map)).context("Failed to read zip file")?;
+    let mut nodes = Vec::new();
+
+    for index in 0..archive.len() {
+        let file = archive
+            .by_index_raw(index)
+            .context("Failed to read zip file entry")?;
+        let Some(rel_path) = entry_path(file.name())? else {
+            continue;
+        };
+
+        let start = file.data_start() as usize;
+        let end = start.saturating_add(file.compressed_size() as usize);
+        ensure!(
+            end <= map.len(),
+            "Zip file entry is truncated: {}",
+            file.name()
+        );
+        let kind = if file.is_dir() {
+            Some(NodeKind::Dir)
+        } else if file.is_symlink() {
+            None
+        } else {
+            Some(NodeKind::File)
+        };
+
+        nodes.push((
+            rel_path,
+            ArchiveNode {
+                kind,
+                data: start..end,
+                size: file.size(),
+                metadata: file.unix_mode().map(|mode| FileMetadata {
+                    mode: Some(mode & 0o7777),
+                    mtime: None,
+                }),
+                source: EntrySource::Zip {
+                    index,
+                    compression: file.compression(),
+                    encrypted: file.encrypted(),
+                },
+            },
+        ));
+    }
+
+    Ok(nodes)
+}
+
+/// Reads the entries of a tar file.
+fn read_tar_nodes(map: &[u8]) -> anyhow::Result<Vec<(PathBuf, ArchiveNode)>> {
+    let mut archive = tar::Archive::new(map);
+    let mut nodes = Vec::new();
+
+    for entry in archive.entries().context("Failed to read tar file")? {
+        let entry = entry.context("Failed to read tar file entry")?;
+        let name = entry.path().context("Invalid tar file entry path")?;
+        let name = name.to_string_lossy().to_string();
+        let Some(rel_path) = entry_path(&name)? else {
+            continue;
+        };
+
+        let header = entry.header().clone();
+        let (kind, link) = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => (Some(NodeKind::File), None),
+            EntryType::Directory => (Some(NodeKind::Dir), None),
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .context("Invalid tar file link target")?
+                    .with_context(|| format!("Tar file link has no target: {}", name))?;
+                (None, Some(target.into_owned()))
+            }
+            EntryType::XGlobalHeader => continue,
+            entry_type => bail!("Unsupported tar file entry {:?}: {}", entry_type, name),
+        };
+
+        let start = entry.raw_file_position() as usize;
+        let size = entry.size();
+        let end = start.saturating_add(size as usize);
+        ensure!(end <= map.len(), "Tar file entry is truncated: {}", name);
+        let metadata = FileMetadata {
+            mode: Some(header.mode().context("Invalid tar file entry mode")? & 0o7777),
+            mtime: header
+                .mtime()
+                .context("Invalid tar file entry modification time")?
+                .checked_mul(1_000_000_000),
+        };
+
+        nodes.push((
+            rel_path,
+            ArchiveNode {
+                kind,
+                data: start..end,
+                size,
+                metadata: Some(metadata),
+                source: EntrySource::Tar {
+                    header: Box::new(header),
+                    link,
+                },
+            },
+        ));
+    }
+
+    Ok(nodes)
+}
+
+impl ArchiveTree {
+    /// Opens the zip or tar archive at `archive_loc`. The format is recognized from the contents
+    /// of the file, not its name.
+    pub fn open(archive_loc: &Path) -> PatchResult<Self> {
+        let file = open_file(archive_loc)?;
+        // SAFETY: the map is only read through bounds checked slices. Archives are not written
+        // while they are read, `save` writes a new file and moves it into place.
+        let map = unsafe { Mmap::map(file.get_ref()) }
+            .with_context(|| format!("Failed to map archive: {}", archive_loc.display()))?;
+
+        let format = if map.starts_with(b"PK\x03\x04") || map.starts_with(b"PK\x05\x06") {
+            ArchiveFormat::Zip
+        } else if map.get(257..262) == Some(b"ustar") {
+            ArchiveFormat::Tar
+        } else {
+            return Err(anyhow!(
+                "Unsupported archive format (expected zip or tar): {}",
+                archive_loc.display()
+            )
+            .into());
+        };
+        let entries = match format {
+            ArchiveFormat::Zip => read_zip_nodes(&map),
+            ArchiveFormat::Tar => read_tar_nodes(&map),
+        }
+        .with_context(|| format!("Failed to read archive: {}", archive_loc.display()))?;
+
+        let mut nodes = BTreeMap::new();
+        for (rel_path, node) in entries {
+            for parent in rel_path.ancestors().skip(1) {
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+                nodes.entry(parent.to_path_buf()).or_insert(ArchiveNode {
+                    kind: Some(NodeKind::Dir),
+                    data: 0..0,
+                    size: 0,
+                    metadata: None,
+                    source: EntrySource::Implied,
+                });
+            }
+            // Later entries replace earlier ones with the same path, like when extracting
+            nodes.insert(rel_path, node);
+        }
+        for (rel_path, node) in &nodes {
+            let holds_entries = nodes
+                .range::<Path, _>((Included(rel_path.as_path()), Unbounded))
+                .nth(1)
+                .is_some_and(|(next, _)| next.starts_with(rel_path));
+            if node.kind != Some(NodeKind::Dir) && holds_entries {
+                return Err(anyhow!(
+                    "Archive holds entries inside non-directory {}: {}",
+                    rel_path.display(),
+                    archive_loc.display()
+                )
+                .into());
+            }
+        }
+
+        Ok(Self {
+            path: archive_loc.to_path_buf(),
+            format,
+            map,
+            nodes,
+            changes: MemoryTree::new(),
+            removed: BTreeSet::new(),
+        })
+    }
+
+    /// Returns the archive file the tree is read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the entry of the archive at `rel_path`, unless it was removed since.
+    fn base_node(&self, rel_path: &Path) -> Option<&ArchiveNode> {
+        if self.removed.contains(rel_path) {
+            return None;
+        }
+        self.nodes.get(rel_path)
+    }
+
+    /// Returns the entry of the archive holding the file at `rel_path`, if the file wasn't
+    /// written since.
+    fn base_file(&self, rel_path: &Path) -> anyhow::Result<Option<&ArchiveNode>> {
+        match self.kind(rel_path)? {
+            Some(NodeKind::File) if self.changes.kind(rel_path)?.is_none() => {
+                Ok(self.base_node(rel_path))
+            }
+            Some(NodeKind::File) => Ok(None),
+            Some(NodeKind::Dir) => bail!("Not a file: {}", rel_path.display()),
+            None => bail!("File not found: {}", rel_path.display()),
+        }
+    }
+
+    /// Checks that none of the parents of `rel_path` is a file.
+    fn check_parents(&self, rel_path: &Path) -> anyhow::Result<()> {
+        for parent in rel_path.ancestors().skip(1) {
+            match self.kind(parent)? {
+                Some(NodeKind::File) => bail!("Parent of {} is a file", rel_path.display()),
+                Some(NodeKind::Dir) => break,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether anything is in the directory at `rel_path`, including links.
+    fn has_children(&self, rel_path: &Path) -> anyhow::Result<bool> {
+        let in_base = self
+            .nodes
+            .range::<Path, _>((Included(rel_path), Unbounded))
+            .skip(1)
+            .take_while(|(path, _)| path.starts_with(rel_path))
+            .any(|(path, _)| !self.removed.contains(path));
+        let in_changes = self
+            .changes
+            .list(&|_, _| false)?
+            .iter()
+            .any(|(path, _)| path != rel_path && path.starts_with(rel_path));
+        Ok(in_base || in_changes)
+    }
+
+    /// Writes the tree to an archive at `out_loc` in the format it was read from. The archive is
+    /// written next to `out_loc` first, so saving over the opened archive never loses it.
+    pub fn save(&self, out_loc: &Path) -> PatchResult<()> {
+        let mut tmp_name = out_loc.as_os_str().to_owned();
+        tmp_name.push(".save");
+        let tmp_loc = Path::new(&tmp_name);
+
+        let result = File::create(tmp_loc)
+            .map_err(|source| PatchError::io(tmp_loc, source))
+            .and_then(|file| {
+                let out = BufWriter::new(file);
+                let written = match self.format {
+                    ArchiveFormat::Zip => self.write_zip(out),
+                    ArchiveFormat::Tar => self.write_tar(out),
+                };
+                Ok(written
+                    .with_context(|| format!("Failed to write archive: {}", out_loc.display()))?)
+            })
+            .and_then(|_| {
+                rename(tmp_loc, out_loc).map_err(|source| PatchError::io(out_loc, source))
+            });
+
+        if result.is_err() {
+            let _ = remove_file(tmp_loc);
+        }
+        result
+    }
+
+    /// Lists what is saved: entries of the archive that are neither removed nor written since,
+    /// with their node, and the files and directories written since, without one. Implied
+    /// directories are only listed if they end up empty, since they are implied otherwise.
+    fn saved_entries(&self) -> anyhow::Result<Vec<(PathBuf, Option<&ArchiveNode>)>> {
+        let mut entries: BTreeMap<PathBuf, Option<&ArchiveNode>> = self
+            .nodes
+            .iter()
+            .filter(|(rel_path, _)| !self.removed.contains(*rel_path))
+            .map(|(rel_path, node)| (rel_path.clone(), Some(node)))
+            .collect();
+        for (rel_path, _) in self.changes.list(&|_, _| false)? {
+            entries.insert(rel_path, None);
+        }
+
+        let entries: Vec<_> = entries.into_iter().collect();
+        Ok(entries
+            .iter()
+            .enumerate()
+            .filter(|(index, (rel_path, node))| {
+                !matches!(
+                    node,
+                    Some(ArchiveNode {
+                        source: EntrySource::Implied,
+                        ..
+                    })
+                ) || !entries
+                    .get(index + 1)
+                    .is_some_and(|(next, _)| next.starts_with(rel_path))
+            })
+            .map(|(_, entry)| entry.clone())
+            .collect())
+    }
+
+    /// Writes the tree as a zip file. Unchanged entries are copied without recompressing them,
+    /// new files are deflated.
+    fn write_zip(&self, out: impl Write + Seek) -> anyhow::Result<()> {
+        let mut source =
+            ZipArchive::new(Cursor::new(&self.map[..])).context("Failed to read zip file")?;
+        let mut zip = ZipWriter::new(out);
+
+        for (rel_path, node) in self.saved_entries()? {
+            let name = rel_path.to_string_lossy().replace("\\", "/");
+            match node {
+                Some(ArchiveNode {
+                    kind: None,
+                    metadata,
+                    source: EntrySource::Zip { index, .. },
+                    ..
+                }) => {
+                    // Copying the entry as it is stored would turn the link into a file
+                    let target = read_zip_link(&mut source, *index)?;
+                    let mut options = SimpleFileOptions::default();
+                    if let Some(mode) = metadata.as_ref().and_then(|metadata| metadata.mode) {
+                        options = options.unix_permissions(mode);
+                    }
+                    zip.add_symlink(name, target, options)
+                }
+                Some(ArchiveNode {
+                    source: EntrySource::Zip { index, .. },
+                    ..
+                }) => {
+                    let file = source
+                        .by_index_raw(*index)
+                        .context("Failed to read zip file entry")?;
+                    zip.raw_copy_file(file)
+                }
+                Some(_) => zip.add_directory(name, SimpleFileOptions::default()),
+                None if self.changes.kind(&rel_path)? == Some(NodeKind::Dir) => {
+                    zip.add_directory(name, SimpleFileOptions::default())
+                }
+                None => {
+                    let mut options = SimpleFileOptions::default()
+                        .compression_method(CompressionMethod::Deflated);
+                    if let Some(mode) = self
+                        .changes
+                        .metadata(&rel_path)?
+                        .and_then(|metadata| metadata.mode)
+                    {
+                        options = options.unix_permissions(mode);
+                    }
+                    let mut contents = self.changes.open(&rel_path)?;
+                    zip.start_file(name, options).and_then(|_| {
+                        copy(&mut contents, &mut zip)?;
+                        Ok(())
+                    })
+                }
+            }
+            .with_context(|| format!("Failed to write zip file entry: {}", rel_path.display()))?;
+        }
+
+        zip.finish()
+            .context("Failed to finish zip file")?
+            .flush()
+            .context("Failed to flush zip file")
+    }
+
+    /// Writes the tree as a tar file. Unchanged entries keep their headers.
+    fn write_tar(&self, out: impl Write) -> anyhow::Result<()> {
+        let mut builder = tar::Builder::new(out);
+
+        for (rel_path, node) in self.saved_entries()? {
+            match node {
+                Some(ArchiveNode {
+                    data,
+                    source: EntrySource::Tar { header, link },
+                    ..
+                }) => match link {
+                    Some(target) => {
+                        builder.append_link(&mut header.as_ref().clone(), &rel_path, target)
+                    }
+                    None => builder.append_data(
+                        &mut header.as_ref().clone(),
+                        &rel_path,
+                        &self.map[data.clone()],
+                    ),
+                },
+                Some(_) => append_tar_dir(&mut builder, &rel_path),
+                None if self.changes.kind(&rel_path)? == Some(NodeKind::Dir) => {
+                    append_tar_dir(&mut builder, &rel_path)
+                }
+                None => {
+                    let metadata = self.changes.metadata(&rel_path)?;
+                    let mut header = Header::new_gnu();
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(self.changes.size(&rel_path)?);
+                    header.set_mode(
+                        metadata
+                            .as_ref()
+                            .and_then(|meta| meta.mode)
+                            .unwrap_or(0o644),
+                    );
+                    header.set_mtime(
+                        metadata
+                            .and_then(|meta| meta.mtime)
+                            .map_or_else(now, |mtime| mtime / 1_000_000_000),
+                    );
+                    builder.append_data(&mut header, &rel_path, self.changes.open(&rel_path)?)
+                }
+            }
+            .with_context(|| format!("Failed to write tar file entry: {}", rel_path.display()))?;
+        }
+
+        builder
+            .into_inner()
+            .and_then(|mut out| out.flush())
+            .context("Failed to finish tar file")
+    }
+}
+
+/// Reads the target of the symlink stored as entry `index` of a zip file.
+fn read_zip_link(
+    source: &mut ZipArchive<impl Read + Seek>,
+    index: usize,
+) -> anyhow::Result<String> {
+    let mut target = String::new();
+    source
+        .by_index(index)
+        .context("Failed to read zip file entry")?
+        .read_to_string(&mut target)
+        .context("Failed to read zip file link target")?;
+    Ok(target)
+}
+
+/// Appends a directory entry to a tar file.
+fn append_tar_dir(builder: &mut tar::Builder<impl Write>, rel_path: &Path) -> std::io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_mtime(now());
+    builder.append_data(&mut header, rel_path, empty())
+}
+
+impl FileTree for ArchiveTree {
+    fn list(
+        &self,
+        skip: &dyn Fn(&Path, NodeKind) -> bool,
+    ) -> PatchResult<Vec<(PathBuf, NodeKind)>> {
+        let mut listing: BTreeMap<PathBuf, NodeKind> = self
+            .nodes
+            .iter()
+            .filter(|(rel_path, _)| !self.removed.contains(*rel_path))
+            .filter_map(|(rel_path, node)| node.kind.map(|kind| (rel_path.clone(), kind)))
+            .collect();
+        listing.extend(self.changes.list(&|_, _| false)?);
+        Ok(prune_listing(listing, skip))
+    }
+
+    fn kind(&self, rel_path: &Path) -> PatchResult<Option<NodeKind>> {
+        if let Some(kind) = self.changes.kind(rel_path)? {
+            return Ok(Some(kind));
+        }
+        match self.base_node(rel_path) {
+            Some(ArchiveNode {
+                kind: Some(kind), ..
+            }) => Ok(Some(*kind)),
+            Some(_) => {
+                Err(anyhow!("Refusing to use link in archive: {}", rel_path.display()).into())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size(&self, rel_path: &Path) -> PatchResult<u64> {
+        match self.base_file(rel_path)? {
+            Some(node) => Ok(node.size),
+            None => self.changes.size(rel_path),
+        }
+    }
+
+    fn metadata(&self, rel_path: &Path) -> PatchResult<Option<FileMetadata>> {
+        match self.base_file(rel_path)? {
+            Some(node) => Ok(node.metadata.clone()),
+            None => self.changes.metadata(rel_path),
+        }
+    }
+
+    fn open(&self, rel_path: &Path) -> PatchResult<Box<dyn Read + '_>> {
+        let Some(node) = self.base_file(rel_path)? else {
+            return self.changes.open(rel_path);
+        };
+
+        let data = &self.map[node.data.clone()];
+        match &node.source {
+            EntrySource::Zip {
+                encrypted: true, ..
+            } => Err(anyhow!(
+                "Encrypted archive entries are not supported: {}",
+                rel_path.display()
+            )
+            .into()),
+            EntrySource::Zip {
+                compression: CompressionMethod::Stored,
+                ..
+            } => Ok(Box::new(data)),
+            EntrySource::Zip {
+                compression: CompressionMethod::Deflated,
+                ..
+            } => Ok(Box::new(DeflateDecoder::new(data))),
+            EntrySource::Zip { compression, .. } => Err(anyhow!(
+                "Unsupported compression {:?} of archive entry: {}",
+                compression,
+                rel_path.display()
+            )
+            .into()),
+            _ => Ok(Box::new(data)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        rel_path: &Path,
+        contents: &mut dyn Read,
+        metadata: Option<&FileMetadata>,
+    ) -> PatchResult<()> {
+        if self.kind(rel_path)? == Some(NodeKind::Dir) {
+            return Err(PatchError::NotAFile {
+                path: rel_path.to_path_buf(),
+                entry: None,
+            });
+        }
+        self.check_parents(rel_path)?;
+        self.changes.write(rel_path, contents, metadata)
+    }
+
+    fn remove_file(&mut self, rel_path: &Path) -> PatchResult<()> {
+        if self.kind(rel_path)? != Some(NodeKind::File) {
+            return Err(anyhow!("File not found: {}", rel_path.display()).into());
+        }
+        if self.changes.kind(rel_path)?.is_some() {
+            self.changes.remove_file(rel_path)?;
+        }
+        if self.nodes.contains_key(rel_path) {
+            self.removed.insert(rel_path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        match self.kind(rel_path)? {
+            Some(NodeKind::Dir) => Ok(()),
+            Some(NodeKind::File) => Err(PatchError::NotADirectory {
+                path: rel_path.to_path_buf(),
+                entry: None,
+            }),
+            None => {
+                self.check_parents(rel_path)?;
+                self.changes.create_dir(rel_path)
+            }
+        }
+    }
+
+    fn remove_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        if self.kind(rel_path)? != Some(NodeKind::Dir) {
+            return Err(anyhow!("Not a directory: {}", rel_path.display()).into());
+        }
+        if self.has_children(rel_path)? {
+            return Err(anyhow!("Directory is not empty: {}", rel_path.display()).into());
+        }
+        if self.changes.kind(rel_path)?.is_some() {
+            self.changes.remove_dir(rel_path)?;
+        }
+        if self.nodes.contains_key(rel_path) {
+            self.removed.insert(rel_path.to_path_buf());
+        }
+        Ok(())
+    }
+}
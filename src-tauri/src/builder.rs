@@ -0,0 +1,413 @@
+//! Putting patch packages together change by change, without two trees to compare.
+
+use std::{
+    collections::BTreeMap,
+    ops::Bound::{Excluded, Unbounded},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, ensure};
+use files_diff::hash;
+
+use crate::{
+    error::PatchResult,
+    hunks::{TextHunks, MAX_TEXT_SIZE},
+    info::PackageInfo,
+    metadata::FileMetadata,
+    stream::PatchWriter,
+    verify::check_rel_path,
+    write_chunk_deltas, write_data_chunks, write_patch_file, CreateOptions, PatchEntry,
+    PatchOperation, CHUNK_SIZE,
+};
+
+/// How a change touches a path, which decides what can be changed inside it.
+#[derive(Clone, Copy, PartialEq)]
+enum PathRole {
+    Written,    // File is added, modified or renamed to
+    Removed,    // File is removed or renamed from
+    AddedDir,   // Directory is added
+    RemovedDir, // Directory is removed
+}
+
+/// Change to a single path of a [`PatchBuilder`].
+enum BuiltChange {
+    Add {
+        contents: Vec<u8>,
+    },
+    Remove {
+        before: Option<Vec<u8>>,
+    },
+    Modify {
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    Rename {
+        from: PathBuf,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    AddDir,
+    RemoveDir,
+}
+
+/// A change with the metadata of the file it writes.
+struct BuiltEntry {
+    change: BuiltChange,
+    metadata: Option<FileMetadata>, // Metadata of the new file, if set
+}
+
+/// Returns whether a path changed as `inner` can be inside a directory changed as `outer`.
+/// Added directories can only be filled and removed directories only emptied.
+fn can_nest(outer: PathRole, inner: PathRole) -> bool {
+    matches!(
+        (outer, inner),
+        (PathRole::AddedDir, PathRole::Written | PathRole::AddedDir)
+            | (
+                PathRole::RemovedDir,
+                PathRole::Removed | PathRole::RemovedDir
+            )
+    )
+}
+
+/// Returns the path of an entry the way it is written to a package.
+fn entry_path(rel_path: &Path) -> String {
+    rel_path.to_string_lossy().replace("\\", "/")
+}
+
+/// Builds a patch package from changes to single paths, for tools that produce the new contents
+/// themselves instead of a tree to compare against. Every change is checked as it is added: paths
+/// must stay inside the target, each path can only be changed once, and changes inside added or
+/// removed directories must fit them.
+///
+/// Only [`CreateOptions::reversible`], [`CreateOptions::metadata`],
+/// [`CreateOptions::text_hunks`], the storage strategies and the package info options apply.
+/// The old permissions and modification times of the files it changes aren't known, so
+/// reverting a built package keeps the current ones of modified and renamed files and gives
+/// restored files default ones.
+pub struct PatchBuilder {
+    options: CreateOptions,
+    entries: BTreeMap<PathBuf, BuiltEntry>, // Changes by the path they write or remove
+    roles: BTreeMap<PathBuf, PathRole>,     // Every path touched, including rename sources
+}
+
+impl Default for PatchBuilder {
+    fn default() -> Self {
+        Self::new(CreateOptions::default())
+    }
+}
+
+impl PatchBuilder {
+    /// Creates a builder for a package without any changes.
+    pub fn new(options: CreateOptions) -> Self {
+        Self {
+            options,
+            entries: BTreeMap::new(),
+            roles: BTreeMap::new(),
+        }
+    }
+
+    /// Checks that `rel_path` is safe, not changed yet and can be changed as `role` given the
+    /// changes around it. Returns the normalized path.
+    fn check_claim(&self, rel_path: &str, role: PathRole) -> anyhow::Result<PathBuf> {
+        let path = check_rel_path(rel_path)?;
+        ensure!(
+            !self.roles.contains_key(&path),
+            "Path is already changed by the patch: {}",
+            path.display()
+        );
+
+        for parent in path.ancestors().skip(1) {
+            if let Some(&outer) = self.roles.get(parent) {
+                ensure!(
+                    can_nest(outer, role),
+                    "Change to {} conflicts with the change to {}",
+                    path.display(),
+                    parent.display()
+                );
+            }
+        }
+        let conflict = self
+            .roles
+            .range::<Path, _>((Excluded(path.as_path()), Unbounded))
+            .take_while(|(inner_path, _)| inner_path.starts_with(&path))
+            .find(|(_, &inner)| !can_nest(role, inner));
+        if let Some((inner_path, _)) = conflict {
+            bail!(
+                "Change to {} conflicts with the change to {}",
+                path.display(),
+                inner_path.display()
+            );
+        }
+
+        Ok(path)
+    }
+
+    /// Records `change` to the path returned by [`Self::check_claim`].
+    fn insert(&mut self, path: PathBuf, role: PathRole, change: BuiltChange) -> &mut Self {
+        self.roles.insert(path.clone(), role);
+        self.entries.insert(
+            path,
+            BuiltEntry {
+                change,
+                metadata: None,
+            },
+        );
+        self
+    }
+
+    /// Adds a file holding `contents`, replacing the file at `rel_path` if there is one.
+    pub fn add(&mut self, rel_path: &str, contents: impl Into<Vec<u8>>) -> PatchResult<&mut Self> {
+        let path = self.check_claim(rel_path, PathRole::Written)?;
+        Ok(self.insert(
+            path,
+            PathRole::Written,
+            BuiltChange::Add {
+                contents: contents.into(),
+            },
+        ))
+    }
+
+    /// Removes the file at `rel_path`. Its current contents `before` are only needed by
+    /// reversible packages, which store them to restore it, and can be left out otherwise.
+    pub fn remove(&mut self, rel_path: &str, before: Option<Vec<u8>>) -> PatchResult<&mut Self> {
+        let path = self.check_claim(rel_path, PathRole::Removed)?;
+        Ok(self.insert(path, PathRole::Removed, BuiltChange::Remove { before }))
+    }
+
+    /// Turns the file at `rel_path` from `before` into `after`. Applying the package fails if the
+    /// file doesn't hold `before`.
+    pub fn modify(
+        &mut self,
+        rel_path: &str,
+        before: impl Into<Vec<u8>>,
+        after: impl Into<Vec<u8>>,
+    ) -> PatchResult<&mut Self> {
+        let path = self.check_claim(rel_path, PathRole::Written)?;
+        Ok(self.insert(
+            path,
+            PathRole::Written,
+            BuiltChange::Modify {
+                before: before.into(),
+                after: after.into(),
+            },
+        ))
+    }
+
+    /// Moves the file at `from` holding `before` to `to`, where it holds `after`. Pass the same
+    /// contents for both to move the file as is.
+    pub fn rename(
+        &mut self,
+        from: &str,
+        to: &str,
+        before: impl Into<Vec<u8>>,
+        after: impl Into<Vec<u8>>,
+    ) -> PatchResult<&mut Self> {
+        let from_path = self.check_claim(from, PathRole::Removed)?;
+        let to_path = self.check_claim(to, PathRole::Written)?;
+        if from_path.starts_with(&to_path) || to_path.starts_with(&from_path) {
+            return Err(anyhow!(
+                "Can't rename {} to {}",
+                from_path.display(),
+                to_path.display()
+            )
+            .into());
+        }
+
+        self.roles.insert(from_path.clone(), PathRole::Removed);
+        Ok(self.insert(
+            to_path,
+            PathRole::Written,
+            BuiltChange::Rename {
+                from: from_path,
+                before: before.into(),
+                after: after.into(),
+            },
+        ))
+    }
+
+    /// Adds the directory at `rel_path`. Directories holding added files are created anyway, this
+    /// is only needed for empty ones.
+    pub fn add_dir(&mut self, rel_path: &str) -> PatchResult<&mut Self> {
+        let path = self.check_claim(rel_path, PathRole::AddedDir)?;
+        Ok(self.insert(path, PathRole::AddedDir, BuiltChange::AddDir))
+    }
+
+    /// Removes the directory at `rel_path`, which must be empty once the files in it the package
+    /// removes are gone.
+    pub fn remove_dir(&mut self, rel_path: &str) -> PatchResult<&mut Self> {
+        let path = self.check_claim(rel_path, PathRole::RemovedDir)?;
+        Ok(self.insert(path, PathRole::RemovedDir, BuiltChange::RemoveDir))
+    }
+
+    /// Sets the metadata the file added, modified or renamed to `rel_path` is given. Recorded
+    /// unless [`CreateOptions::metadata`] is off.
+    pub fn set_metadata(
+        &mut self,
+        rel_path: &str,
+        metadata: FileMetadata,
+    ) -> PatchResult<&mut Self> {
+        let path = check_rel_path(rel_path)?;
+        match self.entries.get_mut(&path) {
+            Some(
+                entry @ BuiltEntry {
+                    change:
+                        BuiltChange::Add { .. }
+                        | BuiltChange::Modify { .. }
+                        | BuiltChange::Rename { .. },
+                    ..
+                },
+            ) => entry.metadata = Some(metadata),
+            _ => {
+                return Err(
+                    anyhow!("No file is written by the patch at: {}", path.display()).into(),
+                )
+            }
+        }
+        Ok(self)
+    }
+
+    /// Writes the package to `patch_loc`, in the same format [`crate::create_patch`] writes.
+    ///
+    /// The builder doesn't know the tree the package applies to, so no base tree hash is
+    /// recorded: applying it checks the files it changes against their old contents instead of
+    /// the whole tree. Fails if the package is reversible and the contents of a removed file
+    /// weren't given.
+    pub fn write(&self, patch_loc: &Path) -> PatchResult<()> {
+        for (path, entry) in &self.entries {
+            match &entry.change {
+                BuiltChange::Modify { before, after }
+                    if before == after && entry.metadata.is_none() =>
+                {
+                    return Err(anyhow!("Modified file is unchanged: {}", path.display()).into());
+                }
+                BuiltChange::Remove { before: None } if self.options.reversible => {
+                    return Err(anyhow!(
+                        "Patch is reversible, but the contents of the removed file are unknown: {}",
+                        path.display()
+                    )
+                    .into());
+                }
+                _ => {}
+            }
+        }
+
+        let info = PackageInfo::without_base_tree(&self.options);
+        Ok(write_patch_file(patch_loc, &info, CHUNK_SIZE, |writer| {
+            // Files first, then added and removed directories, like packages created from trees
+            for (path, entry) in &self.entries {
+                self.write_file_entry(writer, path, entry)?;
+            }
+            for (path, entry) in &self.entries {
+                if let BuiltChange::AddDir = entry.change {
+                    writer
+                        .write_entry(&PatchEntry::new(PatchOperation::AddDir, entry_path(path)))?;
+                }
+            }
+            for (path, entry) in &self.entries {
+                if let BuiltChange::RemoveDir = entry.change {
+                    writer.write_entry(&PatchEntry::new(
+                        PatchOperation::RemoveDir,
+                        entry_path(path),
+                    ))?;
+                }
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Writes the entry and contents of a file change, doing nothing for directories.
+    fn write_file_entry(
+        &self,
+        writer: &mut PatchWriter<impl std::io::Write>,
+        path: &Path,
+        entry: &BuiltEntry,
+    ) -> anyhow::Result<()> {
+        let options = &self.options;
+        let metadata = entry.metadata.clone().filter(|_| options.metadata);
+
+        match &entry.change {
+            BuiltChange::Add { contents } => {
+                writer.write_entry(
+                    &PatchEntry::new(
+                        PatchOperation::Add {
+                            hash: hash(contents),
+                        },
+                        entry_path(path),
+                    )
+                    .with_metadata(metadata),
+                )?;
+                let strategy = options.strategy_for(path, contents.len() as u64);
+                write_data_chunks(writer, &mut contents.as_slice(), path, strategy.compression)?;
+            }
+            BuiltChange::Remove { before } => {
+                writer.write_entry(&PatchEntry::new(PatchOperation::Remove, entry_path(path)))?;
+                if let Some(before) = before.as_ref().filter(|_| options.reversible) {
+                    writer.write_inverse(&hash(before), None)?;
+                    let strategy = options.strategy_for(path, before.len() as u64);
+                    write_data_chunks(writer, &mut before.as_slice(), path, strategy.compression)?;
+                }
+            }
+            BuiltChange::Modify { before, after } | BuiltChange::Rename { before, after, .. } => {
+                let before_hash = hash(before);
+                let after_hash = hash(after);
+                let contents_changed = before_hash != after_hash;
+                let operation = match &entry.change {
+                    BuiltChange::Rename { from, .. } => PatchOperation::Rename {
+                        from: entry_path(from),
+                        before_hash: before_hash.clone(),
+                        after_hash,
+                    },
+                    _ => PatchOperation::Modify {
+                        before_hash: before_hash.clone(),
+                        after_hash,
+                    },
+                };
+                let text_hunks = if options.text_hunks
+                    && contents_changed
+                    && matches!(operation, PatchOperation::Modify { .. })
+                    && before.len() as u64 <= MAX_TEXT_SIZE
+                    && after.len() as u64 <= MAX_TEXT_SIZE
+                {
+                    TextHunks::diff(before, after)
+                } else {
+                    None
+                };
+
+                writer.write_entry(
+                    &PatchEntry::new(operation, entry_path(path))
+                        .with_metadata(metadata)
+                        .with_text_hunks(text_hunks),
+                )?;
+                let strategy = options.strategy_for(path, after.len() as u64);
+                if contents_changed {
+                    write_chunk_deltas(
+                        writer,
+                        &mut before.as_slice(),
+                        &mut after.as_slice(),
+                        path,
+                        strategy,
+                        PatchWriter::write_delta,
+                    )?;
+                }
+
+                if options.reversible {
+                    // The old metadata isn't known, reverting keeps the current one
+                    writer.write_inverse(&before_hash, None)?;
+                    if contents_changed {
+                        write_chunk_deltas(
+                            writer,
+                            &mut after.as_slice(),
+                            &mut before.as_slice(),
+                            path,
+                            strategy,
+                            PatchWriter::write_reverse_delta,
+                        )?;
+                    }
+                }
+            }
+            BuiltChange::AddDir | BuiltChange::RemoveDir => {}
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+use std::path::PathBuf;
+
+use clap::{Subcommand, ValueEnum};
+use colored::Colorize;
+use polylauncher::{
+    apply_patch_to, apply_patch_with_options, export_unified_diff, import_unified_diff,
+    read_package_info, read_patch_entry, sign_patch, squash_patches, upgrade_patch, verify_patch,
+    ApplyOptions, CreateOptions, EntryChange, EntryFilter, EntryKind, PatchResult, SigningKey,
+    VerifyingKey,
+};
+
+use crate::error::PolyResult;
+
+/// Kind of patch entry selected by `pl-cli patch apply --only`
+#[derive(Clone, Copy, ValueEnum)]
+pub enum EntryKindArg {
+    Added,
+    Removed,
+    Modified,
+    Renamed,
+    AddedDir,
+    RemovedDir,
+}
+
+impl From<EntryKindArg> for EntryKind {
+    fn from(kind: EntryKindArg) -> Self {
+        match kind {
+            EntryKindArg::Added => EntryKind::Added,
+            EntryKindArg::Removed => EntryKind::Removed,
+            EntryKindArg::Modified => EntryKind::Modified,
+            EntryKindArg::Renamed => EntryKind::Renamed,
+            EntryKindArg::AddedDir => EntryKind::AddedDir,
+            EntryKindArg::RemovedDir => EntryKind::RemovedDir,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum PatchCommands {
+    /// Apply a patch package to a directory, or only some of its entries
+    Apply {
+        #[arg(help = "The patch package to apply.")]
+        patch: PathBuf,
+        #[arg(help = "The directory to apply the patch to.")]
+        target: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "A public key the package may be signed with. Can be given several times."
+        )]
+        key: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "Also apply unsigned packages and packages signed with other keys."
+        )]
+        allow_untrusted: bool,
+        #[arg(
+            short,
+            long,
+            help = "Only apply entries whose path matches this gitignore style pattern. Can be given several times."
+        )]
+        include: Vec<String>,
+        #[arg(
+            short,
+            long,
+            help = "Skip entries whose path matches this gitignore style pattern. Can be given several times."
+        )]
+        exclude: Vec<String>,
+        #[arg(
+            long,
+            value_enum,
+            help = "Only apply entries of this kind. Can be given several times."
+        )]
+        only: Vec<EntryKindArg>,
+        #[arg(
+            long,
+            help = "Only apply the entry for this relative path. Can be given several times."
+        )]
+        path: Vec<String>,
+        #[arg(
+            long,
+            help = "Patch text files that changed since the package was made from their text hunks."
+        )]
+        fuzzy: bool,
+        #[arg(
+            long,
+            help = "Apply to a target that isn't the tree the package was made from, e.g. to apply the rest of a partly applied package."
+        )]
+        ignore_base_tree: bool,
+        #[arg(
+            long,
+            help = "The PolyTrack version the target holds. Read from the target's name when it is an installed version."
+        )]
+        polytrack_version: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "Write the patched tree to this new directory and leave the target as it is."
+        )]
+        output: Option<PathBuf>,
+    },
+    /// Squash consecutive patch packages into a single package
+    Squash {
+        #[arg(help = "The directory the first patch applies to.")]
+        base: PathBuf,
+        #[arg(
+            required = true,
+            help = "The patch packages to squash, in the order they apply."
+        )]
+        patches: Vec<PathBuf>,
+        #[arg(short, long, help = "Where to write the squashed patch package.")]
+        output: PathBuf,
+        #[arg(long, help = "Record what is needed to revert the squashed patch.")]
+        reversible: bool,
+    },
+    /// Show the information recorded in a patch package
+    Info {
+        #[arg(help = "The patch package to inspect.")]
+        patch: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Show what the package does to this relative path instead."
+        )]
+        entry: Option<String>,
+    },
+    /// Check a patch package for corruption without applying it
+    Verify {
+        #[arg(help = "The patch package to verify.")]
+        patch: PathBuf,
+    },
+    /// Generate a key pair for signing patch packages
+    Keygen {
+        #[arg(
+            help = "Where to write the secret key. The public key is written next to it with a .pub extension."
+        )]
+        output: PathBuf,
+    },
+    /// Sign a patch package, replacing any previous signature
+    Sign {
+        #[arg(help = "The patch package to sign.")]
+        patch: PathBuf,
+        #[arg(short, long, help = "The secret key to sign with.")]
+        key: PathBuf,
+    },
+    /// Rewrite a patch package from an older version in the newest format
+    Upgrade {
+        #[arg(help = "The patch package to upgrade.")]
+        patch: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Where to write the upgraded patch package. Defaults to replacing the original."
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "The secret key to sign the upgraded package with."
+        )]
+        key: Option<PathBuf>,
+    },
+    /// Write a patch package as a git style unified diff for review
+    Export {
+        #[arg(help = "The patch package to export.")]
+        patch: PathBuf,
+        #[arg(help = "The directory the patch applies to.")]
+        base: PathBuf,
+        #[arg(short, long, help = "Where to write the diff.")]
+        output: PathBuf,
+        #[arg(
+            long,
+            help = "Leave the contents of binary files out, only noting that they differ."
+        )]
+        no_binary: bool,
+    },
+    /// Create a patch package from a git style unified diff
+    Import {
+        #[arg(help = "The diff to import.")]
+        diff: PathBuf,
+        #[arg(help = "The directory the diff applies to.")]
+        base: PathBuf,
+        #[arg(short, long, help = "Where to write the patch package.")]
+        output: PathBuf,
+        #[arg(long, help = "Record what is needed to revert the patch.")]
+        reversible: bool,
+    },
+}
+
+/// Handle the patch command - works with patch packages
+pub fn handle_patch(command: PatchCommands) -> PolyResult<()> {
+    match command {
+        PatchCommands::Apply {
+            patch,
+            target,
+            key,
+            allow_untrusted,
+            include,
+            exclude,
+            only,
+            path,
+            fuzzy,
+            ignore_base_tree,
+            polytrack_version,
+            output,
+        } => handle_apply(
+            patch,
+            target,
+            key,
+            ApplyOptions {
+                allow_untrusted,
+                polytrack_version,
+                ignore_base_tree,
+                fuzzy,
+                filter: EntryFilter {
+                    include,
+                    exclude,
+                    paths: (!path.is_empty()).then_some(path),
+                    kinds: (!only.is_empty())
+                        .then(|| only.into_iter().map(EntryKind::from).collect()),
+                },
+                ..Default::default()
+            },
+            output,
+        ),
+        PatchCommands::Squash {
+            base,
+            patches,
+            output,
+            reversible,
+        } => handle_squash(base, patches, output, reversible),
+        PatchCommands::Info { patch, entry } => match entry {
+            Some(rel_path) => handle_entry_info(patch, rel_path),
+            None => handle_info(patch),
+        },
+        PatchCommands::Verify { patch } => handle_verify(patch),
+        PatchCommands::Keygen { output } => handle_keygen(output),
+        PatchCommands::Sign { patch, key } => handle_sign(patch, key),
+        PatchCommands::Upgrade { patch, output, key } => handle_upgrade(patch, output, key),
+        PatchCommands::Export {
+            patch,
+            base,
+            output,
+            no_binary,
+        } => handle_export(patch, base, output, no_binary),
+        PatchCommands::Import {
+            diff,
+            base,
+            output,
+            reversible,
+        } => handle_import(diff, base, output, reversible),
+    }
+}
+
+/// Apply a patch package, or the entries of it selected by `options.filter`, in place or into
+/// `output`
+fn handle_apply(
+    patch: PathBuf,
+    target: PathBuf,
+    keys: Vec<PathBuf>,
+    options: ApplyOptions,
+    output: Option<PathBuf>,
+) -> PolyResult<()> {
+    let trusted_keys = keys
+        .iter()
+        .map(|key| VerifyingKey::read(key))
+        .collect::<PatchResult<Vec<_>>>()?;
+
+    let options = ApplyOptions {
+        trusted_keys,
+        ..options
+    };
+    let report = match &output {
+        Some(output) => apply_patch_to(&target, &patch, output, &options),
+        None => apply_patch_with_options(&patch, &target, &options),
+    }?;
+
+    let written_to = output.as_ref().unwrap_or(&target);
+    println!(
+        "{}",
+        format!("✓ Applied {} to {}", patch.display(), written_to.display())
+            .green()
+            .bold()
+    );
+    for file in &report.fuzzy {
+        println!(
+            "{}",
+            format!(
+                "Patched {} from its text hunks, {} of {} hunks rejected",
+                file.rel_path,
+                file.rejected(),
+                file.hunks.len()
+            )
+            .yellow()
+        );
+    }
+    if !report.skipped.is_empty() {
+        println!(
+            "{}",
+            format!("Skipped {} entries:", report.skipped.len()).yellow()
+        );
+        for rel_path in &report.skipped {
+            println!("  {}", rel_path);
+        }
+    }
+    if !report.already_applied.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "{} entries were already applied and left as they are",
+                report.already_applied.len()
+            )
+            .yellow()
+        );
+    }
+
+    Ok(())
+}
+
+/// Squash several patch packages into one
+fn handle_squash(
+    base: PathBuf,
+    patches: Vec<PathBuf>,
+    output: PathBuf,
+    reversible: bool,
+) -> PolyResult<()> {
+    println!(
+        "{}",
+        format!("Squashing {} patch packages...", patches.len())
+            .cyan()
+            .bold()
+    );
+
+    squash_patches(
+        &base,
+        &patches,
+        &output,
+        &CreateOptions {
+            reversible,
+            ..Default::default()
+        },
+    )?;
+
+    println!(
+        "{}",
+        format!("✓ Squashed patch written to {}", output.display())
+            .green()
+            .bold()
+    );
+
+    Ok(())
+}
+
+/// Show the package info of a patch package
+fn handle_info(patch: PathBuf) -> PolyResult<()> {
+    let Some(info) = read_package_info(&patch)? else {
+        println!(
+            "{}",
+            "This patch package was created before package info was recorded.".yellow()
+        );
+        return Ok(());
+    };
+
+    let unknown = || "unknown".to_string();
+    println!(
+        "{}",
+        format!("Patch package {}", patch.display()).cyan().bold()
+    );
+    println!(
+        "PolyTrack version: {}",
+        info.polytrack_version.unwrap_or_else(unknown)
+    );
+    println!("Description: {}", info.description.unwrap_or_else(unknown));
+    println!("Created with: PolyLauncher {}", info.tool_version);
+    match info.created_at {
+        0 => println!("Created at: unknown"),
+        created_at => println!("Created at: {} (Unix time)", created_at),
+    }
+    println!(
+        "Base tree hash: {}",
+        info.base_tree_hash.unwrap_or_else(unknown)
+    );
+    if !info.ignore.is_empty() {
+        println!("Left out of the hash: {}", info.ignore.join(", "));
+    }
+
+    let stats = info.stats;
+    println!(
+        "Files: {} added, {} modified, {} renamed, {} removed",
+        stats.added, stats.modified, stats.renamed, stats.removed
+    );
+    println!(
+        "Directories: {} added, {} removed",
+        stats.added_dirs, stats.removed_dirs
+    );
+    println!(
+        "Stored: {} bytes of file contents, {} bytes of diffs, {} bytes of revert data",
+        stats.data_size, stats.delta_size, stats.inverse_size
+    );
+
+    Ok(())
+}
+
+/// Show what a patch package does to a single path
+fn handle_entry_info(patch: PathBuf, rel_path: String) -> PolyResult<()> {
+    let Some(entry) = read_patch_entry(&patch, &rel_path)? else {
+        println!(
+            "{}",
+            format!("The patch package does not change {}.", rel_path).yellow()
+        );
+        return Ok(());
+    };
+
+    let change = match &entry.change {
+        EntryChange::Added => "added".to_string(),
+        EntryChange::Removed => "removed".to_string(),
+        EntryChange::Modified => "modified".to_string(),
+        EntryChange::Renamed { from } => format!("renamed from {}", from),
+        EntryChange::AddedDir => "directory added".to_string(),
+        EntryChange::RemovedDir => "directory removed".to_string(),
+    };
+    println!(
+        "{}",
+        format!("{}: {}", entry.rel_path, change).cyan().bold()
+    );
+    if let Some(before_hash) = entry.before_hash {
+        println!("Hash before: {}", before_hash);
+    }
+    if let Some(after_hash) = entry.after_hash {
+        println!("Hash after: {}", after_hash);
+    }
+
+    Ok(())
+}
+
+/// Verify the integrity of a patch package
+fn handle_verify(patch: PathBuf) -> PolyResult<()> {
+    let report = verify_patch(&patch)?;
+
+    println!(
+        "{}",
+        format!(
+            "✓ {} is intact: version {}, {} entries checked",
+            patch.display(),
+            report.version,
+            report.entries
+        )
+        .green()
+        .bold()
+    );
+    if !report.checksum {
+        println!(
+            "{}",
+            "The package predates checksums, so only its structure and file hashes were checked. Upgrade it with `pl-cli patch upgrade`."
+                .yellow()
+        );
+    }
+    match report.signed_by {
+        Some(key) => println!("Signed with key: {}", key),
+        None => println!("{}", "The package is not signed.".yellow()),
+    }
+
+    Ok(())
+}
+
+/// Generate a new signing key pair
+fn handle_keygen(output: PathBuf) -> PolyResult<()> {
+    let public_output = output.with_extension("pub");
+    let key = SigningKey::generate()?;
+    key.write(&output)
+        .and_then(|_| key.verifying_key().write(&public_output))?;
+
+    println!(
+        "{}",
+        format!("✓ Secret key written to {}", output.display())
+            .green()
+            .bold()
+    );
+    println!(
+        "{}",
+        format!("✓ Public key written to {}", public_output.display())
+            .green()
+            .bold()
+    );
+    println!("Public key: {}", key.verifying_key());
+    println!(
+        "{}",
+        "Keep the secret key private. Share the public key with the people applying your patches."
+            .yellow()
+    );
+
+    Ok(())
+}
+
+/// Sign a patch package
+fn handle_sign(patch: PathBuf, key: PathBuf) -> PolyResult<()> {
+    let key = SigningKey::read(&key)?;
+    sign_patch(&patch, &key)?;
+
+    println!(
+        "{}",
+        format!(
+            "✓ Signed {} with key {}",
+            patch.display(),
+            key.verifying_key()
+        )
+        .green()
+        .bold()
+    );
+
+    Ok(())
+}
+
+/// Upgrade a patch package to the newest format
+fn handle_upgrade(patch: PathBuf, output: Option<PathBuf>, key: Option<PathBuf>) -> PolyResult<()> {
+    let output = output.unwrap_or_else(|| patch.clone());
+    let key = key.map(|key| SigningKey::read(&key)).transpose()?;
+
+    let upgrade = upgrade_patch(&patch, &output)?;
+    println!(
+        "{}",
+        format!(
+            "✓ Upgraded {} from version {} to version {}, written to {}",
+            patch.display(),
+            upgrade.from_version,
+            upgrade.to_version,
+            output.display()
+        )
+        .green()
+        .bold()
+    );
+
+    match key {
+        Some(key) => {
+            sign_patch(&output, &key)?;
+            println!(
+                "{}",
+                format!("✓ Signed with key {}", key.verifying_key())
+                    .green()
+                    .bold()
+            );
+        }
+        None if upgrade.was_signed => println!(
+            "{}",
+            "The original signature no longer applies. Sign the upgraded package again with `pl-cli patch sign`."
+                .yellow()
+        ),
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Export a patch package as a unified diff
+fn handle_export(
+    patch: PathBuf,
+    base: PathBuf,
+    output: PathBuf,
+    no_binary: bool,
+) -> PolyResult<()> {
+    println!(
+        "{}",
+        format!("Exporting {}...", patch.display()).cyan().bold()
+    );
+
+    export_unified_diff(&patch, &base, &output, !no_binary)?;
+
+    println!(
+        "{}",
+        format!("✓ Diff written to {}", output.display())
+            .green()
+            .bold()
+    );
+
+    Ok(())
+}
+
+/// Turn a unified diff into a patch package
+fn handle_import(
+    diff: PathBuf,
+    base: PathBuf,
+    output: PathBuf,
+    reversible: bool,
+) -> PolyResult<()> {
+    println!(
+        "{}",
+        format!("Importing {}...", diff.display()).cyan().bold()
+    );
+
+    import_unified_diff(
+        &diff,
+        &base,
+        &output,
+        &CreateOptions {
+            reversible,
+            ..Default::default()
+        },
+    )?;
+
+    println!(
+        "{}",
+        format!("✓ Patch package written to {}", output.display())
+            .green()
+            .bold()
+    );
+
+    Ok(())
+}
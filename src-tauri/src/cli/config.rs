@@ -3,6 +3,8 @@ use std::{
     path::PathBuf,
 };
 
+use polylauncher::POLYTRACK_VERSIONS_DIR;
+
 use crate::error::{PolyError, PolyResult};
 
 /// Latest stable version of PolyTrack
@@ -28,7 +30,7 @@ pub fn get_polylauncher_dir() -> PolyResult<PathBuf> {
 /// Get the directory for a specific PolyTrack version
 pub fn get_version_dir(version: &str) -> PolyResult<PathBuf> {
     Ok(get_polylauncher_dir()?
-        .join("polytrack_versions")
+        .join(POLYTRACK_VERSIONS_DIR)
         .join(version))
 }
 
@@ -5,6 +5,8 @@ use std::{
     path::PathBuf,
 };
 
+use polylauncher::PatchError;
+
 /// Custom result type for PolyLauncher operations
 pub type PolyResult<T> = Result<T, PolyError>;
 
@@ -18,6 +20,7 @@ pub enum PolyError {
     DownloadError(String),
     HarNotFound(String),
     NonEmptyDir(PathBuf),
+    PatchError(String),
 }
 
 impl Display for PolyError {
@@ -34,6 +37,7 @@ impl Display for PolyError {
             PolyError::NonEmptyDir(path) => {
                 write!(f, "The directory '{}' is not empty", path.display())
             }
+            PolyError::PatchError(msg) => write!(f, "Patch error: {}", msg),
         }
     }
 }
@@ -57,3 +61,9 @@ impl From<serde_json::Error> for PolyError {
         PolyError::Json(err)
     }
 }
+
+impl From<PatchError> for PolyError {
+    fn from(err: PatchError) -> Self {
+        PolyError::PatchError(err.to_string())
+    }
+}
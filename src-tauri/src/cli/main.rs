@@ -8,7 +8,10 @@ mod config;
 mod downloader;
 mod error;
 
-use commands::init::handle_init;
+use commands::{
+    init::handle_init,
+    patch::{handle_patch, PatchCommands},
+};
 
 #[derive(Parser)]
 #[command(
@@ -33,6 +36,11 @@ enum Commands {
         )]
         polytrack_version: String,
     },
+    /// Work with patch packages
+    Patch {
+        #[command(subcommand)]
+        command: PatchCommands,
+    },
 }
 
 fn main() {
@@ -42,6 +50,7 @@ fn main() {
             if let Some(command) = cli.subcommand {
                 match command {
                     Commands::Init { polytrack_version } => handle_init(polytrack_version),
+                    Commands::Patch { command } => handle_patch(command),
                 }
             } else {
                 Ok(())
@@ -0,0 +1,278 @@
+//! Errors returned by the public functions of the library.
+
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{signing::VerifyingKey, MIN_PATCH_PACKAGE_VERSION, PATCH_PACKAGE_VERSION};
+
+/// Result type of the public functions of the library.
+pub type PatchResult<T> = Result<T, PatchError>;
+
+/// Error returned by the public functions of the library. Failures front ends can offer a way out
+/// of get their own variant, everything else ends up in [`PatchError::Other`] with its message.
+#[derive(Debug)]
+pub enum PatchError {
+    /// A file doesn't hold the contents the package expects, usually because it was edited.
+    HashMismatch {
+        path: PathBuf,        // File whose contents differ
+        expected: String,     // Hash the package expects
+        actual: String,       // Hash of the current contents
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// A file the package changes doesn't exist.
+    MissingFile {
+        path: PathBuf,        // File that was expected
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// The target holds another PolyTrack version than the package was created for.
+    PolyTrackMismatch {
+        expected: String, // Version the package was created for
+        actual: String,   // Version of the target
+    },
+    /// The target isn't the tree the package was created from.
+    BaseTreeMismatch {
+        expected: String, // Hash of the tree the package was created from
+        actual: String,   // Hash of the target tree
+    },
+    /// Contents stored in the package don't have the hash the package records for them, so the
+    /// package is corrupted.
+    CorruptData {
+        path: PathBuf,        // File that was written, or the entry path when verifying
+        expected: String,     // Hash the package records
+        actual: String,       // Hash of the written contents
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// An entry path leaves the directory the package is applied to.
+    PathEscape {
+        path: String,         // Entry path as recorded in the package
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// A symlink is in the way of a file or directory that is read or changed.
+    Symlink {
+        path: PathBuf,        // Path of the symlink
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// Something other than a file is where an entry expects or puts a file, e.g. a directory.
+    NotAFile {
+        path: PathBuf,        // Path that isn't a file
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// Something other than a directory is where an entry expects or puts a directory.
+    NotADirectory {
+        path: PathBuf,        // Path that isn't a directory
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// A file is already where an entry moves or restores one.
+    PathOccupied {
+        path: PathBuf,        // Path of the file in the way
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// The package holds more than one entry for the same path.
+    DuplicateEntry {
+        path: PathBuf,        // Path changed by more than one entry
+        entry: Option<usize>, // Index of the second entry in the package
+    },
+    /// The package isn't signed and untrusted packages aren't allowed.
+    Unsigned,
+    /// The package is signed with a key that isn't trusted and untrusted packages aren't allowed.
+    UntrustedKey { key: Box<VerifyingKey> },
+    /// The signature doesn't match the package, so it was tampered with after signing.
+    InvalidSignature { key: Box<VerifyingKey> },
+    /// The package was written in a version that can't be read.
+    UnsupportedVersion { version: u32 },
+    /// Reading or writing a file failed.
+    Io {
+        path: PathBuf,        // File that was read or written
+        source: io::Error,    // What went wrong
+        entry: Option<usize>, // Index of the entry in the package, if it happened at one
+    },
+    /// The operation was cancelled through its cancel token.
+    Cancelled,
+    /// Any other failure.
+    Other(anyhow::Error),
+}
+
+impl PatchError {
+    /// Builds an [`PatchError::Io`] error for `path`.
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        PatchError::Io {
+            path: path.to_path_buf(),
+            source,
+            entry: None,
+        }
+    }
+
+    /// Returns the path the error is about, if it is about one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            PatchError::HashMismatch { path, .. }
+            | PatchError::MissingFile { path, .. }
+            | PatchError::CorruptData { path, .. }
+            | PatchError::Symlink { path, .. }
+            | PatchError::NotAFile { path, .. }
+            | PatchError::NotADirectory { path, .. }
+            | PatchError::PathOccupied { path, .. }
+            | PatchError::DuplicateEntry { path, .. }
+            | PatchError::Io { path, .. } => Some(path),
+            PatchError::PathEscape { path, .. } => Some(Path::new(path)),
+            _ => None,
+        }
+    }
+
+    /// Moves the path the error is about from under the directory `from` to under `to`.
+    pub(crate) fn relocate(mut self, from: &Path, to: &Path) -> Self {
+        if let PatchError::HashMismatch { path, .. }
+        | PatchError::MissingFile { path, .. }
+        | PatchError::CorruptData { path, .. }
+        | PatchError::Symlink { path, .. }
+        | PatchError::NotAFile { path, .. }
+        | PatchError::NotADirectory { path, .. }
+        | PatchError::PathOccupied { path, .. }
+        | PatchError::DuplicateEntry { path, .. }
+        | PatchError::Io { path, .. } = &mut self
+        {
+            if let Ok(rel_path) = path.strip_prefix(from) {
+                *path = to.join(rel_path);
+            }
+        }
+        self
+    }
+
+    /// Returns the index of the package entry the error happened at, if known.
+    pub fn entry(&self) -> Option<usize> {
+        match self {
+            PatchError::HashMismatch { entry, .. }
+            | PatchError::MissingFile { entry, .. }
+            | PatchError::CorruptData { entry, .. }
+            | PatchError::PathEscape { entry, .. }
+            | PatchError::Symlink { entry, .. }
+            | PatchError::NotAFile { entry, .. }
+            | PatchError::NotADirectory { entry, .. }
+            | PatchError::PathOccupied { entry, .. }
+            | PatchError::DuplicateEntry { entry, .. }
+            | PatchError::Io { entry, .. } => *entry,
+            _ => None,
+        }
+    }
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::HashMismatch {
+                path,
+                expected,
+                actual,
+                ..
+            } => write!(
+                f,
+                "Hash mismatch in file: {} (expected {}, found {}). File may have been modified.",
+                path.display(),
+                expected,
+                actual
+            ),
+            PatchError::MissingFile { path, .. } => {
+                write!(f, "File to patch not found: {}", path.display())
+            }
+            PatchError::PolyTrackMismatch { expected, actual } => write!(
+                f,
+                "Patch was created for PolyTrack {}, but the target directory holds PolyTrack {}",
+                expected, actual
+            ),
+            PatchError::BaseTreeMismatch { expected, actual } => write!(
+                f,
+                "Target directory does not match the tree the patch was created from (expected tree hash {}, found {}). It may have been modified or already patched.",
+                expected, actual
+            ),
+            PatchError::CorruptData {
+                path,
+                expected,
+                actual,
+                ..
+            } => write!(
+                f,
+                "Hash mismatch in the contents stored for: {} (expected {}, found {}). Patch may be corrupted.",
+                path.display(),
+                expected,
+                actual
+            ),
+            PatchError::PathEscape { path, .. } => {
+                write!(f, "Patch entry path {} escapes the target directory", path)
+            }
+            PatchError::Symlink { path, .. } => {
+                write!(f, "Refusing to use symlink: {}", path.display())
+            }
+            PatchError::NotAFile { path, .. } => {
+                write!(f, "Refusing to change non-file: {}", path.display())
+            }
+            PatchError::NotADirectory { path, .. } => {
+                write!(f, "Refusing to change non-directory: {}", path.display())
+            }
+            PatchError::PathOccupied { path, .. } => {
+                write!(f, "Refusing to overwrite existing file: {}", path.display())
+            }
+            PatchError::DuplicateEntry { path, .. } => {
+                write!(f, "Duplicate patch entry for path: {}", path.display())
+            }
+            PatchError::Unsigned => write!(
+                f,
+                "Patch package is not signed. Refusing to apply it without a trusted signature."
+            ),
+            PatchError::UntrustedKey { key } => {
+                write!(f, "Patch package is signed with an untrusted key: {}", key)
+            }
+            PatchError::InvalidSignature { key } => write!(
+                f,
+                "Patch package signature is invalid for key {}. The package may have been tampered with.",
+                key
+            ),
+            PatchError::UnsupportedVersion { version } => write!(
+                f,
+                "Unsupported patch version: {} (expected {} to {})",
+                version, MIN_PATCH_PACKAGE_VERSION, PATCH_PACKAGE_VERSION
+            ),
+            PatchError::Io { path, source, .. } => {
+                write!(f, "Failed to access file: {}: {}", path.display(), source)
+            }
+            PatchError::Cancelled => write!(f, "Patch operation was cancelled"),
+            PatchError::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+/// Errors are raised as `anyhow` errors inside the library and turned into a [`PatchError`] at
+/// its public functions. Typed errors are taken out of the context they were wrapped in.
+impl From<anyhow::Error> for PatchError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<PatchError>() {
+            Ok(err) => err,
+            Err(err) => PatchError::Other(err),
+        }
+    }
+}
+
+/// Records the index of the entry `err` happened at, if it is about a path of that entry.
+pub(crate) fn at_entry(mut err: anyhow::Error, index: usize) -> anyhow::Error {
+    if let Some(
+        PatchError::HashMismatch { entry, .. }
+        | PatchError::MissingFile { entry, .. }
+        | PatchError::CorruptData { entry, .. }
+        | PatchError::PathEscape { entry, .. }
+        | PatchError::Symlink { entry, .. }
+        | PatchError::NotAFile { entry, .. }
+        | PatchError::NotADirectory { entry, .. }
+        | PatchError::PathOccupied { entry, .. }
+        | PatchError::DuplicateEntry { entry, .. }
+        | PatchError::Io { entry, .. },
+    ) = err.downcast_mut::<PatchError>()
+    {
+        entry.get_or_insert(index);
+    }
+    err
+}
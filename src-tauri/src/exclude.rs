@@ -0,0 +1,114 @@
+//! Gitignore style rules for paths left out of the trees compared by `create_patch`.
+
+use std::{
+    cell::RefCell,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{
+    tree::{FileTree, NodeKind},
+    CreateOptions,
+};
+
+/// Name of the file ignore patterns are read from, at the root of a project.
+pub const IGNORE_FILE_NAME: &str = ".polylauncherignore";
+
+/// Files and directories of a tree, relative to its root.
+pub(crate) struct TreeListing {
+    pub files: Vec<PathBuf>,    // Files that are not left out, sorted by path
+    pub dirs: Vec<PathBuf>,     // Directories that are not left out, sorted by path
+    pub excluded: Vec<PathBuf>, // Paths left out, not counting the ones inside excluded directories
+}
+
+/// Compiled ignore patterns, matched against paths relative to the root of a tree.
+pub(crate) struct ExcludeRules(Gitignore);
+
+impl ExcludeRules {
+    /// Rules that leave nothing out.
+    pub(crate) fn none() -> Self {
+        Self(Gitignore::empty())
+    }
+
+    /// Builds the rules from the ignore file of the project root in `options`, if there is one,
+    /// followed by the patterns in `options`.
+    pub(crate) fn new(options: &CreateOptions) -> anyhow::Result<Self> {
+        Self::from_patterns(&Self::patterns(options)?)
+    }
+
+    /// Returns the lines of the ignore file of the project root in `options`, if there is one,
+    /// followed by the patterns in `options`. They are recorded in the package info, so the
+    /// target is hashed with the same rules when the package is applied.
+    pub(crate) fn patterns(options: &CreateOptions) -> anyhow::Result<Vec<String>> {
+        let mut patterns = Vec::new();
+
+        if let Some(project_root) = &options.project_root {
+            let ignore_file = project_root.join(IGNORE_FILE_NAME);
+            if ignore_file.is_file() {
+                let contents = read_to_string(&ignore_file).with_context(|| {
+                    format!("Failed to read ignore file: {}", ignore_file.display())
+                })?;
+                // Blank lines and comments don't match anything
+                patterns.extend(
+                    contents
+                        .lines()
+                        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                        .map(str::to_string),
+                );
+            }
+        }
+        patterns.extend(options.ignore.iter().cloned());
+
+        Ok(patterns)
+    }
+
+    /// Builds the rules from gitignore style `patterns`, see [`ExcludeRules::patterns`].
+    pub(crate) fn from_patterns(patterns: &[String]) -> anyhow::Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern)
+                .with_context(|| format!("Invalid ignore pattern: {}", pattern))?;
+        }
+
+        Ok(Self(
+            builder.build().context("Failed to build ignore rules")?,
+        ))
+    }
+
+    /// Returns whether the file or directory at `rel_path` is left out.
+    pub(crate) fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        self.0.matched(rel_path, is_dir).is_ignore()
+    }
+
+    /// Lists the files and directories of `tree` that are not left out, and the paths that
+    /// are. Paths inside excluded directories are not listed separately.
+    pub(crate) fn list(&self, tree: &dyn FileTree) -> anyhow::Result<TreeListing> {
+        let excluded = RefCell::new(Vec::new());
+        let listing = tree.list(&|rel_path, kind| {
+            let is_excluded = self.is_excluded(rel_path, kind == NodeKind::Dir);
+            if is_excluded {
+                excluded.borrow_mut().push(rel_path.to_path_buf());
+            }
+            is_excluded
+        })?;
+
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        for (rel_path, kind) in listing {
+            match kind {
+                NodeKind::File => files.push(rel_path),
+                NodeKind::Dir => dirs.push(rel_path),
+            }
+        }
+
+        Ok(TreeListing {
+            files,
+            dirs,
+            excluded: excluded.into_inner(),
+        })
+    }
+}
@@ -0,0 +1,93 @@
+//! Selecting which entries of a patch package are applied.
+
+use std::path::Path;
+
+use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::PatchOperation;
+
+/// Kind of a patch entry, matched by [`EntryFilter::kinds`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Added,
+    Removed,
+    Modified,
+    Renamed,
+    AddedDir,
+    RemovedDir,
+}
+
+/// Selects the entries of a package that are applied, see
+/// [`ApplyOptions::filter`](crate::ApplyOptions). An entry is applied only if it passes every
+/// part of the filter, the default applies all of them. Renamed files are matched by their new
+/// path.
+#[derive(Clone, Debug, Default)]
+pub struct EntryFilter {
+    pub include: Vec<String>, // Gitignore style patterns of the paths to apply, every path if empty
+    pub exclude: Vec<String>, // Patterns of the paths to leave out, even if included
+    pub paths: Option<Vec<String>>, // Relative paths to apply, every path if `None`
+    pub kinds: Option<Vec<EntryKind>>, // Kinds of entries to apply, every kind if `None`
+}
+
+/// Compiled [`EntryFilter`].
+pub(crate) struct EntrySelector<'a> {
+    filter: &'a EntryFilter,
+    include: Gitignore,
+    exclude: Gitignore,
+}
+
+/// Builds gitignore style rules from `patterns`.
+fn build_rules(patterns: &[String]) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("Invalid entry pattern: {}", pattern))?;
+    }
+    builder.build().context("Failed to build entry filter")
+}
+
+impl<'a> EntrySelector<'a> {
+    pub(crate) fn new(filter: &'a EntryFilter) -> anyhow::Result<Self> {
+        Ok(Self {
+            filter,
+            include: build_rules(&filter.include)?,
+            exclude: build_rules(&filter.exclude)?,
+        })
+    }
+
+    /// Returns whether the entry doing `operation` to `rel_path` is applied. Patterns matching a
+    /// directory also match everything in it.
+    pub(crate) fn selects(&self, operation: &PatchOperation, rel_path: &str) -> bool {
+        let kind = match operation {
+            PatchOperation::Add { .. } => EntryKind::Added,
+            PatchOperation::Remove => EntryKind::Removed,
+            PatchOperation::Modify { .. } => EntryKind::Modified,
+            PatchOperation::Rename { .. } => EntryKind::Renamed,
+            PatchOperation::AddDir => EntryKind::AddedDir,
+            PatchOperation::RemoveDir => EntryKind::RemovedDir,
+        };
+        let is_dir = matches!(kind, EntryKind::AddedDir | EntryKind::RemovedDir);
+        let path = Path::new(rel_path);
+
+        self.filter
+            .kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+            && self
+                .filter
+                .paths
+                .as_ref()
+                .is_none_or(|paths| paths.iter().any(|selected| selected == rel_path))
+            && (self.filter.include.is_empty()
+                || self
+                    .include
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore())
+            && !self
+                .exclude
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+    }
+}
@@ -0,0 +1,405 @@
+//! Line and token hunks with context, recorded next to the chunk patches of modified text files
+//! so they can still be patched when their contents changed slightly.
+//!
+//! Hunks work like the ones of `diff -u` and `patch(1)`: every hunk holds the changed lines and
+//! a few unchanged lines around them, and is applied wherever its context is found, even if the
+//! lines before it moved. Minified files with very long lines are split into tokens instead.
+
+use std::{collections::HashMap, str::from_utf8};
+
+use anyhow::anyhow;
+
+use crate::{report::HunkStatus, CHUNK_SIZE};
+
+/// Largest file size hunks are recorded for.
+pub(crate) const MAX_TEXT_SIZE: u64 = CHUNK_SIZE;
+
+/// Unchanged lines kept around every change.
+const CONTEXT: usize = 3;
+
+/// Most lines of context left out at either end of a hunk to find where it applies.
+const MAX_FUZZ: usize = 2;
+
+/// Most lines added or removed before a file is considered too different for hunks.
+const MAX_EDITS: usize = 2048;
+
+/// Lines longer than this make a file split into tokens.
+const LONG_LINE: usize = 1000;
+
+/// Most bytes of lines held by the hunks of a file.
+const MAX_HUNKS_SIZE: usize = CHUNK_SIZE as usize;
+
+/// What a file is split into before diffing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Granularity {
+    Lines,  // Lines, with their line endings
+    Tokens, // Runs of text ending with a line ending, `;`, `{` or `}`
+}
+
+/// A single line of a hunk, with its line ending.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum HunkLine {
+    Context(String), // Unchanged line around the change
+    Removed(String), // Line only in the old file
+    Added(String),   // Line only in the new file
+}
+
+/// A run of changed lines with the unchanged lines around them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TextHunk {
+    pub old_start: u64,       // Index of the first line of the hunk in the old file
+    pub new_start: u64,       // Index of the first line of the hunk in the new file
+    pub lines: Vec<HunkLine>, // Lines of the hunk, in order
+}
+
+/// Hunks turning the old contents of a text file into the new ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TextHunks {
+    pub granularity: Granularity, // What line indices and hunk lines refer to
+    pub hunks: Vec<TextHunk>,     // Hunks in file order, never overlapping
+}
+
+/// A step of an edit script.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Splits `text` into the lines or tokens hunks are made of.
+fn split(text: &str, granularity: Granularity) -> Vec<&str> {
+    match granularity {
+        Granularity::Lines => text.split_inclusive('\n').collect(),
+        Granularity::Tokens => text.split_inclusive(['\n', ';', '{', '}']).collect(),
+    }
+}
+
+/// Maps every line to an id shared by equal lines, so lines are compared by id.
+fn intern<'a>(ids: &mut HashMap<&'a str, u32>, lines: &[&'a str]) -> Vec<u32> {
+    lines
+        .iter()
+        .map(|line| {
+            let next_id = ids.len() as u32;
+            *ids.entry(*line).or_insert(next_id)
+        })
+        .collect()
+}
+
+/// Computes the shortest edit script turning `a` into `b` with Myers' algorithm.
+/// Returns `None` if it takes more than [`MAX_EDITS`] insertions and deletions.
+fn edit_script(a: &[u32], b: &[u32]) -> Option<Vec<Edit>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = MAX_EDITS as isize;
+    let index = |k: isize| (k + max + 1) as usize;
+
+    // Furthest x reached on every diagonal k = x - y, and its state before every round
+    let mut v = vec![0isize; 2 * MAX_EDITS + 3];
+    let mut trace = Vec::new();
+    'search: {
+        for d in 0..=max {
+            trace.push(v[index(-d - 1)..=index(d + 1)].to_vec());
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                    v[index(k + 1)]
+                } else {
+                    v[index(k - 1)] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                v[index(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+        return None;
+    }
+
+    // Walk back from the end through the recorded rounds
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert);
+                y -= 1;
+            } else {
+                edits.push(Edit::Delete);
+                x -= 1;
+            }
+        }
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// Groups the changes turning `old_lines` into `new_lines` into hunks with [`CONTEXT`] lines
+/// around them. Returns `None` if it takes more than [`MAX_EDITS`] changes.
+fn hunks_between(old_lines: &[&str], new_lines: &[&str]) -> Option<Vec<TextHunk>> {
+    // Diff the lines in between the common start and end, compared by id
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut ids = HashMap::new();
+    let old_ids = intern(&mut ids, &old_lines[prefix..old_lines.len() - suffix]);
+    let new_ids = intern(&mut ids, &new_lines[prefix..new_lines.len() - suffix]);
+
+    let mut edits = vec![Edit::Equal; prefix];
+    edits.extend(edit_script(&old_ids, &new_ids)?);
+    edits.extend(vec![Edit::Equal; suffix]);
+
+    // Line index in both files before every edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for edit in &edits {
+        positions.push((i, j));
+        match edit {
+            Edit::Equal => (i, j) = (i + 1, j + 1),
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    // Group changes closer than twice the context into the same hunk
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|&e| edits[e] != Edit::Equal)
+        .collect();
+    let mut hunks = Vec::new();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        let mut group_end = group_start;
+        while group_end + 1 < changes.len()
+            && changes[group_end + 1] - changes[group_end] <= 2 * CONTEXT + 1
+        {
+            group_end += 1;
+        }
+
+        let start = changes[group_start].saturating_sub(CONTEXT);
+        let end = (changes[group_end] + CONTEXT + 1).min(edits.len());
+        let lines = (start..end)
+            .map(|e| {
+                let (i, j) = positions[e];
+                match edits[e] {
+                    Edit::Equal => HunkLine::Context(old_lines[i].to_string()),
+                    Edit::Delete => HunkLine::Removed(old_lines[i].to_string()),
+                    Edit::Insert => HunkLine::Added(new_lines[j].to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+        let (old_start, new_start) = positions[start];
+        hunks.push(TextHunk {
+            old_start: old_start as u64,
+            new_start: new_start as u64,
+            lines,
+        });
+        group_start = group_end + 1;
+    }
+
+    Some(hunks)
+}
+
+/// Computes the line hunks of a unified diff turning `old` into `new`. Files too different to
+/// diff line by line get a single hunk replacing every line.
+pub(crate) fn diff_lines(old: &str, new: &str) -> Vec<TextHunk> {
+    let old_lines = split(old, Granularity::Lines);
+    let new_lines = split(new, Granularity::Lines);
+    hunks_between(&old_lines, &new_lines).unwrap_or_else(|| {
+        let removed = old_lines
+            .iter()
+            .map(|line| HunkLine::Removed(line.to_string()));
+        let added = new_lines
+            .iter()
+            .map(|line| HunkLine::Added(line.to_string()));
+        vec![TextHunk {
+            old_start: 0,
+            new_start: 0,
+            lines: removed.chain(added).collect(),
+        }]
+    })
+}
+
+impl TextHunks {
+    /// Computes the hunks turning `old` into `new`. Returns `None` if either isn't UTF-8 text,
+    /// is larger than [`MAX_TEXT_SIZE`] or if they differ too much for hunks to be useful.
+    pub fn diff(old: &[u8], new: &[u8]) -> Option<Self> {
+        if old.len() as u64 > MAX_TEXT_SIZE || new.len() as u64 > MAX_TEXT_SIZE {
+            return None;
+        }
+        let (old, new) = (from_utf8(old).ok()?, from_utf8(new).ok()?);
+
+        let has_long_lines = |text: &str| text.split('\n').any(|line| line.len() > LONG_LINE);
+        let granularity = if has_long_lines(old) || has_long_lines(new) {
+            Granularity::Tokens
+        } else {
+            Granularity::Lines
+        };
+        let hunks = hunks_between(&split(old, granularity), &split(new, granularity))?;
+        let size: usize = hunks
+            .iter()
+            .flat_map(|hunk| &hunk.lines)
+            .map(|line| line.text().len())
+            .sum();
+        if size > MAX_HUNKS_SIZE {
+            return None;
+        }
+
+        Some(Self { granularity, hunks })
+    }
+
+    /// Applies the hunks to `contents`, each where its context is found closest to where it was
+    /// recorded, like `patch(1)`. Hunks whose context isn't found even with some of it left out
+    /// are rejected and the rest still applied. Returns the patched contents and the outcome of
+    /// every hunk.
+    pub fn apply(&self, contents: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<HunkStatus>)> {
+        let text = from_utf8(contents).map_err(|_| anyhow!("File is not UTF-8 text"))?;
+        let lines = split(text, self.granularity);
+
+        let mut out = String::with_capacity(text.len());
+        let mut statuses = Vec::with_capacity(self.hunks.len());
+        let mut copied = 0; // Lines of the file before the last applied hunk
+        let mut drift = 0; // Offset the last applied hunk was found at
+        for hunk in &self.hunks {
+            let Some(place) = hunk.locate(&lines, copied, drift) else {
+                statuses.push(HunkStatus::Rejected);
+                continue;
+            };
+
+            let new = hunk.new_lines();
+            out.extend(lines[copied..place.start].iter().copied());
+            out.extend(new[place.front..new.len() - place.back].iter().copied());
+            copied = place.start + place.len;
+
+            drift = place.start as i64 - (hunk.old_start as usize + place.front) as i64;
+            statuses.push(match drift {
+                0 => HunkStatus::Applied { fuzz: place.fuzz },
+                offset => HunkStatus::Offset {
+                    offset,
+                    fuzz: place.fuzz,
+                },
+            });
+        }
+        out.extend(lines[copied..].iter().copied());
+
+        Ok((out.into_bytes(), statuses))
+    }
+}
+
+impl HunkLine {
+    /// Returns the text of the line.
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Context(text) | Self::Removed(text) | Self::Added(text) => text,
+        }
+    }
+}
+
+/// Where a hunk was found in a file.
+struct Placement {
+    start: usize, // Index of the first matched line
+    len: usize,   // Lines matched
+    front: usize, // Context lines left out at the start of the hunk
+    back: usize,  // Context lines left out at the end of the hunk
+    fuzz: usize,  // Fuzz factor the hunk was found with
+}
+
+impl TextHunk {
+    /// Returns the lines the hunk expects to find.
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter(|line| !matches!(line, HunkLine::Added(_)))
+            .map(HunkLine::text)
+            .collect()
+    }
+
+    /// Returns the lines the hunk leaves in place of them.
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter(|line| !matches!(line, HunkLine::Removed(_)))
+            .map(HunkLine::text)
+            .collect()
+    }
+
+    /// Finds the lines of `lines` from `min_start` on the hunk applies to, searching outwards
+    /// from where it was recorded moved by `drift`. Leaves out more and more context lines at
+    /// both ends until it is found.
+    fn locate(&self, lines: &[&str], min_start: usize, drift: i64) -> Option<Placement> {
+        let old = self.old_lines();
+        let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+        let leading = self.lines.iter().take_while(is_context).count();
+        let trailing = self.lines.iter().rev().take_while(is_context).count();
+
+        for fuzz in 0..=MAX_FUZZ {
+            if fuzz > 0 && fuzz > leading && fuzz > trailing {
+                // Nothing more to leave out
+                break;
+            }
+            let front = fuzz.min(leading);
+            let back = fuzz.min(trailing);
+            let pattern = &old[front..old.len() - back];
+            if lines.len() < pattern.len() {
+                continue;
+            }
+
+            let max_start = lines.len() - pattern.len();
+            if min_start > max_start {
+                return None;
+            }
+            let expected = (self.old_start as i64 + front as i64 + drift)
+                .clamp(min_start as i64, max_start as i64) as usize;
+            let matches = |start: usize| lines[start..start + pattern.len()] == *pattern;
+
+            for distance in 0..=(max_start - min_start) {
+                let found = [
+                    expected.checked_sub(distance).filter(|&s| s >= min_start),
+                    Some(expected + distance).filter(|&s| distance > 0 && s <= max_start),
+                ]
+                .into_iter()
+                .flatten()
+                .find(|&start| matches(start));
+
+                if let Some(start) = found {
+                    return Some(Placement {
+                        start,
+                        len: pattern.len(),
+                        front,
+                        back,
+                        fuzz,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,227 @@
+//! Package level information recorded right after the header of a patch package.
+
+use std::{
+    env,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::ensure;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    error::{PatchError, PatchResult},
+    exclude::ExcludeRules,
+    open_patch,
+    transaction::TRANSACTION_DIR,
+    tree::FileTree,
+    ApplyOptions, CreateOptions,
+};
+
+/// Name of the directory PolyLauncher keeps installed PolyTrack versions in, each in a directory
+/// named after the version.
+pub const POLYTRACK_VERSIONS_DIR: &str = "polytrack_versions";
+
+/// Counts and sizes of what a package holds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageStats {
+    pub added: u64,        // Added files
+    pub removed: u64,      // Removed files
+    pub modified: u64,     // Modified files
+    pub renamed: u64,      // Renamed files
+    pub added_dirs: u64,   // Added directories
+    pub removed_dirs: u64, // Removed directories
+    pub data_size: u64,    // Bytes of file contents stored for added files and replaced chunks
+    pub delta_size: u64, // Bytes of chunk patches and text hunks stored for modified and renamed files
+    pub inverse_size: u64, // Bytes stored to revert the package
+}
+
+/// Information about a patch package, recorded when it is created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageInfo {
+    pub polytrack_version: Option<String>, // PolyTrack version the package applies to
+    pub description: Option<String>,       // Description of what the package targets
+    pub tool_version: String,              // PolyLauncher version that created the package
+    pub created_at: u64, // Creation time in seconds since the Unix epoch, 0 if unknown
+    pub base_tree_hash: Option<String>, // Hash of the tree the package applies to
+    pub ignore: Vec<String>, // Ignore patterns of the paths left out of the base tree hash
+    pub prune_empty_dirs: bool, // Prune directories emptied by removed files, for upgraded packages
+    pub stats: PackageStats, // Filled in once every entry is written
+}
+
+/// Returns the creation time recorded when none is given: `SOURCE_DATE_EPOCH` if it is set, so
+/// reproducible builds get the same package from the same trees, and the current time otherwise.
+fn default_created_at() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs())
+        })
+}
+
+impl PackageStats {
+    /// Adds the counts and sizes of `other`.
+    pub(crate) fn add(&mut self, other: &PackageStats) {
+        self.added += other.added;
+        self.removed += other.removed;
+        self.modified += other.modified;
+        self.renamed += other.renamed;
+        self.added_dirs += other.added_dirs;
+        self.removed_dirs += other.removed_dirs;
+        self.data_size += other.data_size;
+        self.delta_size += other.delta_size;
+        self.inverse_size += other.inverse_size;
+    }
+
+    /// Returns the number of entries in the package.
+    pub fn entries(&self) -> u64 {
+        self.added
+            + self.removed
+            + self.modified
+            + self.renamed
+            + self.added_dirs
+            + self.removed_dirs
+    }
+}
+
+impl PackageInfo {
+    /// Builds the info of a package created from `base_tree`. Paths left out by the ignore
+    /// patterns of `options` are left out of its hash too.
+    pub(crate) fn new(base_tree: &dyn FileTree, options: &CreateOptions) -> anyhow::Result<Self> {
+        let ignore = ExcludeRules::patterns(options)?;
+        Ok(Self {
+            base_tree_hash: Some(hash_tree(
+                base_tree,
+                &ExcludeRules::from_patterns(&ignore)?,
+            )?),
+            ignore,
+            ..Self::without_base_tree(options)
+        })
+    }
+
+    /// Builds the info of a package put together without a tree to compare against, see
+    /// [`crate::PatchBuilder`]. Only the files it changes are checked when it is applied.
+    pub(crate) fn without_base_tree(options: &CreateOptions) -> Self {
+        Self {
+            polytrack_version: options.polytrack_version.clone(),
+            description: options.description.clone(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: options.created_at.unwrap_or_else(default_created_at),
+            base_tree_hash: None,
+            ignore: Vec::new(),
+            prune_empty_dirs: false,
+            stats: PackageStats::default(),
+        }
+    }
+
+    /// Builds the info of a package upgraded from a version that didn't record any. Nothing is
+    /// known about the tree it applies to, so it isn't checked when the package is applied.
+    pub(crate) fn unknown() -> Self {
+        Self {
+            polytrack_version: None,
+            description: None,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: default_created_at(),
+            base_tree_hash: None,
+            ignore: Vec::new(),
+            prune_empty_dirs: false,
+            stats: PackageStats::default(),
+        }
+    }
+}
+
+/// Computes a hash over the relative path and contents of every file in `tree` not left out by
+/// `rules`. Directories, permissions and the transaction directory are not part of it.
+pub(crate) fn hash_tree(tree: &dyn FileTree, rules: &ExcludeRules) -> anyhow::Result<String> {
+    let mut paths = rules.list(tree)?.files;
+    paths.retain(|rel_path| !rel_path.starts_with(TRANSACTION_DIR));
+
+    let hashes = paths
+        .par_iter()
+        .map(|rel_path| tree.hash(rel_path))
+        .collect::<PatchResult<Vec<_>>>()?;
+
+    let mut context = md5::Context::new();
+    for (rel_path, hash) in paths.iter().zip(hashes) {
+        let rel_path_str = rel_path.to_string_lossy().replace("\\", "/");
+        context.consume(rel_path_str.as_bytes());
+        context.consume([0]);
+        context.consume(hash.as_bytes());
+        context.consume([b'\n']);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// Checks that `target` is the tree the package was created from, leaving out the paths its
+/// ignore patterns left out.
+pub(crate) fn check_base_tree(info: &PackageInfo, target: &dyn FileTree) -> anyhow::Result<()> {
+    let Some(expected) = &info.base_tree_hash else {
+        return Ok(());
+    };
+    let actual = hash_tree(target, &ExcludeRules::from_patterns(&info.ignore)?)?;
+    ensure!(
+        &actual == expected,
+        PatchError::BaseTreeMismatch {
+            expected: expected.clone(),
+            actual
+        }
+    );
+    Ok(())
+}
+
+/// Returns the PolyTrack version of the target at `target_path`: the one in `options`, or else
+/// the name of the target if it is a version directory of PolyLauncher.
+fn target_version(options: &ApplyOptions, target_path: Option<&Path>) -> Option<String> {
+    if let Some(version) = &options.polytrack_version {
+        return Some(version.clone());
+    }
+    let target_path = target_path?;
+    let versions_dir = target_path.parent()?.file_name()?;
+    (versions_dir == POLYTRACK_VERSIONS_DIR)
+        .then(|| target_path.file_name())
+        .flatten()
+        .map(|version| version.to_string_lossy().into_owned())
+}
+
+/// Checks the package info against the target tree, found at `target_path` if it is a
+/// directory, before anything is applied. A target that isn't the tree the package was created
+/// from is refused unless [`ApplyOptions::ignore_base_tree`] is set. Returns whether entries
+/// have to be checked strictly then, see [`crate::apply_patch`].
+pub(crate) fn check_target(
+    info: Option<&PackageInfo>,
+    target: &dyn FileTree,
+    target_path: Option<&Path>,
+    options: &ApplyOptions,
+) -> anyhow::Result<bool> {
+    let Some(info) = info else {
+        // Packages from before package info can't be checked
+        return Ok(false);
+    };
+
+    if let (Some(expected), Some(actual)) = (
+        &info.polytrack_version,
+        target_version(options, target_path),
+    ) {
+        ensure!(
+            expected == &actual,
+            PatchError::PolyTrackMismatch {
+                expected: expected.clone(),
+                actual
+            }
+        );
+    }
+    if options.ignore_base_tree {
+        return Ok(true);
+    }
+    check_base_tree(info, target)?;
+    Ok(false)
+}
+
+/// Reads the package info of a patch package without reading its entries.
+/// Returns `None` for packages created before package info was recorded.
+pub fn read_package_info(patch_loc: &Path) -> PatchResult<Option<PackageInfo>> {
+    Ok(open_patch(patch_loc)?.info().cloned())
+}
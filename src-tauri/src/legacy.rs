@@ -0,0 +1,96 @@
+//! Reading of patch packages written in older formats.
+//!
+//! Version 1 packages are a single rkyv archive holding every entry with its full contents.
+//! They are converted into the framed format in memory, so the rest of the crate only ever deals
+//! with frames.
+
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, ensure, Context};
+use files_diff::{hash, CompressAlgorithm, Patch};
+use rkyv::{access, deserialize, rancor::Error, util::AlignedVec, Archive, Deserialize};
+
+use crate::{
+    error::PatchError,
+    stream::{PackageHeader, PatchReader, PatchWriter},
+    PatchEntry, PatchOperation,
+};
+
+/// Operation of a version 1 entry.
+#[derive(Archive, Deserialize)]
+enum OperationV1 {
+    Add(Vec<u8>),  // File is added
+    Remove,        // File is removed
+    Modify(Patch), // File is modified
+}
+
+/// Entry of a version 1 package.
+#[derive(Archive, Deserialize)]
+struct EntryV1 {
+    operation: OperationV1,
+    rel_path: String,
+}
+
+/// Version 1 package, archived as a whole.
+#[derive(Archive, Deserialize)]
+struct PackageV1 {
+    version: u32,
+    entries: Vec<EntryV1>,
+}
+
+/// Reads a version 1 package and returns a reader over the same entries in the framed format.
+/// Version 1 patches cover whole files, so they are read as a single chunk.
+pub(crate) fn read_v1_package(mut inner: impl Read) -> anyhow::Result<PatchReader<Box<dyn Read>>> {
+    let mut bytes = AlignedVec::<16>::new();
+    bytes
+        .extend_from_reader(&mut inner)
+        .context("Failed to read patch file")?;
+
+    let archived = access::<ArchivedPackageV1, Error>(&bytes)
+        .map_err(|e| anyhow!("Failed to access archived patch package: {:?}", e))?;
+    let package = deserialize::<PackageV1, Error>(archived)
+        .map_err(|e| anyhow!("Failed to deserialize patch package: {:?}", e))?;
+    ensure!(
+        package.version == 1,
+        PatchError::UnsupportedVersion {
+            version: package.version
+        }
+    );
+
+    let header = PackageHeader {
+        version: 1,
+        chunk_size: u64::MAX,
+    };
+    let mut writer = PatchWriter::new(Vec::new(), &header)?;
+    for entry in package.entries {
+        match entry.operation {
+            OperationV1::Add(contents) => {
+                writer.write_entry(&PatchEntry::new(
+                    PatchOperation::Add {
+                        hash: hash(&contents),
+                    },
+                    entry.rel_path,
+                ))?;
+                if !contents.is_empty() {
+                    writer.write_data(&contents, CompressAlgorithm::None)?;
+                }
+            }
+            OperationV1::Remove => {
+                writer.write_entry(&PatchEntry::new(PatchOperation::Remove, entry.rel_path))?;
+            }
+            OperationV1::Modify(patch) => {
+                writer.write_entry(&PatchEntry::new(
+                    PatchOperation::Modify {
+                        before_hash: patch.before_hash.clone(),
+                        after_hash: patch.after_hash.clone(),
+                    },
+                    entry.rel_path,
+                ))?;
+                writer.write_delta(&patch)?;
+            }
+        }
+    }
+
+    let framed = writer.finish()?;
+    PatchReader::new(Box::new(Cursor::new(framed)) as Box<dyn Read>)
+}
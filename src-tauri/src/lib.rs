@@ -1,168 +1,809 @@
-// TODO: Don't load entire files into memory at once for large files.
+mod archive;
+mod builder;
+mod error;
+mod exclude;
+mod filter;
+mod hunks;
+mod info;
+mod legacy;
+mod metadata;
+mod mirror;
+mod progress;
+mod rename;
+mod report;
+mod signing;
+mod squash;
+mod strategy;
+mod stream;
+#[cfg(test)]
+mod tests;
+mod transaction;
+mod tree;
+mod unified;
+mod upgrade;
+mod verify;
 
 use std::{
-    collections::HashSet,
-    fs::{create_dir_all, read, remove_dir, remove_file, symlink_metadata, write},
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{hard_link, remove_dir, remove_file, symlink_metadata, File},
+    io::{copy, sink, BufRead, BufReader, BufWriter, Read, Write},
+    ops::Bound::{Excluded, Unbounded},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use anyhow::{anyhow, ensure, Context};
-use files_diff::{apply, diff, hash, CompressAlgorithm, DiffAlgorithm, Patch};
-use rkyv::{access, deserialize, rancor::Error, to_bytes, Archive, Deserialize, Serialize};
-use walkdir::WalkDir;
+use anyhow::{anyhow, bail, ensure, Context};
+use files_diff::{apply, diff, Patch};
+use memmap2::Mmap;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-const PATCH_PACKAGE_VERSION: u32 = 1;
+pub use crate::{
+    archive::ArchiveTree,
+    builder::PatchBuilder,
+    error::{PatchError, PatchResult},
+    exclude::IGNORE_FILE_NAME,
+    filter::{EntryFilter, EntryKind},
+    info::{read_package_info, PackageInfo, PackageStats, POLYTRACK_VERSIONS_DIR},
+    metadata::FileMetadata,
+    mirror::apply_patch_to,
+    progress::{CancelToken, ProgressObserver},
+    report::{
+        ApplyReport, CreateReport, DryRunReport, EntryChange, EntryFailure, EntryInfo, FuzzyFile,
+        HunkStatus, VerifyReport,
+    },
+    signing::{sign_patch, SigningKey, VerifyingKey},
+    squash::squash_patches,
+    strategy::{CompressAlgorithm, DiffAlgorithm, DiffRule, DiffStrategy},
+    tree::{DirTree, FileTree, MemoryTree, NodeKind},
+    unified::{export_unified_diff, import_unified_diff},
+    upgrade::{upgrade_patch, PatchUpgrade},
+    verify::verify_patch,
+};
+use crate::{
+    error::at_entry,
+    exclude::{ExcludeRules, TreeListing},
+    filter::EntrySelector,
+    hunks::{TextHunks, MAX_TEXT_SIZE},
+    info::check_target,
+    legacy::read_v1_package,
+    rename::{detect_renames, DetectedRename},
+    signing::check_signature,
+    strategy::pick_strategy,
+    stream::{
+        append_checksum, ChunkChange, PackageHeader, PatchReader, PatchWriter, Payload, MAGIC,
+    },
+    transaction::{recover, Transaction, TRANSACTION_DIR},
+    verify::check_rel_path,
+};
+
+const PATCH_PACKAGE_VERSION: u32 = 10;
+
+/// Oldest patch package version that can still be read.
+const MIN_PATCH_PACKAGE_VERSION: u32 = 1;
+
+/// First version that records added and removed directories. Directories emptied by older
+/// packages are pruned instead.
+const DIR_ENTRIES_VERSION: u32 = 6;
+
+/// First version that ends with a checksum of the whole package.
+const CHECKSUM_VERSION: u32 = 10;
+
+/// Size of the chunks files are hashed, diffed and stored in.
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Largest total size of the files diffed in parallel at once, which bounds the memory held by
+/// their entries before they are written.
+const PARALLEL_BATCH_SIZE: u64 = 16 * CHUNK_SIZE;
+
+/// Largest chunk size accepted when reading a patch package.
+const MAX_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
 
 /// Enum representing the type of operation a patch entry represents.
-#[derive(Archive, Serialize, Deserialize)]
+/// Contents of added and modified files are stored as chunks following the entry. Chunks of
+/// modified files are stored as is instead of as a patch when the patch would be bigger.
 enum PatchOperation {
-    Add(Vec<u8>),  // File is added
-    Remove,        // File is removed
-    Modify(Patch), // File is modified
+    // File is added
+    Add {
+        hash: String,
+    },
+    // File is removed
+    Remove,
+    // File is modified
+    Modify {
+        before_hash: String,
+        after_hash: String,
+    },
+    // File is moved from another path, and modified if the hashes differ
+    Rename {
+        from: String,
+        before_hash: String,
+        after_hash: String,
+    },
+    // Directory is added
+    AddDir,
+    // Empty directory is removed
+    RemoveDir,
 }
 
 /// A single entry in a patch, may contain the diff and relative path info.
-#[derive(Archive, Serialize, Deserialize)]
 struct PatchEntry {
-    pub operation: PatchOperation, // Operation type
-    pub rel_path: String,          // Relative file path
+    pub operation: PatchOperation,      // Operation type
+    pub rel_path: String,               // Relative file path
+    pub metadata: Option<FileMetadata>, // Metadata of the new file, if recorded
+    pub text_hunks: Option<TextHunks>,  // Text hunks of a modified file, if recorded
 }
 
 impl PatchEntry {
     pub fn new(operation: PatchOperation, rel_path: String) -> Self {
         let rel_path = rel_path.replace("\\", "/"); // Normalize to forward slashes
+        let operation = match operation {
+            PatchOperation::Rename {
+                from,
+                before_hash,
+                after_hash,
+            } => PatchOperation::Rename {
+                from: from.replace("\\", "/"),
+                before_hash,
+                after_hash,
+            },
+            operation => operation,
+        };
         Self {
             operation,
             rel_path,
+            metadata: None,
+            text_hunks: None,
         }
     }
+
+    pub fn with_metadata(self, metadata: Option<FileMetadata>) -> Self {
+        Self { metadata, ..self }
+    }
+
+    pub fn with_text_hunks(self, text_hunks: Option<TextHunks>) -> Self {
+        Self { text_hunks, ..self }
+    }
+
+    /// Describes the entry for callers outside the crate.
+    fn info(self) -> EntryInfo {
+        let (change, before_hash, after_hash) = match self.operation {
+            PatchOperation::Add { hash } => (EntryChange::Added, None, Some(hash)),
+            PatchOperation::Remove => (EntryChange::Removed, None, None),
+            PatchOperation::Modify {
+                before_hash,
+                after_hash,
+            } => (EntryChange::Modified, Some(before_hash), Some(after_hash)),
+            PatchOperation::Rename {
+                from,
+                before_hash,
+                after_hash,
+            } => (
+                EntryChange::Renamed { from },
+                Some(before_hash),
+                Some(after_hash),
+            ),
+            PatchOperation::AddDir => (EntryChange::AddedDir, None, None),
+            PatchOperation::RemoveDir => (EntryChange::RemovedDir, None, None),
+        };
+        EntryInfo {
+            rel_path: self.rel_path,
+            change,
+            before_hash,
+            after_hash,
+        }
+    }
+}
+
+/// Opens a file for buffered reading.
+fn open_file(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).map_err(|source| PatchError::io(path, source))?;
+    Ok(BufReader::new(file))
+}
+
+/// Computes the hash of a file without reading it into memory at once.
+/// Produces the same value as `files_diff::hash` over the whole contents.
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut context = md5::Context::new();
+    copy(&mut open_file(path)?, &mut context).map_err(|source| PatchError::io(path, source))?;
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// Reads the next chunk of at most `chunk_size` bytes into `buf`, returning the number of bytes read.
+fn read_chunk(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+    chunk_size: u64,
+) -> std::io::Result<usize> {
+    buf.clear();
+    reader.take(chunk_size).read_to_end(buf)
 }
 
-/// A package containing multiple patch entries.
-#[derive(Archive, Serialize, Deserialize)]
-struct PatchPackage {
-    pub version: u32, // Version for future compatibility
-    pub entries: Vec<PatchEntry>,
+/// Options for [`create_patch_with_options`].
+pub struct CreateOptions {
+    pub reversible: bool, // Record what is needed to undo the patch with `revert_patch`
+    pub detect_renames: bool, // Record moved files as renames instead of a removal and an addition
+    pub metadata: bool,   // Record file permissions and modification times
+    pub polytrack_version: Option<String>, // PolyTrack version of the tree the patch applies to
+    pub description: Option<String>, // Description of what the patch targets
+    pub created_at: Option<u64>, // Creation time in seconds since the Unix epoch, `SOURCE_DATE_EPOCH` or now if `None`
+    pub diff_rules: Vec<DiffRule>, // Rules picking how files are stored, the first match applies
+    pub default_strategy: DiffStrategy, // How files no rule matches are stored
+    pub ignore: Vec<String>,     // Gitignore style patterns of paths left out of both trees
+    pub project_root: Option<PathBuf>, // Project whose ignore file is read, if any
+    pub text_hunks: bool, // Also record line or token hunks of modified text files, see `ApplyOptions::fuzzy`
+    pub progress: Option<Arc<dyn ProgressObserver>>, // Notified as files are compared
+    pub cancel: CancelToken, // Checked between files, cancelling fails the patch
 }
 
-impl PatchPackage {
-    pub fn new(version: u32, entries: Vec<PatchEntry>) -> Self {
-        Self { version, entries }
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            reversible: false,
+            detect_renames: true,
+            metadata: true,
+            polytrack_version: None,
+            description: None,
+            created_at: None,
+            diff_rules: Vec::new(),
+            default_strategy: DiffStrategy::default(),
+            ignore: Vec::new(),
+            project_root: None,
+            text_hunks: false,
+            progress: None,
+            cancel: CancelToken::default(),
+        }
     }
 }
 
-/// Recursively collects all file paths under a directory, returning paths relative to `base_path`.
-fn collect_file_paths(base_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    let mut paths = Vec::new();
+impl CreateOptions {
+    /// Picks how the file at `rel_path` is stored, from the size of its new contents.
+    fn strategy_for(&self, rel_path: &Path, size: u64) -> DiffStrategy {
+        pick_strategy(&self.diff_rules, self.default_strategy, rel_path, size)
+    }
+}
 
-    for entry in WalkDir::new(base_path) {
-        let entry = entry.with_context(|| {
-            format!("Failed to read directory entry in: {}", base_path.display())
+/// Returns the size of a file in bytes.
+fn file_size(path: &Path) -> anyhow::Result<u64> {
+    Ok(path
+        .metadata()
+        .map_err(|source| PatchError::io(path, source))?
+        .len())
+}
+
+/// Reads the metadata of the file at `rel_path` in `tree` if `options` asks for it to be recorded.
+fn recorded_metadata(
+    tree: &dyn FileTree,
+    rel_path: &Path,
+    options: &CreateOptions,
+) -> anyhow::Result<Option<FileMetadata>> {
+    if !options.metadata {
+        return Ok(None);
+    }
+    Ok(tree.metadata(rel_path)?)
+}
+
+/// Computes the text hunks turning the file at `rel_path` in `tree1` into the one in `tree2` if
+/// `options` asks for them to be recorded and both are small enough.
+fn recorded_text_hunks(
+    tree1: &dyn FileTree,
+    tree2: &dyn FileTree,
+    rel_path: &Path,
+    options: &CreateOptions,
+) -> anyhow::Result<Option<TextHunks>> {
+    if !options.text_hunks
+        || tree1.size(rel_path)? > MAX_TEXT_SIZE
+        || tree2.size(rel_path)? > MAX_TEXT_SIZE
+    {
+        return Ok(None);
+    }
+    Ok(TextHunks::diff(
+        &tree1.read(rel_path)?,
+        &tree2.read(rel_path)?,
+    ))
+}
+
+/// Writes the contents of a file as data chunks compressed with `compression`. `file_path` names
+/// the file in errors.
+fn write_data_chunks(
+    writer: &mut PatchWriter<impl Write>,
+    contents: &mut impl Read,
+    file_path: &Path,
+    compression: CompressAlgorithm,
+) -> anyhow::Result<()> {
+    let mut chunk = Vec::new();
+    while read_chunk(contents, &mut chunk, CHUNK_SIZE)
+        .with_context(|| format!("Failed to read file: {}", file_path.display()))?
+        > 0
+    {
+        writer.write_data(&chunk, compression)?;
+    }
+    Ok(())
+}
+
+/// Diffs the contents of `from` against `to` chunk by chunk, the n-th chunk of `to` against the
+/// n-th chunk of `from`, passing the change to every chunk to `f`. Chunks whose patch would be
+/// bigger than the new chunk are replaced as a whole. `file_path` names the file in errors.
+fn diff_chunks(
+    from: &mut impl Read,
+    to: &mut impl Read,
+    file_path: &Path,
+    strategy: DiffStrategy,
+    mut f: impl FnMut(ChunkChange) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut from_chunk = Vec::new();
+    let mut to_chunk = Vec::new();
+
+    while read_chunk(to, &mut to_chunk, CHUNK_SIZE)
+        .with_context(|| format!("Failed to read file: {}", file_path.display()))?
+        > 0
+    {
+        read_chunk(from, &mut from_chunk, CHUNK_SIZE)
+            .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+
+        let patch = diff(
+            &from_chunk,
+            &to_chunk,
+            strategy.algorithm,
+            strategy.compression,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "Failed to compute diff for file: {}: {:?}",
+                file_path.display(),
+                e
+            )
         })?;
 
-        if entry.file_type().is_file() {
-            // Strip the base path to get relative path
-            let rel_path = entry
-                .path()
-                .strip_prefix(base_path)
-                .with_context(|| {
-                    format!(
-                        "Failed to strip prefix from path: {}",
-                        entry.path().display()
-                    )
-                })?
-                .to_path_buf();
-            paths.push(rel_path);
+        if patch.patch.len() >= to_chunk.len() {
+            f(ChunkChange::Replace(std::mem::take(&mut to_chunk)))?;
+        } else {
+            f(ChunkChange::Delta(patch))?;
         }
     }
 
-    Ok(paths)
+    Ok(())
 }
 
-/// Creates a patch file that represents changes between `path1` and `path2`.
-pub fn create_patch(patch_loc: &Path, path1: &Path, path2: &Path) -> anyhow::Result<()> {
-    let mut entries = Vec::new();
+/// Writes the chunk changes turning `from` into `to`, patches with `write` and replaced chunks
+/// as data, see [`diff_chunks`].
+fn write_chunk_deltas<W: Write>(
+    writer: &mut PatchWriter<W>,
+    from: &mut impl Read,
+    to: &mut impl Read,
+    file_path: &Path,
+    strategy: DiffStrategy,
+    write: fn(&mut PatchWriter<W>, &Patch) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    diff_chunks(from, to, file_path, strategy, |change| match change {
+        ChunkChange::Delta(patch) => write(writer, &patch),
+        ChunkChange::Replace(chunk) => writer.write_data(&chunk, strategy.compression),
+    })
+}
 
-    // Collect relative file paths for both directories
-    let paths1 =
-        collect_file_paths(path1).context("Failed to collect files from first directory")?;
-    let paths2 =
-        collect_file_paths(path2).context("Failed to collect files from second directory")?;
+/// Writes the entries describing the changes to the file at `rel_path` between `tree1` and
+/// `tree2`. Files in `rename_sources` are written as part of their rename in `renames` instead.
+fn write_path_entries(
+    writer: &mut PatchWriter<impl Write>,
+    tree1: &dyn FileTree,
+    tree2: &dyn FileTree,
+    rel_path: &Path,
+    renames: &HashMap<PathBuf, DetectedRename>,
+    rename_sources: &HashSet<&Path>,
+    options: &CreateOptions,
+) -> anyhow::Result<()> {
+    let exists_in_1 = tree1.kind(rel_path)? == Some(NodeKind::File);
+    let exists_in_2 = tree2.kind(rel_path)? == Some(NodeKind::File);
 
-    // Unique set of all file paths across both directories
-    let unique_paths: HashSet<PathBuf> = paths1.into_iter().chain(paths2).collect();
-    let mut unique_paths: Vec<_> = unique_paths.into_iter().collect();
-    unique_paths.sort();
-
-    for rel_path in unique_paths {
-        let file1 = path1.join(&rel_path);
-        let file2 = path2.join(&rel_path);
+    match (exists_in_1, exists_in_2) {
+        (true, true) => {
+            // File exists in both directories; compute modification patch
+            let before_hash = tree1.hash(rel_path)?;
+            let after_hash = tree2.hash(rel_path)?;
+            let before_metadata = recorded_metadata(tree1, rel_path, options)?;
+            let after_metadata = recorded_metadata(tree2, rel_path, options)?;
+            let strategy = options.strategy_for(rel_path, tree2.size(rel_path)?);
 
-        let exists_in_1 = file1.exists();
-        let exists_in_2 = file2.exists();
+            let contents_changed = before_hash != after_hash;
+            let mode_changed = before_metadata.as_ref().map(|meta| meta.mode)
+                != after_metadata.as_ref().map(|meta| meta.mode);
+            if !contents_changed && !mode_changed {
+                // No changes (a new modification time alone doesn't count), skip
+                return Ok(());
+            }
+            let text_hunks = if contents_changed {
+                recorded_text_hunks(tree1, tree2, rel_path, options)?
+            } else {
+                None
+            };
 
-        match (exists_in_1, exists_in_2) {
-            (true, true) => {
-                // File exists in both directories; compute modification patch
-                let data1 = read(&file1)
-                    .with_context(|| format!("Failed to read file: {}", file1.display()))?;
-                let data2 = read(&file2)
-                    .with_context(|| format!("Failed to read file: {}", file2.display()))?;
+            writer.write_entry(
+                &PatchEntry::new(
+                    PatchOperation::Modify {
+                        before_hash: before_hash.clone(),
+                        after_hash,
+                    },
+                    rel_path.to_string_lossy().to_string(),
+                )
+                .with_metadata(after_metadata)
+                .with_text_hunks(text_hunks),
+            )?;
+            if contents_changed {
+                write_chunk_deltas(
+                    writer,
+                    &mut tree1.open(rel_path)?,
+                    &mut tree2.open(rel_path)?,
+                    rel_path,
+                    strategy,
+                    PatchWriter::write_delta,
+                )?;
+            }
 
-                if data1 == data2 {
-                    // No changes, skip
-                    continue;
+            if options.reversible {
+                writer.write_inverse(&before_hash, before_metadata.as_ref())?;
+                if contents_changed {
+                    write_chunk_deltas(
+                        writer,
+                        &mut tree2.open(rel_path)?,
+                        &mut tree1.open(rel_path)?,
+                        rel_path,
+                        strategy,
+                        PatchWriter::write_reverse_delta,
+                    )?;
                 }
+            }
+        }
+        (true, false) => {
+            if rename_sources.contains(rel_path) {
+                // Written as part of the rename
+                return Ok(());
+            }
 
-                let patch = diff(
-                    &data1,
-                    &data2,
-                    DiffAlgorithm::Rsync020,
-                    CompressAlgorithm::Zstd,
-                )
-                .map_err(|e| {
-                    anyhow!(
-                        "Failed to compute diff for file: {}: {:?}",
-                        rel_path.display(),
-                        e
-                    )
-                })?;
+            // File removed in second directory
+            writer.write_entry(&PatchEntry::new(
+                PatchOperation::Remove,
+                rel_path.to_string_lossy().to_string(),
+            ))?;
 
-                entries.push(PatchEntry::new(
-                    PatchOperation::Modify(patch),
-                    rel_path.to_string_lossy().to_string(),
-                ));
+            if options.reversible {
+                writer.write_inverse(
+                    &tree1.hash(rel_path)?,
+                    recorded_metadata(tree1, rel_path, options)?.as_ref(),
+                )?;
+                let strategy = options.strategy_for(rel_path, tree1.size(rel_path)?);
+                write_data_chunks(
+                    writer,
+                    &mut tree1.open(rel_path)?,
+                    rel_path,
+                    strategy.compression,
+                )?;
             }
-            (true, false) => {
-                // File removed in second directory
-                entries.push(PatchEntry::new(
-                    PatchOperation::Remove,
+        }
+        (false, true) if renames.contains_key(rel_path) => {
+            // File moved from another path
+            let rename = &renames[rel_path];
+            let strategy = options.strategy_for(rel_path, tree2.size(rel_path)?);
+
+            writer.write_entry(
+                &PatchEntry::new(
+                    PatchOperation::Rename {
+                        from: rename.from.to_string_lossy().to_string(),
+                        before_hash: rename.before_hash.clone(),
+                        after_hash: rename.after_hash.clone(),
+                    },
                     rel_path.to_string_lossy().to_string(),
-                ));
+                )
+                .with_metadata(recorded_metadata(tree2, rel_path, options)?),
+            )?;
+
+            // Contents only need to be recorded if the file changed on the way
+            let contents_changed = rename.before_hash != rename.after_hash;
+            if contents_changed {
+                write_chunk_deltas(
+                    writer,
+                    &mut tree1.open(&rename.from)?,
+                    &mut tree2.open(rel_path)?,
+                    rel_path,
+                    strategy,
+                    PatchWriter::write_delta,
+                )?;
             }
-            (false, true) => {
-                // File added in second directory
-                let data2 = read(&file2)
-                    .with_context(|| format!("Failed to read file: {}", file2.display()))?;
 
-                entries.push(PatchEntry::new(
-                    PatchOperation::Add(data2),
-                    rel_path.to_string_lossy().to_string(),
-                ));
+            if options.reversible {
+                writer.write_inverse(
+                    &rename.before_hash,
+                    recorded_metadata(tree1, &rename.from, options)?.as_ref(),
+                )?;
+                if contents_changed {
+                    write_chunk_deltas(
+                        writer,
+                        &mut tree2.open(rel_path)?,
+                        &mut tree1.open(&rename.from)?,
+                        rel_path,
+                        strategy,
+                        PatchWriter::write_reverse_delta,
+                    )?;
+                }
             }
-            (false, false) => {
-                // Should never happen, but safe to ignore
+        }
+        (false, true) => {
+            // File added in second directory
+            writer.write_entry(
+                &PatchEntry::new(
+                    PatchOperation::Add {
+                        hash: tree2.hash(rel_path)?,
+                    },
+                    rel_path.to_string_lossy().to_string(),
+                )
+                .with_metadata(recorded_metadata(tree2, rel_path, options)?),
+            )?;
+            let strategy = options.strategy_for(rel_path, tree2.size(rel_path)?);
+            write_data_chunks(
+                writer,
+                &mut tree2.open(rel_path)?,
+                rel_path,
+                strategy.compression,
+            )?;
+        }
+        (false, false) => {
+            // Should never happen, but safe to ignore
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the entries describing the changes between `tree1` and `tree2`, listed in `listing1`
+/// and `listing2`.
+fn write_entries(
+    writer: &mut PatchWriter<impl Write>,
+    tree1: &dyn FileTree,
+    tree2: &dyn FileTree,
+    listing1: &TreeListing,
+    listing2: &TreeListing,
+    options: &CreateOptions,
+) -> anyhow::Result<()> {
+    let paths1 = &listing1.files;
+    let paths2 = &listing2.files;
+    // Pair removed files with added ones that hold the same or similar contents
+    let renames = if options.detect_renames {
+        let set1: HashSet<&PathBuf> = paths1.iter().collect();
+        let set2: HashSet<&PathBuf> = paths2.iter().collect();
+        let mut removed: Vec<PathBuf> = set1.difference(&set2).map(|p| p.to_path_buf()).collect();
+        let mut added: Vec<PathBuf> = set2.difference(&set1).map(|p| p.to_path_buf()).collect();
+        removed.sort();
+        added.sort();
+        detect_renames(tree1, tree2, &removed, &added)?
+    } else {
+        HashMap::new()
+    };
+    let rename_sources: HashSet<&Path> = renames
+        .values()
+        .map(|rename| rename.from.as_path())
+        .collect();
+
+    // Unique set of all file paths across both directories
+    let unique_paths: HashSet<PathBuf> = paths1.iter().chain(paths2).cloned().collect();
+    let mut unique_paths: Vec<_> = unique_paths.into_iter().collect();
+    unique_paths.sort();
+    progress::report(&options.progress, |progress| {
+        progress.started(Some(unique_paths.len() as u64))
+    });
+
+    // Diff batches of files in parallel, each into its own buffer, and write the buffers in
+    // order so the output doesn't depend on scheduling
+    // Files missing from one of the trees count as empty there
+    let size_in = |tree: &dyn FileTree, paths: &[PathBuf], rel_path: &PathBuf| match paths
+        .binary_search(rel_path)
+    {
+        Ok(_) => tree.size(rel_path),
+        Err(_) => Ok(0),
+    };
+    let sizes = unique_paths
+        .iter()
+        .map(|rel_path| Ok(size_in(tree1, paths1, rel_path)? + size_in(tree2, paths2, rel_path)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Writes the entries of the n-th path with `write`, checking for cancellation first
+    let write_nth = |n: usize, write: &mut dyn FnMut() -> anyhow::Result<()>| {
+        options.cancel.check()?;
+        let rel_path = unique_paths[n].to_string_lossy().replace("\\", "/");
+        progress::report(&options.progress, |progress| {
+            progress.entry_started(&rel_path)
+        });
+        write()?;
+        progress::report(&options.progress, |progress| {
+            progress.entry_finished(&rel_path, sizes[n])
+        });
+        anyhow::Ok(())
+    };
+
+    let mut start = 0;
+    while start < unique_paths.len() {
+        let mut end = start + 1;
+        let mut batch_size = sizes[start];
+        while end < unique_paths.len() && batch_size + sizes[end] <= PARALLEL_BATCH_SIZE {
+            batch_size += sizes[end];
+            end += 1;
+        }
+
+        if end - start == 1 {
+            // Large files are streamed straight into the package
+            write_nth(start, &mut || {
+                write_path_entries(
+                    writer,
+                    tree1,
+                    tree2,
+                    &unique_paths[start],
+                    &renames,
+                    &rename_sources,
+                    options,
+                )
+            })?;
+        } else {
+            let buffers = (start..end)
+                .into_par_iter()
+                .map(|n| {
+                    let mut buffer = PatchWriter::buffer();
+                    write_nth(n, &mut || {
+                        write_path_entries(
+                            &mut buffer,
+                            tree1,
+                            tree2,
+                            &unique_paths[n],
+                            &renames,
+                            &rename_sources,
+                            options,
+                        )
+                    })?;
+                    Ok(buffer)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for buffer in buffers {
+                writer.append(buffer)?;
             }
         }
+        start = end;
+    }
+
+    write_dir_entries(writer, &listing1.dirs, &listing2.dirs)
+}
+
+/// Writes the entries for directories that only exist in one of `dirs1` and `dirs2`.
+fn write_dir_entries(
+    writer: &mut PatchWriter<impl Write>,
+    dirs1: &[PathBuf],
+    dirs2: &[PathBuf],
+) -> anyhow::Result<()> {
+    let dirs1: HashSet<&PathBuf> = dirs1.iter().collect();
+    let dirs2: HashSet<&PathBuf> = dirs2.iter().collect();
+    let mut added: Vec<_> = dirs2.difference(&dirs1).collect();
+    added.sort();
+    for rel_path in added {
+        writer.write_entry(&PatchEntry::new(
+            PatchOperation::AddDir,
+            rel_path.to_string_lossy().to_string(),
+        ))?;
     }
 
-    // Serialize and write the patch package to file
-    let patch_package = PatchPackage::new(PATCH_PACKAGE_VERSION, entries);
-    let serialized = to_bytes::<Error>(&patch_package)
-        .map_err(|e| anyhow!("Failed to serialize patch package: {:?}", e))?;
+    let mut removed: Vec<_> = dirs1.difference(&dirs2).collect();
+    removed.sort();
+    for rel_path in removed {
+        writer.write_entry(&PatchEntry::new(
+            PatchOperation::RemoveDir,
+            rel_path.to_string_lossy().to_string(),
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Creates a patch file that represents changes between `path1` and `path2`.
+/// Files are processed in chunks and entries are written as they are produced, so memory use
+/// does not depend on the size of the trees. Small files are hashed and diffed in parallel, and
+/// the same trees always produce the same package.
+pub fn create_patch(patch_loc: &Path, path1: &Path, path2: &Path) -> PatchResult<CreateReport> {
+    create_patch_with_options(patch_loc, path1, path2, &CreateOptions::default())
+}
+
+/// Creates a patch file that represents changes between `path1` and `path2`, see [`create_patch`].
+/// The package info records a hash of the whole first tree, which [`apply_patch`] checks.
+///
+/// Paths matched by [`CreateOptions::ignore`] or the [`IGNORE_FILE_NAME`] file of
+/// [`CreateOptions::project_root`] are left out of both trees and listed in the report.
+///
+/// Every file of both trees is reported to [`CreateOptions::progress`] as it is compared.
+/// Cancelling [`CreateOptions::cancel`] stops before the next file and removes the partial
+/// package.
+pub fn create_patch_with_options(
+    patch_loc: &Path,
+    path1: &Path,
+    path2: &Path,
+    options: &CreateOptions,
+) -> PatchResult<CreateReport> {
+    create_patch_from_trees(
+        patch_loc,
+        &DirTree::new(path1),
+        &DirTree::new(path2),
+        options,
+    )
+}
+
+/// Creates a patch file that represents changes between two trees of any kind, see
+/// [`create_patch_with_options`]. E.g. two zipped builds can be compared with [`ArchiveTree`]
+/// without unpacking them.
+pub fn create_patch_from_trees(
+    patch_loc: &Path,
+    tree1: &dyn FileTree,
+    tree2: &dyn FileTree,
+    options: &CreateOptions,
+) -> PatchResult<CreateReport> {
+    let exclude = ExcludeRules::new(options)?;
+    let listing1 = exclude
+        .list(tree1)
+        .context("Failed to collect files from first tree")?;
+    let listing2 = exclude
+        .list(tree2)
+        .context("Failed to collect files from second tree")?;
+
+    let info = PackageInfo::new(tree1, options)?;
+    write_patch_file(patch_loc, &info, CHUNK_SIZE, |writer| {
+        write_entries(writer, tree1, tree2, &listing1, &listing2, options)
+    })?;
 
-    write(patch_loc, serialized)
-        .with_context(|| format!("Failed to write patch file: {}", patch_loc.display()))?;
+    let mut ignored: Vec<String> = listing1
+        .excluded
+        .iter()
+        .chain(&listing2.excluded)
+        .map(|rel_path| rel_path.to_string_lossy().replace("\\", "/"))
+        .collect();
+    ignored.sort();
+    ignored.dedup();
+    Ok(CreateReport { ignored })
+}
+
+/// Writes a new patch package to `patch_loc` with the given info and chunk size, with its entries
+/// written by `write`. Removes the partially written file if anything fails.
+fn write_patch_file(
+    patch_loc: &Path,
+    info: &PackageInfo,
+    chunk_size: u64,
+    write: impl FnOnce(&mut PatchWriter<BufWriter<File>>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // Opened for reading too, the checksum is computed from the finished file
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(patch_loc)
+        .map_err(|source| PatchError::io(patch_loc, source))?;
+
+    let result = PatchWriter::new(
+        BufWriter::new(file),
+        &PackageHeader {
+            version: PATCH_PACKAGE_VERSION,
+            chunk_size,
+        },
+    )
+    .and_then(|mut writer| {
+        writer.write_info(info)?;
+        write(&mut writer)?;
+        let mut file = writer
+            .finish_with_stats()?
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to flush patch file")?;
+        append_checksum(&mut file)
+    });
+
+    if let Err(e) = result {
+        // Don't leave a partial patch file behind
+        let _ = remove_file(patch_loc);
+        return Err(e.context(format!(
+            "Failed to write patch file: {}",
+            patch_loc.display()
+        )));
+    }
 
     Ok(())
 }
@@ -175,8 +816,10 @@ fn verify_no_symlinks_in_path(path: &Path) -> anyhow::Result<()> {
         if let Ok(meta) = symlink_metadata(&cur) {
             ensure!(
                 !meta.file_type().is_symlink(),
-                "Refusing to traverse symlink component: {}",
-                cur.display()
+                PatchError::Symlink {
+                    path: cur,
+                    entry: None,
+                }
             );
         }
     }
@@ -208,156 +851,1555 @@ fn remove_empty_parents(file_path: &Path, target_path: &Path) {
     }
 }
 
-/// Applies a patch package to a target directory.
-/// NOTE: It is recommended to back up data before applying patches. This operation may corrupt data.
-pub fn apply_patch(patch_loc: &Path, target_path: &Path) -> anyhow::Result<()> {
-    // Verify target path is not a symlink
-    let meta = symlink_metadata(target_path)?;
+/// Streams the data chunks of an added file into `out`, verifying the hash of the result.
+fn write_added_file(
+    reader: &mut PatchReader<impl Read>,
+    file_path: &Path,
+    out: &mut impl Write,
+    expected_hash: &str,
+) -> anyhow::Result<()> {
+    let mut context = md5::Context::new();
+
+    while let Some(data) = reader.next_data()? {
+        context.consume(&*data);
+        out.write_all(&data)
+            .with_context(|| format!("Failed to write added file: {}", file_path.display()))?;
+    }
+    out.flush()
+        .with_context(|| format!("Failed to write added file: {}", file_path.display()))?;
+
+    let actual = format!("{:x}", context.compute());
     ensure!(
-        !meta.file_type().is_symlink(),
-        "Target path must not be a symlink"
+        actual == expected_hash,
+        PatchError::CorruptData {
+            path: file_path.to_path_buf(),
+            expected: expected_hash.to_string(),
+            actual,
+            entry: None,
+        }
     );
 
-    // Read the serialized patch package
-    let patch_data = read(patch_loc)
-        .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()))?;
+    Ok(())
+}
 
-    // Access archived patch package (without full deserialization - calling methods on it is unsafe)
-    let patch_package_archive = access::<ArchivedPatchPackage, Error>(&patch_data)
-        .map_err(|e| anyhow!("Failed to access archived patch package: {:?}", e))?;
+/// Applies the chunk changes returned by `next_change` to the `original` contents of `file_path`,
+/// writing the result to `out`. Verifies the hash of the result.
+fn write_modified_file<R: Read>(
+    reader: &mut PatchReader<R>,
+    next_change: fn(&mut PatchReader<R>) -> anyhow::Result<Option<ChunkChange<Payload>>>,
+    file_path: &Path,
+    original: &mut impl Read,
+    out: &mut impl Write,
+    chunk_size: u64,
+    expected_hash: &str,
+) -> anyhow::Result<()> {
+    let mut context = md5::Context::new();
+    let mut original_chunk = Vec::new();
 
-    // Deserialize patch package
-    let patch_package = deserialize::<PatchPackage, Error>(patch_package_archive)
-        .map_err(|e| anyhow!("Failed to deserialize patch package: {:?}", e))?;
+    while let Some(change) = next_change(reader)? {
+        // Replaced chunks still take the place of a chunk of the original
+        read_chunk(original, &mut original_chunk, chunk_size).with_context(|| {
+            format!(
+                "Failed to read file for modification: {}",
+                file_path.display()
+            )
+        })?;
 
-    // Verify version compatibility
+        let patched;
+        let modified_chunk: &[u8] = match &change {
+            ChunkChange::Delta(patch) => {
+                patched = apply(&original_chunk, patch).map_err(|e| {
+                    anyhow!(
+                        "Failed to apply patch to file {}: {:?}",
+                        file_path.display(),
+                        e
+                    )
+                })?;
+                &patched
+            }
+            ChunkChange::Replace(chunk) => chunk,
+        };
+
+        context.consume(modified_chunk);
+        out.write_all(modified_chunk)
+            .with_context(|| format!("Failed to write modified file: {}", file_path.display()))?;
+    }
+    out.flush()
+        .with_context(|| format!("Failed to write modified file: {}", file_path.display()))?;
+
+    // Verify the hash after applying patch
+    let actual = format!("{:x}", context.compute());
+    ensure!(
+        actual == expected_hash,
+        PatchError::CorruptData {
+            path: file_path.to_path_buf(),
+            expected: expected_hash.to_string(),
+            actual,
+            entry: None,
+        }
+    );
+
+    Ok(())
+}
+
+/// Streams the new contents of an entry into `out`, verifying them against the entry's hashes.
+/// `open_original` opens the file the new contents are based on: `file_path` itself, or the
+/// source of a rename. Entries without contents (removals and directories) write nothing.
+fn write_entry_contents<O: Read>(
+    reader: &mut PatchReader<impl Read>,
+    operation: &PatchOperation,
+    file_path: &Path,
+    open_original: impl FnOnce() -> anyhow::Result<O>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let chunk_size = reader.header().chunk_size;
+    match operation {
+        PatchOperation::Add { hash } => write_added_file(reader, file_path, out, hash),
+        PatchOperation::Remove | PatchOperation::AddDir | PatchOperation::RemoveDir => Ok(()),
+        PatchOperation::Modify {
+            before_hash,
+            after_hash,
+        }
+        | PatchOperation::Rename {
+            before_hash,
+            after_hash,
+            ..
+        } if before_hash == after_hash => {
+            // Contents are unchanged (moved as is or only new metadata), the hash of the
+            // original was checked when validating
+            copy(&mut open_original()?, out)
+                .and_then(|_| out.flush())
+                .with_context(|| format!("Failed to write file: {}", file_path.display()))?;
+            Ok(())
+        }
+        PatchOperation::Modify { after_hash, .. } | PatchOperation::Rename { after_hash, .. } => {
+            write_modified_file(
+                reader,
+                PatchReader::next_change,
+                file_path,
+                &mut open_original()?,
+                out,
+                chunk_size,
+                after_hash,
+            )
+        }
+    }
+}
+
+/// Resolves the path of a patch entry inside `target_path`, refusing paths that escape it,
+/// point into the transaction directory or traverse symlinks.
+fn resolve_entry_path(target_path: &Path, rel_path: &str) -> anyhow::Result<PathBuf> {
+    let joined = target_path.join(rel_path);
+
+    // Normalize path without touching filesystem
+    let normalized = joined.components().fold(PathBuf::new(), |mut acc, c| {
+        match c {
+            std::path::Component::ParentDir => {
+                acc.pop();
+            }
+            std::path::Component::CurDir => {
+                // Skip "." components
+            }
+            _ => {
+                acc.push(c);
+            }
+        }
+        acc
+    });
+
+    // Ensure it stays within target_path
+    ensure!(
+        normalized.starts_with(target_path) && normalized != target_path,
+        PatchError::PathEscape {
+            path: rel_path.to_string(),
+            entry: None,
+        }
+    );
+
+    // The transaction directory is off limits
+    ensure!(
+        !normalized.starts_with(target_path.join(TRANSACTION_DIR)),
+        "Patch entry path {} points into the transaction directory",
+        rel_path
+    );
+
+    // Defense in depth: verify no symlinks in the entire path
+    verify_no_symlinks_in_path(&normalized)?;
+
+    Ok(normalized)
+}
+
+/// What applying a single entry would do to the target directory.
+enum PlannedChange {
+    Create,                 // File is created
+    Overwrite,              // Added file replaces an existing file
+    Modify,                 // File is modified
+    FuzzyModify(TextHunks), // File changed since the patch was made, patched from its text hunks
+    Remove,                 // File is removed
+    Missing,                // File or directory marked for removal does not exist, nothing to do
+    Rename(PathBuf),        // File is moved from the given path
+    CreateDir,              // Directory is created
+    ExistingDir,            // Directory to add already exists, nothing to do
+    RemoveDir,              // Directory is removed
+    AlreadyApplied,         // File already holds what the entry leaves it with, nothing to do
+}
+
+/// Plans the modification of a file whose current contents hash to `hash`. Files that don't match
+/// `before_hash` are patched from `text_hunks` if given, files that already match `after_hash`
+/// are left as they are.
+fn plan_modify(
+    hash: &str,
+    before_hash: &str,
+    after_hash: &str,
+    text_hunks: Option<&TextHunks>,
+    file_path: &Path,
+) -> anyhow::Result<PlannedChange> {
+    if hash != before_hash && hash == after_hash {
+        return Ok(PlannedChange::AlreadyApplied);
+    }
+    if let Some(text_hunks) = text_hunks.filter(|_| hash != before_hash) {
+        return Ok(PlannedChange::FuzzyModify(text_hunks.clone()));
+    }
+    ensure!(
+        hash == before_hash,
+        PatchError::HashMismatch {
+            path: file_path.to_path_buf(),
+            expected: before_hash.to_string(),
+            actual: hash.to_string(),
+            entry: None,
+        }
+    );
+    Ok(PlannedChange::Modify)
+}
+
+/// Plans replacing a file whose current contents hash to `hash` with an added file. With `strict`,
+/// files that don't already hold the added contents are refused, since the package doesn't know
+/// what they held.
+fn plan_overwrite(
+    hash: String,
+    added_hash: &str,
+    strict: bool,
+    file_path: &Path,
+) -> anyhow::Result<PlannedChange> {
+    if hash == added_hash {
+        return Ok(PlannedChange::AlreadyApplied);
+    }
     ensure!(
-        patch_package.version == PATCH_PACKAGE_VERSION,
-        "Unsupported patch version: {} (expected {})",
-        patch_package.version,
-        PATCH_PACKAGE_VERSION
+        !strict,
+        PatchError::HashMismatch {
+            path: file_path.to_path_buf(),
+            expected: added_hash.to_string(),
+            actual: hash,
+            entry: None,
+        }
     );
+    Ok(PlannedChange::Overwrite)
+}
+
+/// Checks an entry against the current state of `file_path` without changing anything.
+/// Modified files whose contents don't match are patched from `text_hunks` if given. With
+/// `strict`, only files as they were before or after the entry are changed, for targets that
+/// aren't the tree the package was created from.
+fn validate_entry(
+    operation: &PatchOperation,
+    target_path: &Path,
+    file_path: &Path,
+    text_hunks: Option<&TextHunks>,
+    strict: bool,
+) -> anyhow::Result<PlannedChange> {
+    match operation {
+        PatchOperation::Add { hash } => {
+            if !file_path.exists() {
+                return Ok(PlannedChange::Create);
+            }
+
+            // Final check: ensure we're not overwriting a symlink or a directory
+            let meta = symlink_metadata(file_path)?;
+            ensure!(
+                !meta.file_type().is_symlink(),
+                PatchError::Symlink {
+                    path: file_path.to_path_buf(),
+                    entry: None,
+                }
+            );
+            ensure!(
+                meta.is_file(),
+                PatchError::NotAFile {
+                    path: file_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            plan_overwrite(hash_file(file_path)?, hash, strict, file_path)
+        }
+        PatchOperation::Remove => {
+            if !file_path.exists() {
+                return Ok(PlannedChange::Missing);
+            }
+
+            // Final check: ensure we're not removing a symlink or a directory
+            let meta = symlink_metadata(file_path)?;
+            ensure!(
+                !meta.file_type().is_symlink(),
+                PatchError::Symlink {
+                    path: file_path.to_path_buf(),
+                    entry: None,
+                }
+            );
+            ensure!(
+                meta.is_file(),
+                PatchError::NotAFile {
+                    path: file_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            Ok(PlannedChange::Remove)
+        }
+        PatchOperation::Modify {
+            before_hash,
+            after_hash,
+        } => {
+            // Final check: ensure we're not modifying a symlink
+            let meta = symlink_metadata(file_path).map_err(|_| PatchError::MissingFile {
+                path: file_path.to_path_buf(),
+                entry: None,
+            })?;
+            ensure!(
+                !meta.file_type().is_symlink(),
+                PatchError::Symlink {
+                    path: file_path.to_path_buf(),
+                    entry: None,
+                }
+            );
 
-    for entry in patch_package.entries {
-        let joined = target_path.join(&entry.rel_path);
+            // Verify the hash before applying patch
+            let hash = hash_file(file_path)?;
+            plan_modify(&hash, before_hash, after_hash, text_hunks, file_path)
+        }
+        PatchOperation::Rename {
+            from,
+            before_hash,
+            after_hash,
+        } => {
+            let from_path = resolve_entry_path(target_path, from)?;
+
+            // Final check: ensure we're only moving a regular file
+            let Ok(meta) = symlink_metadata(&from_path) else {
+                // The file may have been moved already
+                let moved = symlink_metadata(file_path).is_ok_and(|meta| meta.is_file())
+                    && &hash_file(file_path)? == after_hash;
+                ensure!(
+                    moved,
+                    PatchError::MissingFile {
+                        path: from_path,
+                        entry: None,
+                    }
+                );
+                return Ok(PlannedChange::AlreadyApplied);
+            };
+            ensure!(
+                !meta.file_type().is_symlink(),
+                PatchError::Symlink {
+                    path: from_path.to_path_buf(),
+                    entry: None,
+                }
+            );
+            ensure!(
+                meta.is_file(),
+                PatchError::NotAFile {
+                    path: from_path.to_path_buf(),
+                    entry: None
+                }
+            );
+
+            // Verify the hash before moving the file
+            let hash = hash_file(&from_path)?;
+            ensure!(
+                &hash == before_hash,
+                PatchError::HashMismatch {
+                    path: from_path,
+                    expected: before_hash.clone(),
+                    actual: hash,
+                    entry: None,
+                }
+            );
+
+            ensure!(
+                symlink_metadata(file_path).is_err(),
+                PatchError::PathOccupied {
+                    path: file_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            Ok(PlannedChange::Rename(from_path))
+        }
+        PatchOperation::AddDir => {
+            let Ok(meta) = symlink_metadata(file_path) else {
+                return Ok(PlannedChange::CreateDir);
+            };
+            ensure!(
+                meta.is_dir(),
+                PatchError::NotADirectory {
+                    path: file_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            Ok(PlannedChange::ExistingDir)
+        }
+        PatchOperation::RemoveDir => {
+            let Ok(meta) = symlink_metadata(file_path) else {
+                return Ok(PlannedChange::Missing);
+            };
+
+            // Final check: ensure we're only removing a real directory
+            ensure!(
+                !meta.file_type().is_symlink(),
+                PatchError::Symlink {
+                    path: file_path.to_path_buf(),
+                    entry: None,
+                }
+            );
+            ensure!(
+                meta.is_dir(),
+                PatchError::NotADirectory {
+                    path: file_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            Ok(PlannedChange::RemoveDir)
+        }
+    }
+}
 
-        // Normalize path without touching filesystem
-        let normalized = joined.components().fold(PathBuf::new(), |mut acc, c| {
-            match c {
-                std::path::Component::ParentDir => {
-                    acc.pop();
+/// Checks an entry against the current state of `rel_path` in `tree` without changing anything,
+/// see [`validate_entry`].
+fn validate_tree_entry(
+    operation: &PatchOperation,
+    tree: &dyn FileTree,
+    rel_path: &Path,
+    text_hunks: Option<&TextHunks>,
+    strict: bool,
+) -> anyhow::Result<PlannedChange> {
+    let kind = tree.kind(rel_path)?;
+    match operation {
+        PatchOperation::Add { hash } => match kind {
+            None => Ok(PlannedChange::Create),
+            Some(NodeKind::File) => plan_overwrite(tree.hash(rel_path)?, hash, strict, rel_path),
+            Some(NodeKind::Dir) => bail!(PatchError::NotAFile {
+                path: rel_path.to_path_buf(),
+                entry: None
+            }),
+        },
+        PatchOperation::Remove => match kind {
+            None => Ok(PlannedChange::Missing),
+            Some(NodeKind::File) => Ok(PlannedChange::Remove),
+            Some(NodeKind::Dir) => bail!(PatchError::NotAFile {
+                path: rel_path.to_path_buf(),
+                entry: None
+            }),
+        },
+        PatchOperation::Modify {
+            before_hash,
+            after_hash,
+        } => {
+            ensure!(
+                kind == Some(NodeKind::File),
+                PatchError::MissingFile {
+                    path: rel_path.to_path_buf(),
+                    entry: None,
                 }
-                std::path::Component::CurDir => {
-                    // Skip "." components
+            );
+            let hash = tree.hash(rel_path)?;
+            plan_modify(&hash, before_hash, after_hash, text_hunks, rel_path)
+        }
+        PatchOperation::Rename {
+            from,
+            before_hash,
+            after_hash,
+        } => {
+            let from_path = check_rel_path(from)?;
+            let from_kind = tree.kind(&from_path)?;
+            if from_kind.is_none()
+                && kind == Some(NodeKind::File)
+                && &tree.hash(rel_path)? == after_hash
+            {
+                // The file was moved already
+                return Ok(PlannedChange::AlreadyApplied);
+            }
+            ensure!(
+                from_kind == Some(NodeKind::File),
+                PatchError::MissingFile {
+                    path: from_path,
+                    entry: None,
                 }
-                _ => {
-                    acc.push(c);
+            );
+            let hash = tree.hash(&from_path)?;
+            ensure!(
+                &hash == before_hash,
+                PatchError::HashMismatch {
+                    path: from_path,
+                    expected: before_hash.clone(),
+                    actual: hash,
+                    entry: None,
                 }
+            );
+            ensure!(
+                kind.is_none(),
+                PatchError::PathOccupied {
+                    path: rel_path.to_path_buf(),
+                    entry: None
+                }
+            );
+            Ok(PlannedChange::Rename(from_path))
+        }
+        PatchOperation::AddDir => match kind {
+            None => Ok(PlannedChange::CreateDir),
+            Some(NodeKind::Dir) => Ok(PlannedChange::ExistingDir),
+            Some(NodeKind::File) => bail!(PatchError::NotADirectory {
+                path: rel_path.to_path_buf(),
+                entry: None
+            }),
+        },
+        PatchOperation::RemoveDir => match kind {
+            None => Ok(PlannedChange::Missing),
+            Some(NodeKind::Dir) => Ok(PlannedChange::RemoveDir),
+            Some(NodeKind::File) => {
+                bail!(PatchError::NotADirectory {
+                    path: rel_path.to_path_buf(),
+                    entry: None
+                })
             }
-            acc
+        },
+    }
+}
+
+/// Writes the new contents of an entry to `staged_path` and applies `metadata` to them.
+/// Files moved as is are hard linked from `original_path` when possible instead of copied.
+fn stage_file(
+    reader: &mut PatchReader<impl Read>,
+    operation: &PatchOperation,
+    file_path: &Path,
+    original_path: &Path,
+    staged_path: &Path,
+    metadata: Option<&FileMetadata>,
+) -> anyhow::Result<()> {
+    if let PatchOperation::Rename {
+        before_hash,
+        after_hash,
+        ..
+    } = operation
+    {
+        // The link shares metadata with the original, so only link if it stays as it is
+        if before_hash == after_hash
+            && metadata.is_none()
+            && hard_link(original_path, staged_path).is_ok()
+        {
+            return Ok(());
+        }
+    }
+
+    let file = File::create(staged_path).map_err(|source| PatchError::io(staged_path, source))?;
+    write_entry_contents(
+        reader,
+        operation,
+        file_path,
+        || open_file(original_path),
+        &mut BufWriter::new(file),
+    )?;
+
+    if let Some(metadata) = metadata {
+        metadata.restore(staged_path)?;
+    }
+    Ok(())
+}
+
+/// Patches the current contents of `file_path` hunk by hunk, writes the result to `staged_path`
+/// and applies `metadata` to it. Returns the outcome of every hunk.
+fn stage_fuzzy_file(
+    file_path: &Path,
+    staged_path: &Path,
+    text_hunks: &TextHunks,
+    metadata: Option<&FileMetadata>,
+) -> anyhow::Result<Vec<HunkStatus>> {
+    let contents = std::fs::read(file_path).map_err(|source| PatchError::io(file_path, source))?;
+    let (patched, hunks) = text_hunks.apply(&contents).with_context(|| {
+        format!(
+            "Failed to apply text hunks to file: {}",
+            file_path.display()
+        )
+    })?;
+    std::fs::write(staged_path, patched).map_err(|source| PatchError::io(staged_path, source))?;
+
+    if let Some(metadata) = metadata {
+        metadata.restore(staged_path)?;
+    }
+    Ok(hunks)
+}
+
+/// Returns the metadata the file written by `entry` is given, leaving out what `options` don't
+/// let the package set.
+fn entry_metadata(entry: &PatchEntry, options: &ApplyOptions) -> Option<FileMetadata> {
+    let metadata = entry
+        .metadata
+        .as_ref()
+        .filter(|_| !options.ignore_metadata)?;
+    Some(if options.special_mode_bits {
+        metadata.clone()
+    } else {
+        metadata.without_special_bits()
+    })
+}
+
+/// Options for [`apply_patch_with_options`].
+#[derive(Default)]
+pub struct ApplyOptions {
+    pub ignore_metadata: bool, // Leave permissions and modification times recorded in the patch out
+    pub special_mode_bits: bool, // Also restore setuid, setgid and sticky bits recorded in the patch
+    pub trusted_keys: Vec<VerifyingKey>, // Keys packages must be signed with
+    pub allow_untrusted: bool,   // Also apply unsigned packages and packages signed with other keys
+    pub polytrack_version: Option<String>, // PolyTrack version of the target, checked against the package, see `apply_patch`
+    pub ignore_base_tree: bool, // Apply to targets that are not the base tree, checking every entry instead
+    pub fuzzy: bool, // Patch modified text files that changed since from their text hunks, see `apply_patch`
+    pub progress: Option<Arc<dyn ProgressObserver>>, // Notified as entries are staged
+    pub cancel: CancelToken, // Checked between entries, cancelling leaves the target untouched
+    pub filter: EntryFilter, // Entries to apply, the others are skipped and listed in the report
+}
+
+/// Validates every entry of the package selected by `selector` and stages its changes in
+/// `transaction`, adding the files patched from their text hunks and the skipped entries to
+/// `report`. Returns the paths of the files that will be removed. Entries are checked with
+/// `strict` if the target isn't the tree the package was created from, see [`validate_entry`].
+///
+/// Directories are removed once every entry was seen, and only if no skipped entry is in them,
+/// since they wouldn't be empty otherwise.
+fn stage_entries(
+    reader: &mut PatchReader<impl Read>,
+    transaction: &mut Transaction,
+    target_path: &Path,
+    options: &ApplyOptions,
+    strict: bool,
+    selector: &EntrySelector,
+    report: &mut ApplyReport,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let mut removed_dirs = Vec::new();
+    // Paths of skipped entries and where skipped renames come from, which stay as they are
+    let mut left_in_place = Vec::new();
+
+    for index in 0.. {
+        options.cancel.check()?;
+        let Some(entry) = reader.next_entry()? else {
+            break;
+        };
+        progress::report(&options.progress, |progress| {
+            progress.entry_started(&entry.rel_path)
+        });
+
+        if !selector.selects(&entry.operation, &entry.rel_path) {
+            if let PatchOperation::Rename { from, .. } = &entry.operation {
+                left_in_place.push(from.clone());
+            }
+            left_in_place.push(entry.rel_path.clone());
+            report.skipped.push(entry.rel_path.clone());
+            progress::report(&options.progress, |progress| {
+                progress.entry_finished(&entry.rel_path, 0)
+            });
+            continue;
+        }
+
+        let file_path =
+            resolve_entry_path(target_path, &entry.rel_path).map_err(|e| at_entry(e, index))?;
+        let metadata = entry_metadata(&entry, options);
+        let text_hunks = entry.text_hunks.as_ref().filter(|_| options.fuzzy);
+
+        let staged_path = match validate_entry(
+            &entry.operation,
+            target_path,
+            &file_path,
+            text_hunks,
+            strict,
+        )
+        .map_err(|e| at_entry(e, index))?
+        {
+            PlannedChange::Create | PlannedChange::Overwrite | PlannedChange::Modify => {
+                let staged_path = transaction.stage_write(&file_path)?;
+                stage_file(
+                    reader,
+                    &entry.operation,
+                    &file_path,
+                    &file_path,
+                    &staged_path,
+                    metadata.as_ref(),
+                )
+                .map_err(|e| at_entry(e, index))?;
+                Some(staged_path)
+            }
+            PlannedChange::FuzzyModify(text_hunks) => {
+                let staged_path = transaction.stage_write(&file_path)?;
+                let hunks =
+                    stage_fuzzy_file(&file_path, &staged_path, &text_hunks, metadata.as_ref())
+                        .map_err(|e| at_entry(e, index))?;
+                report.fuzzy.push(FuzzyFile {
+                    rel_path: entry.rel_path.clone(),
+                    hunks,
+                });
+                Some(staged_path)
+            }
+            PlannedChange::Rename(from_path) => {
+                let staged_path = transaction.stage_write(&file_path)?;
+                stage_file(
+                    reader,
+                    &entry.operation,
+                    &file_path,
+                    &from_path,
+                    &staged_path,
+                    metadata.as_ref(),
+                )
+                .map_err(|e| at_entry(e, index))?;
+                transaction.stage_remove(&from_path)?;
+                removed.push(from_path);
+                Some(staged_path)
+            }
+            PlannedChange::Remove => {
+                transaction.stage_remove(&file_path)?;
+                removed.push(file_path);
+                None
+            }
+            PlannedChange::CreateDir => {
+                transaction.stage_create_dir(&file_path)?;
+                None
+            }
+            PlannedChange::RemoveDir => {
+                removed_dirs.push((entry.rel_path.clone(), file_path));
+                None
+            }
+            PlannedChange::Missing | PlannedChange::ExistingDir | PlannedChange::AlreadyApplied => {
+                report.already_applied.push(entry.rel_path.clone());
+                None
+            }
+        };
+
+        let written = staged_path.map_or(Ok(0), |staged_path| file_size(&staged_path))?;
+        progress::report(&options.progress, |progress| {
+            progress.entry_finished(&entry.rel_path, written)
+        });
+    }
+
+    for (rel_path, dir_path) in removed_dirs {
+        let holds_skipped = left_in_place
+            .iter()
+            .any(|skipped| Path::new(skipped).starts_with(&rel_path));
+        if holds_skipped {
+            report.skipped.push(rel_path);
+        } else {
+            transaction.stage_remove_dir(&dir_path)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// A change to a tree, staged in memory until every entry was validated.
+enum TreeChange {
+    // File is written with the given contents
+    Write {
+        rel_path: PathBuf,
+        contents: Vec<u8>,
+        metadata: Option<FileMetadata>,
+    },
+    // File is removed
+    Remove(PathBuf),
+    // Directory is created
+    CreateDir(PathBuf),
+    // Empty directory is removed
+    RemoveDir(PathBuf),
+}
+
+/// Validates every entry of the package selected by `selector` against `tree` and returns the
+/// changes to make to it, in order, see [`stage_entries`].
+fn stage_tree_entries(
+    reader: &mut PatchReader<impl Read>,
+    tree: &dyn FileTree,
+    options: &ApplyOptions,
+    strict: bool,
+    selector: &EntrySelector,
+    report: &mut ApplyReport,
+) -> anyhow::Result<Vec<TreeChange>> {
+    let mut changes = Vec::new();
+    let mut removed_dirs = Vec::new();
+    // Paths of skipped entries and where skipped renames come from, which stay as they are
+    let mut left_in_place = Vec::new();
+    let mut seen = HashSet::new();
+
+    for index in 0.. {
+        options.cancel.check()?;
+        let Some(entry) = reader.next_entry()? else {
+            break;
+        };
+        progress::report(&options.progress, |progress| {
+            progress.entry_started(&entry.rel_path)
         });
 
-        // Ensure it stays within target_path
+        if !selector.selects(&entry.operation, &entry.rel_path) {
+            if let PatchOperation::Rename { from, .. } = &entry.operation {
+                left_in_place.push(from.clone());
+            }
+            left_in_place.push(entry.rel_path.clone());
+            report.skipped.push(entry.rel_path.clone());
+            progress::report(&options.progress, |progress| {
+                progress.entry_finished(&entry.rel_path, 0)
+            });
+            continue;
+        }
+
+        let rel_path = check_rel_path(&entry.rel_path).map_err(|e| at_entry(e, index))?;
         ensure!(
-            normalized.starts_with(target_path),
-            "Patch entry path {} escapes target directory {}",
-            entry.rel_path,
-            target_path.display()
+            seen.insert(rel_path.clone()),
+            PatchError::DuplicateEntry {
+                path: rel_path,
+                entry: Some(index),
+            }
         );
+        let metadata = entry_metadata(&entry, options);
+        let text_hunks = entry.text_hunks.as_ref().filter(|_| options.fuzzy);
 
-        let file_path = normalized;
+        let mut written = 0;
+        match validate_tree_entry(&entry.operation, tree, &rel_path, text_hunks, strict)
+            .map_err(|e| at_entry(e, index))?
+        {
+            change @ (PlannedChange::Create
+            | PlannedChange::Overwrite
+            | PlannedChange::Modify
+            | PlannedChange::Rename(_)) => {
+                let original = match &change {
+                    PlannedChange::Rename(from_path) => {
+                        ensure!(
+                            seen.insert(from_path.clone()),
+                            PatchError::DuplicateEntry {
+                                path: from_path.clone(),
+                                entry: Some(index),
+                            }
+                        );
+                        from_path
+                    }
+                    _ => &rel_path,
+                };
+                let mut contents = Vec::new();
+                write_entry_contents(
+                    reader,
+                    &entry.operation,
+                    &rel_path,
+                    || Ok(tree.open(original)?),
+                    &mut contents,
+                )
+                .map_err(|e| at_entry(e, index))?;
+                written = contents.len() as u64;
+                changes.push(TreeChange::Write {
+                    rel_path: rel_path.clone(),
+                    contents,
+                    metadata,
+                });
+                if let PlannedChange::Rename(from_path) = change {
+                    changes.push(TreeChange::Remove(from_path));
+                }
+            }
+            PlannedChange::FuzzyModify(text_hunks) => {
+                let original = tree
+                    .read(&rel_path)
+                    .map_err(|e| at_entry(e.into(), index))?;
+                let (contents, hunks) = text_hunks
+                    .apply(&original)
+                    .with_context(|| {
+                        format!("Failed to apply text hunks to file: {}", rel_path.display())
+                    })
+                    .map_err(|e| at_entry(e, index))?;
+                report.fuzzy.push(FuzzyFile {
+                    rel_path: entry.rel_path.clone(),
+                    hunks,
+                });
+                written = contents.len() as u64;
+                changes.push(TreeChange::Write {
+                    rel_path,
+                    contents,
+                    metadata,
+                });
+            }
+            PlannedChange::Remove => changes.push(TreeChange::Remove(rel_path)),
+            PlannedChange::CreateDir => changes.push(TreeChange::CreateDir(rel_path)),
+            PlannedChange::RemoveDir => removed_dirs.push((entry.rel_path.clone(), rel_path)),
+            PlannedChange::Missing | PlannedChange::ExistingDir | PlannedChange::AlreadyApplied => {
+                report.already_applied.push(entry.rel_path.clone())
+            }
+        }
+
+        progress::report(&options.progress, |progress| {
+            progress.entry_finished(&entry.rel_path, written)
+        });
+    }
 
-        // Defense in depth: verify no symlinks in the entire path
-        verify_no_symlinks_in_path(&file_path)?;
+    // Directories are removed after the files in them, deepest first
+    removed_dirs.sort_by(|a, b| b.1.cmp(&a.1));
+    for (rel_path, dir_path) in removed_dirs {
+        let holds_skipped = left_in_place
+            .iter()
+            .any(|skipped| Path::new(skipped).starts_with(&rel_path));
+        if holds_skipped {
+            report.skipped.push(rel_path);
+        } else {
+            changes.push(TreeChange::RemoveDir(dir_path));
+        }
+    }
 
-        match entry.operation {
-            PatchOperation::Add(data) => {
-                // Ensure parent directories exist
-                if let Some(parent) = file_path.parent() {
-                    create_dir_all(parent).with_context(|| {
+    Ok(changes)
+}
+
+/// Removes the directories of `tree` left empty by removing the files at `removed`, see
+/// [`remove_empty_parents`].
+fn remove_empty_tree_parents(tree: &mut dyn FileTree, removed: &[PathBuf]) -> anyhow::Result<()> {
+    let mut remaining: BTreeSet<PathBuf> = tree
+        .list(&|_, _| false)?
+        .into_iter()
+        .map(|(rel_path, _)| rel_path)
+        .collect();
+
+    for file_path in removed {
+        for dir in file_path.ancestors().skip(1) {
+            let is_empty = !remaining
+                .range::<Path, _>((Excluded(dir), Unbounded))
+                .next()
+                .is_some_and(|next| next.starts_with(dir));
+            if dir.as_os_str().is_empty() || !is_empty || tree.remove_dir(dir).is_err() {
+                break;
+            }
+            remaining.remove(dir);
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens a patch package and checks that its header can be applied.
+/// Packages in the version 1 format are converted when opened. Later versions share the framed
+/// format and only add frame kinds, so they are read by the same reader, straight from the
+/// memory-mapped file.
+fn open_patch(patch_loc: &Path) -> anyhow::Result<PatchReader<Box<dyn Read>>> {
+    let mut file = open_file(patch_loc)?;
+    let is_framed = file
+        .fill_buf()
+        .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()))?
+        .starts_with(MAGIC);
+    if !is_framed {
+        return read_v1_package(file)
+            .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()));
+    }
+
+    // SAFETY: the map is only read through bounds checked slices. Package files are not written
+    // while they are read, sign_patch and upgrade_patch drop their reader before replacing one.
+    let map = unsafe { Mmap::map(file.get_ref()) }
+        .with_context(|| format!("Failed to map patch file: {}", patch_loc.display()))?;
+
+    // Open the patch package and read its header
+    let mut reader = PatchReader::mapped(map)
+        .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()))?;
+
+    // Verify version compatibility
+    let header = reader.header();
+    ensure!(
+        (MIN_PATCH_PACKAGE_VERSION..=PATCH_PACKAGE_VERSION).contains(&header.version),
+        PatchError::UnsupportedVersion {
+            version: header.version
+        }
+    );
+    ensure!(
+        header.chunk_size > 0 && header.chunk_size <= MAX_CHUNK_SIZE,
+        "Invalid chunk size in patch file: {}",
+        header.chunk_size
+    );
+
+    reader
+        .read_info()
+        .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()))?;
+    Ok(reader)
+}
+
+/// Stages changes with `stage` and commits them to `target_path` in a single transaction.
+/// `stage` returns the paths of the files it removes, whose empty parents are cleaned up after
+/// if `prune_empty_dirs` is set.
+fn commit_staged(
+    target_path: &Path,
+    prune_empty_dirs: bool,
+    stage: impl FnOnce(&mut Transaction) -> anyhow::Result<Vec<PathBuf>>,
+) -> anyhow::Result<()> {
+    let mut transaction = Transaction::begin(target_path)?;
+    let removed = match stage(&mut transaction) {
+        Ok(removed) => removed,
+        Err(e) => {
+            transaction.abort();
+            return Err(e);
+        }
+    };
+    transaction.commit()?;
+
+    // Safely remove empty parent directories
+    if prune_empty_dirs {
+        for file_path in removed {
+            remove_empty_parents(&file_path, target_path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Verifies that the directory a patch is applied to exists and is not a symlink.
+fn check_target_path(target_path: &Path) -> anyhow::Result<()> {
+    let meta =
+        symlink_metadata(target_path).map_err(|source| PatchError::io(target_path, source))?;
+    ensure!(
+        !meta.file_type().is_symlink(),
+        PatchError::Symlink {
+            path: target_path.to_path_buf(),
+            entry: None,
+        }
+    );
+    Ok(())
+}
+
+/// Rolls back a patch whose application was interrupted (e.g. by a crash) in `target_path`.
+/// Returns whether anything had to be restored. Called automatically by [`apply_patch`].
+pub fn recover_patch(target_path: &Path) -> PatchResult<bool> {
+    Ok(recover(target_path)?)
+}
+
+/// Applies a patch package to a target directory.
+///
+/// The package is read one entry at a time and files are processed in chunks. All changes are
+/// staged and validated first and then committed together: if any entry fails, the target
+/// directory is left exactly as it was. A transaction interrupted by a crash is rolled back the
+/// next time a patch is applied to the same directory. File permissions and modification times
+/// recorded in the package are restored unless [`ApplyOptions::ignore_metadata`] is set. Setuid,
+/// setgid and sticky bits are left out unless [`ApplyOptions::special_mode_bits`] is set.
+///
+/// The package must be signed (see [`sign_patch`]) with one of `trusted_keys`. The signature is
+/// checked once the whole package has been read, before anything is committed.
+///
+/// A package made for a PolyTrack version only applies to that version. The version of the
+/// target is [`ApplyOptions::polytrack_version`], or else the name of the target directory if it
+/// is in a [`POLYTRACK_VERSIONS_DIR`] directory. Targets of unknown version aren't checked.
+///
+/// The package records a hash of the tree it was created from, leaving out the paths its ignore
+/// patterns left out (see [`CreateOptions::ignore`]). A target that doesn't match it fails with
+/// [`PatchError::BaseTreeMismatch`] unless [`ApplyOptions::ignore_base_tree`] is set (e.g. it
+/// holds saves or other mods, or the package was already applied in part). Every entry then has
+/// to find its files as they were before or after it instead: an added file may not replace a
+/// file with other contents.
+///
+/// Entries whose changes are already in place are left as they are and listed in the report:
+/// files that already hold their new contents, removed files that are already gone and added
+/// directories that already exist. Applying a package again with
+/// [`ApplyOptions::ignore_base_tree`], or the rest of it after applying only some of its
+/// entries, is safe that way.
+///
+/// With [`ApplyOptions::fuzzy`], modified text files whose contents changed since the package
+/// was made are patched from the text hunks recorded with [`CreateOptions::text_hunks`] instead
+/// of failing. Only the contents of modified files are checked less strictly: the target still
+/// has to be the base tree unless [`ApplyOptions::ignore_base_tree`] is set, and added files
+/// still may not replace unknown ones. Every hunk is applied where its context is found, even if
+/// lines moved, and hunks whose context is gone are left out. The report lists the outcome of
+/// every hunk. Files patched this way can't be reverted with [`revert_patch`], since they don't
+/// end up with the contents the package recorded.
+pub fn apply_patch(
+    patch_loc: &Path,
+    target_path: &Path,
+    trusted_keys: &[VerifyingKey],
+) -> PatchResult<ApplyReport> {
+    apply_patch_with_options(
+        patch_loc,
+        target_path,
+        &ApplyOptions {
+            trusted_keys: trusted_keys.to_vec(),
+            ..Default::default()
+        },
+    )
+}
+
+/// Applies a patch package to a target directory, see [`apply_patch`].
+///
+/// Only the entries selected by [`ApplyOptions::filter`] are applied, the report lists the paths
+/// of the others. A directory is only removed if none of the skipped entries is in it. The
+/// signature is checked like for the whole package. Applying the whole package later with
+/// [`ApplyOptions::ignore_base_tree`] applies the rest of it, the entries already in place are
+/// left as they are. A partly applied package
+/// can't be reverted with [`revert_patch`], since the skipped files don't match it.
+///
+/// Every entry is reported to [`ApplyOptions::progress`] as it is staged. Cancelling
+/// [`ApplyOptions::cancel`] stops before the next entry and aborts the transaction, so the
+/// target is left as it was.
+pub fn apply_patch_with_options(
+    patch_loc: &Path,
+    target_path: &Path,
+    options: &ApplyOptions,
+) -> PatchResult<ApplyReport> {
+    check_target_path(target_path)?;
+
+    // Restore the target if a previous run was interrupted
+    recover_patch(target_path)?;
+
+    let selector = EntrySelector::new(&options.filter)?;
+    let mut reader = open_patch(patch_loc)?;
+    let strict = check_target(
+        reader.info(),
+        &DirTree::new(target_path),
+        Some(target_path),
+        options,
+    )?;
+    progress::report(&options.progress, |progress| {
+        progress.started(reader.info().map(|info| info.stats.entries()))
+    });
+
+    let mut report = ApplyReport::default();
+    let prune_empty_dirs = reader.prunes_empty_dirs();
+    commit_staged(target_path, prune_empty_dirs, |transaction| {
+        let removed = stage_entries(
+            &mut reader,
+            transaction,
+            target_path,
+            options,
+            strict,
+            &selector,
+            &mut report,
+        )?;
+        check_signature(&reader, options)?;
+        Ok(removed)
+    })?;
+    Ok(report)
+}
+
+/// Applies a patch package to a tree of any kind, see [`apply_patch_with_options`]. E.g. a mod can
+/// be applied straight into a zipped build opened as an [`ArchiveTree`], which is then saved.
+///
+/// Every entry is validated and the new contents of every file are held in memory before the tree
+/// is changed, so a failing entry or signature leaves it as it was. Changes are not journaled
+/// though: if the tree fails to make one of them, it is left partly patched. Directories on disk
+/// should be patched with [`apply_patch_with_options`] instead of through a [`DirTree`].
+pub fn apply_patch_to_tree(
+    patch_loc: &Path,
+    tree: &mut dyn FileTree,
+    options: &ApplyOptions,
+) -> PatchResult<ApplyReport> {
+    let selector = EntrySelector::new(&options.filter)?;
+    let mut reader = open_patch(patch_loc)?;
+    let strict = check_target(reader.info(), tree, None, options)?;
+    progress::report(&options.progress, |progress| {
+        progress.started(reader.info().map(|info| info.stats.entries()))
+    });
+
+    let mut report = ApplyReport::default();
+    let changes = stage_tree_entries(&mut reader, tree, options, strict, &selector, &mut report)?;
+    check_signature(&reader, options)?;
+
+    let mut removed = Vec::new();
+    for change in changes {
+        match change {
+            TreeChange::Write {
+                rel_path,
+                contents,
+                metadata,
+            } => tree.write(&rel_path, &mut &contents[..], metadata.as_ref())?,
+            TreeChange::Remove(rel_path) => {
+                tree.remove_file(&rel_path)?;
+                removed.push(rel_path);
+            }
+            TreeChange::CreateDir(rel_path) => tree.create_dir(&rel_path)?,
+            TreeChange::RemoveDir(rel_path) => tree.remove_dir(&rel_path)?,
+        }
+    }
+
+    if reader.prunes_empty_dirs() {
+        remove_empty_tree_parents(tree, &removed)?;
+    }
+    Ok(report)
+}
+
+/// Reads the entry for `rel_path` from a patch package, without decoding the entries before it.
+/// Returns `None` if the package doesn't change that path.
+pub fn read_patch_entry(patch_loc: &Path, rel_path: &str) -> PatchResult<Option<EntryInfo>> {
+    let entry = open_patch(patch_loc)?
+        .find_entry(rel_path)
+        .with_context(|| format!("Failed to read patch file: {}", patch_loc.display()))?;
+    Ok(entry.map(PatchEntry::info))
+}
+
+/// Checks a patch package against a target directory without changing anything.
+///
+/// The package goes through the same checks [`apply_patch_with_options`] performs with
+/// `options` before anything is applied (signature, PolyTrack version and base tree), failing
+/// with the same errors. Every entry selected by [`ApplyOptions::filter`] then goes through the
+/// same checks too (path containment, symlinks, hashes before and after, text hunks with
+/// [`ApplyOptions::fuzzy`]) and the report lists what would be added, modified or removed and
+/// which entries would fail.
+pub fn dry_run_patch(
+    patch_loc: &Path,
+    target_path: &Path,
+    options: &ApplyOptions,
+) -> PatchResult<DryRunReport> {
+    check_target_path(target_path)?;
+
+    let selector = EntrySelector::new(&options.filter)?;
+    let mut reader = open_patch(patch_loc)?;
+    let strict = check_target(
+        reader.info(),
+        &DirTree::new(target_path),
+        Some(target_path),
+        options,
+    )?;
+
+    let mut report = DryRunReport::default();
+    let mut seen = HashSet::new();
+    let mut removed_dirs = Vec::new();
+    // Paths of skipped entries and where skipped renames come from, see `stage_entries`
+    let mut left_in_place = Vec::new();
+    let mut index = 0;
+
+    while let Some(entry) = reader.next_entry()? {
+        if !selector.selects(&entry.operation, &entry.rel_path) {
+            if let PatchOperation::Rename { from, .. } = &entry.operation {
+                left_in_place.push(from.clone());
+            }
+            left_in_place.push(entry.rel_path.clone());
+            report.skipped.push(entry.rel_path);
+            index += 1;
+            continue;
+        }
+
+        let text_hunks = entry.text_hunks.as_ref().filter(|_| options.fuzzy);
+        let result = resolve_entry_path(target_path, &entry.rel_path).and_then(|file_path| {
+            ensure!(
+                seen.insert(file_path.clone()),
+                PatchError::DuplicateEntry {
+                    path: file_path,
+                    entry: Some(index),
+                }
+            );
+            let change = validate_entry(
+                &entry.operation,
+                target_path,
+                &file_path,
+                text_hunks,
+                strict,
+            )?;
+            let original_path = match &change {
+                PlannedChange::AlreadyApplied => return Ok(change),
+                PlannedChange::FuzzyModify(text_hunks) => {
+                    let contents = std::fs::read(&file_path)
+                        .map_err(|source| PatchError::io(&file_path, source))?;
+                    text_hunks.apply(&contents).with_context(|| {
                         format!(
-                            "Failed to create parent directories for file: {}",
+                            "Failed to apply text hunks to file: {}",
                             file_path.display()
                         )
                     })?;
+                    return Ok(change);
                 }
-
-                // Final check: ensure we're not overwriting a symlink
-                if file_path.exists() {
+                PlannedChange::Rename(from_path) => {
                     ensure!(
-                        !symlink_metadata(&file_path)?.file_type().is_symlink(),
-                        "Refusing to overwrite symlink: {}",
-                        file_path.display()
+                        seen.insert(from_path.clone()),
+                        PatchError::DuplicateEntry {
+                            path: from_path.clone(),
+                            entry: Some(index),
+                        }
                     );
+                    from_path.clone()
                 }
+                _ => file_path.clone(),
+            };
+            write_entry_contents(
+                &mut reader,
+                &entry.operation,
+                &file_path,
+                || open_file(&original_path),
+                &mut sink(),
+            )?;
+            Ok(change)
+        });
 
-                write(&file_path, data).with_context(|| {
-                    format!("Failed to write added file: {}", file_path.display())
-                })?;
+        let rel_path = entry.rel_path;
+        match result {
+            Ok(PlannedChange::Create) => report.added.push(rel_path),
+            Ok(PlannedChange::Overwrite) => {
+                report.overwritten.push(rel_path.clone());
+                report.added.push(rel_path);
             }
-            PatchOperation::Remove => {
-                if file_path.exists() {
-                    // Final check: ensure we're not removing a symlink
-                    ensure!(
-                        !symlink_metadata(&file_path)?.file_type().is_symlink(),
-                        "Refusing to remove symlink: {}",
-                        file_path.display()
-                    );
-
-                    remove_file(&file_path).with_context(|| {
-                        format!("Failed to remove file: {}", file_path.display())
-                    })?;
-
-                    // Safely remove empty parent directories
-                    remove_empty_parents(&file_path, target_path);
+            Ok(PlannedChange::Modify | PlannedChange::FuzzyModify(_)) => {
+                report.modified.push(rel_path)
+            }
+            Ok(PlannedChange::Remove) => report.removed.push(rel_path),
+            Ok(PlannedChange::Missing) => report.missing.push(rel_path),
+            Ok(PlannedChange::CreateDir) => report.added_dirs.push(rel_path),
+            Ok(PlannedChange::RemoveDir) => removed_dirs.push(rel_path),
+            Ok(PlannedChange::ExistingDir) => {}
+            Ok(PlannedChange::AlreadyApplied) => report.already_applied.push(rel_path),
+            Ok(PlannedChange::Rename(_)) => {
+                if let PatchOperation::Rename { from, .. } = entry.operation {
+                    report.renamed.push((from, rel_path));
                 }
             }
-            PatchOperation::Modify(patch) => {
-                // Final check: ensure we're not modifying a symlink
-                ensure!(
-                    !symlink_metadata(&file_path)?.file_type().is_symlink(),
-                    "Refusing to modify symlink: {}",
-                    file_path.display()
-                );
+            Err(e) => report.failures.push(EntryFailure {
+                index,
+                rel_path,
+                reason: format!("{:#}", e),
+            }),
+        }
+        index += 1;
+    }
+
+    // Directories holding skipped entries are left in place, like `stage_entries` does
+    for rel_path in removed_dirs {
+        let holds_skipped = left_in_place
+            .iter()
+            .any(|skipped| Path::new(skipped).starts_with(&rel_path));
+        if holds_skipped {
+            report.skipped.push(rel_path);
+        } else {
+            report.removed_dirs.push(rel_path);
+        }
+    }
+
+    check_signature(&reader, options)?;
+    Ok(report)
+}
 
-                // Read current file and apply patch
-                let original_data = read(&file_path).with_context(|| {
+/// Verifies that `file_path` is a regular file with the hash a patch left it with.
+fn verify_patched_file(file_path: &Path, expected_hash: &str) -> anyhow::Result<()> {
+    let meta = symlink_metadata(file_path).map_err(|_| PatchError::MissingFile {
+        path: file_path.to_path_buf(),
+        entry: None,
+    })?;
+    ensure!(
+        !meta.file_type().is_symlink(),
+        PatchError::Symlink {
+            path: file_path.to_path_buf(),
+            entry: None,
+        }
+    );
+    ensure!(
+        meta.is_file(),
+        PatchError::NotAFile {
+            path: file_path.to_path_buf(),
+            entry: None
+        }
+    );
+    let hash = hash_file(file_path)?;
+    ensure!(
+        hash == expected_hash,
+        PatchError::HashMismatch {
+            path: file_path.to_path_buf(),
+            expected: expected_hash.to_string(),
+            actual: hash,
+            entry: None,
+        }
+    );
+    Ok(())
+}
+
+/// Writes the original contents of the modified or renamed `file_path` to `staged_path` using the
+/// reverse chunk patches of the entry, and applies `metadata` to them.
+fn stage_reverted_file(
+    reader: &mut PatchReader<impl Read>,
+    file_path: &Path,
+    staged_path: &Path,
+    before_hash: &str,
+    after_hash: &str,
+    metadata: Option<&FileMetadata>,
+) -> anyhow::Result<()> {
+    // The link shares metadata with the original, so only link if it stays as it is
+    if before_hash == after_hash && metadata.is_none() && hard_link(file_path, staged_path).is_ok()
+    {
+        return Ok(());
+    }
+
+    let file = File::create(staged_path).map_err(|source| PatchError::io(staged_path, source))?;
+    let mut out = BufWriter::new(file);
+    if before_hash == after_hash {
+        copy(&mut open_file(file_path)?, &mut out)
+            .and_then(|_| out.flush())
+            .map_err(|source| PatchError::io(staged_path, source))?;
+    } else {
+        let chunk_size = reader.header().chunk_size;
+        write_modified_file(
+            reader,
+            PatchReader::next_reverse_change,
+            file_path,
+            &mut open_file(file_path)?,
+            &mut out,
+            chunk_size,
+            before_hash,
+        )?;
+    }
+    drop(out);
+
+    if let Some(metadata) = metadata {
+        metadata.without_special_bits().restore(staged_path)?;
+    }
+    Ok(())
+}
+
+/// Validates every entry of the package against the patched tree and stages the changes that
+/// undo it in `transaction`. Returns the paths of the files that will be removed.
+fn stage_revert_entries(
+    reader: &mut PatchReader<impl Read>,
+    transaction: &mut Transaction,
+    target_path: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+
+    for index in 0.. {
+        let Some(entry) = reader.next_entry()? else {
+            break;
+        };
+        let file_path =
+            resolve_entry_path(target_path, &entry.rel_path).map_err(|e| at_entry(e, index))?;
+
+        match entry.operation {
+            PatchOperation::Add { hash } => {
+                // Only remove the file if it is still the one the patch added
+                verify_patched_file(&file_path, &hash).map_err(|e| at_entry(e, index))?;
+                transaction.stage_remove(&file_path)?;
+                removed.push(file_path);
+            }
+            PatchOperation::Remove => {
+                let (hash, metadata) = reader.next_inverse()?.with_context(|| {
                     format!(
-                        "Failed to read file for modification: {}",
-                        file_path.display()
+                        "Patch is not reversible: no contents recorded for removed file {}",
+                        entry.rel_path
                     )
                 })?;
+                ensure!(
+                    symlink_metadata(&file_path).is_err(),
+                    PatchError::PathOccupied {
+                        path: file_path.to_path_buf(),
+                        entry: None
+                    }
+                );
 
-                // Verify the hash before applying patch
-                if hash(&original_data) != patch.before_hash {
-                    return Err(anyhow!(
-                        "Hash mismatch before applying patch to file: {}. File may have been modified.",
-                        file_path.display()
-                    ));
+                let staged_path = transaction.stage_write(&file_path)?;
+                let file = File::create(&staged_path)
+                    .map_err(|source| PatchError::io(&staged_path, source))?;
+                write_added_file(reader, &file_path, &mut BufWriter::new(file), &hash)
+                    .map_err(|e| at_entry(e, index))?;
+                if let Some(metadata) = metadata {
+                    metadata.without_special_bits().restore(&staged_path)?;
                 }
+            }
+            PatchOperation::Modify {
+                before_hash,
+                after_hash,
+            } => {
+                verify_patched_file(&file_path, &after_hash).map_err(|e| at_entry(e, index))?;
 
-                let modified_data = apply(&original_data, &patch).map_err(|e| {
-                    anyhow!(
-                        "Failed to apply patch to file {}: {:?}",
-                        file_path.display(),
-                        e
+                reader.skip_changes()?;
+                let (_, metadata) = reader.next_inverse()?.with_context(|| {
+                    format!(
+                        "Patch is not reversible: no reverse diff recorded for modified file {}",
+                        entry.rel_path
                     )
                 })?;
 
-                // Verify the hash after applying patch
-                if hash(&modified_data) != patch.after_hash {
-                    return Err(anyhow!(
-                        "Hash mismatch after applying patch to file: {}. Patch may be corrupted.",
-                        file_path.display()
-                    ));
-                }
+                let staged_path = transaction.stage_write(&file_path)?;
+                stage_reverted_file(
+                    reader,
+                    &file_path,
+                    &staged_path,
+                    &before_hash,
+                    &after_hash,
+                    metadata.as_ref(),
+                )
+                .map_err(|e| at_entry(e, index))?;
+            }
+            PatchOperation::Rename {
+                from,
+                before_hash,
+                after_hash,
+            } => {
+                // Move the file back to where it came from
+                verify_patched_file(&file_path, &after_hash).map_err(|e| at_entry(e, index))?;
+                let from_path =
+                    resolve_entry_path(target_path, &from).map_err(|e| at_entry(e, index))?;
+                ensure!(
+                    symlink_metadata(&from_path).is_err(),
+                    PatchError::PathOccupied {
+                        path: from_path.to_path_buf(),
+                        entry: None
+                    }
+                );
 
-                write(&file_path, modified_data).with_context(|| {
-                    format!("Failed to write modified file: {}", file_path.display())
-                })?;
+                // Files moved as is can be moved back without any recorded data
+                reader.skip_changes()?;
+                let inverse = reader.next_inverse()?;
+                ensure!(
+                    inverse.is_some() || before_hash == after_hash,
+                    "Patch is not reversible: no reverse diff recorded for renamed file {}",
+                    entry.rel_path
+                );
+                let metadata = inverse.and_then(|(_, metadata)| metadata);
+
+                let staged_path = transaction.stage_write(&from_path)?;
+                stage_reverted_file(
+                    reader,
+                    &file_path,
+                    &staged_path,
+                    &before_hash,
+                    &after_hash,
+                    metadata.as_ref(),
+                )
+                .map_err(|e| at_entry(e, index))?;
+
+                transaction.stage_remove(&file_path)?;
+                removed.push(file_path);
+            }
+            PatchOperation::AddDir => {
+                // Directories added by the patch that are already gone need no change
+                if let Ok(meta) = symlink_metadata(&file_path) {
+                    ensure!(
+                        !meta.file_type().is_symlink() && meta.is_dir(),
+                        PatchError::NotADirectory {
+                            path: file_path.to_path_buf(),
+                            entry: None
+                        }
+                    );
+                    transaction.stage_remove_dir(&file_path)?;
+                }
             }
+            PatchOperation::RemoveDir => match symlink_metadata(&file_path) {
+                Ok(meta) => ensure!(
+                    meta.is_dir(),
+                    PatchError::PathOccupied {
+                        path: file_path.to_path_buf(),
+                        entry: None,
+                    }
+                ),
+                Err(_) => transaction.stage_create_dir(&file_path)?,
+            },
         }
     }
 
+    Ok(removed)
+}
+
+/// Reverts a patch package previously applied to a target directory, turning the patched tree
+/// back into the original one.
+///
+/// The package must have been created with [`CreateOptions::reversible`]. Every file is checked
+/// against the state the patch left it in before anything is reversed, and all changes are
+/// committed together like in [`apply_patch`].
+pub fn revert_patch(patch_loc: &Path, target_path: &Path) -> PatchResult<()> {
+    check_target_path(target_path)?;
+
+    // Restore the target if a previous run was interrupted
+    recover_patch(target_path)?;
+
+    let mut reader = open_patch(patch_loc)?;
+    let prune_empty_dirs = reader.prunes_empty_dirs();
+    commit_staged(target_path, prune_empty_dirs, |transaction| {
+        stage_revert_entries(&mut reader, transaction, target_path)
+    })?;
     Ok(())
 }
@@ -0,0 +1,70 @@
+//! File metadata carried by patch entries.
+
+use std::{
+    fs::{metadata, File},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// Permissions and modification time of a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMetadata {
+    pub mode: Option<u32>, // Unix permission bits, `None` on platforms without them
+    pub mtime: Option<u64>, // Modification time in nanoseconds since the Unix epoch
+}
+
+impl FileMetadata {
+    /// Reads the metadata of the file at `path`.
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let meta = metadata(path)
+            .with_context(|| format!("Failed to read file metadata: {}", path.display()))?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(meta.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .and_then(|since_epoch| u64::try_from(since_epoch.as_nanos()).ok());
+
+        Ok(Self { mode, mtime })
+    }
+
+    /// Returns the metadata without the setuid, setgid and sticky bits, which packages only get
+    /// to set when asked to, see [`crate::ApplyOptions::special_mode_bits`].
+    pub(crate) fn without_special_bits(&self) -> Self {
+        Self {
+            mode: self.mode.map(|mode| mode & 0o777),
+            mtime: self.mtime,
+        }
+    }
+
+    /// Applies the metadata to the file at `path`. The modification time is set before the mode,
+    /// so read-only modes can be restored too.
+    pub(crate) fn restore(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(mtime) = self.mtime {
+            File::options()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_nanos(mtime)))
+                .with_context(|| format!("Failed to set modification time: {}", path.display()))?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::{fs::set_permissions, os::unix::fs::PermissionsExt};
+            set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Applying patch packages into a new directory, leaving the tree they apply to untouched.
+
+use std::{
+    fs::{canonicalize, copy, create_dir, hard_link, remove_dir_all, rename},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use walkdir::WalkDir;
+
+use crate::{
+    check_target_path, commit_staged,
+    error::{PatchError, PatchResult},
+    filter::EntrySelector,
+    info::check_target,
+    metadata::FileMetadata,
+    open_patch, progress,
+    progress::CancelToken,
+    signing::check_signature,
+    stage_entries,
+    transaction::TRANSACTION_DIR,
+    tree::DirTree,
+    ApplyOptions, ApplyReport,
+};
+
+/// Recreates the tree at `base_path` in the new directory `out_path`. Files are hard linked when
+/// possible and copied with their metadata otherwise, symlinks are recreated as they are.
+fn mirror_tree(base_path: &Path, out_path: &Path, cancel: &CancelToken) -> anyhow::Result<()> {
+    create_dir(out_path)
+        .with_context(|| format!("Failed to create directory: {}", out_path.display()))?;
+
+    for entry in WalkDir::new(base_path).min_depth(1).sort_by_file_name() {
+        cancel.check()?;
+        let entry = entry.with_context(|| {
+            format!("Failed to read directory entry in: {}", base_path.display())
+        })?;
+        let rel_path = entry.path().strip_prefix(base_path).with_context(|| {
+            format!(
+                "Failed to strip prefix from path: {}",
+                entry.path().display()
+            )
+        })?;
+        let mirrored = out_path.join(rel_path);
+
+        if entry.file_type().is_dir() {
+            create_dir(&mirrored)
+                .with_context(|| format!("Failed to create directory: {}", mirrored.display()))?;
+        } else if entry.file_type().is_symlink() {
+            mirror_symlink(entry.path(), &mirrored)?;
+        } else if hard_link(entry.path(), &mirrored).is_err() {
+            copy(entry.path(), &mirrored)
+                .with_context(|| format!("Failed to copy file: {}", entry.path().display()))?;
+            FileMetadata::read(entry.path())?.restore(&mirrored)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Recreates the symlink at `link_path` at `mirrored`, pointing to the same place.
+#[cfg(unix)]
+fn mirror_symlink(link_path: &Path, mirrored: &Path) -> anyhow::Result<()> {
+    let target = std::fs::read_link(link_path)
+        .with_context(|| format!("Failed to read symlink: {}", link_path.display()))?;
+    std::os::unix::fs::symlink(target, mirrored)
+        .with_context(|| format!("Failed to create symlink: {}", mirrored.display()))
+}
+
+/// Symlinks can't be recreated reliably on this platform, so trees holding one are refused.
+#[cfg(not(unix))]
+fn mirror_symlink(link_path: &Path, _mirrored: &Path) -> anyhow::Result<()> {
+    Err(PatchError::Symlink {
+        path: link_path.to_path_buf(),
+        entry: None,
+    }
+    .into())
+}
+
+/// Returns whether the directory `out_path`, which doesn't exist yet, would be inside
+/// `base_path`.
+fn is_inside(out_path: &Path, base_path: &Path) -> anyhow::Result<bool> {
+    let base_dir = canonicalize(base_path)
+        .with_context(|| format!("Failed to resolve path: {}", base_path.display()))?;
+    let parent = match out_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent_dir = canonicalize(parent)
+        .with_context(|| format!("Failed to resolve path: {}", parent.display()))?;
+    Ok(parent_dir.starts_with(base_dir))
+}
+
+/// Applies a patch package to the tree at `base_path` and writes the result to the new directory
+/// `out_path`, leaving `base_path` as it is. E.g. several modded variants can be built from a
+/// pristine release without copying it first.
+///
+/// Files the package doesn't change are hard linked from the base tree when it is on the same
+/// file system, and copied otherwise. Linked files share their contents with the base tree, so
+/// they should be replaced rather than written to in place ([`crate::apply_patch`] always
+/// replaces files). Every entry is checked like for [`crate::apply_patch_with_options`] and
+/// `options` are used the same way. The result is built in a directory next to `out_path` and
+/// only moved there once the whole package was applied, so a failing or cancelled run leaves
+/// nothing behind.
+pub fn apply_patch_to(
+    base_path: &Path,
+    patch_loc: &Path,
+    out_path: &Path,
+    options: &ApplyOptions,
+) -> PatchResult<ApplyReport> {
+    check_target_path(base_path)?;
+    if base_path.join(TRANSACTION_DIR).exists() {
+        return Err(anyhow!(
+            "Base directory holds an interrupted patch, recover it first: {}",
+            base_path.display()
+        )
+        .into());
+    }
+    if out_path.symlink_metadata().is_ok() {
+        return Err(anyhow!("Output directory already exists: {}", out_path.display()).into());
+    }
+    if is_inside(out_path, base_path)? {
+        return Err(anyhow!(
+            "Output directory can't be inside the base directory: {}",
+            out_path.display()
+        )
+        .into());
+    }
+
+    let selector = EntrySelector::new(&options.filter)?;
+    let mut reader = open_patch(patch_loc)?;
+    let strict = check_target(
+        reader.info(),
+        &DirTree::new(base_path),
+        Some(base_path),
+        options,
+    )?;
+
+    // A partial tree left by an interrupted run is only ever found under this name
+    let mut partial_name = out_path.as_os_str().to_owned();
+    partial_name.push(".partial");
+    let partial_path = PathBuf::from(partial_name);
+    if partial_path.symlink_metadata().is_ok() {
+        remove_dir_all(&partial_path)
+            .with_context(|| format!("Failed to remove directory: {}", partial_path.display()))?;
+    }
+
+    let mut report = ApplyReport::default();
+    let prune_empty_dirs = reader.prunes_empty_dirs();
+    let result = mirror_tree(base_path, &partial_path, &options.cancel)
+        .and_then(|()| {
+            progress::report(&options.progress, |progress| {
+                progress.started(reader.info().map(|info| info.stats.entries()))
+            });
+            commit_staged(&partial_path, prune_empty_dirs, |transaction| {
+                let removed = stage_entries(
+                    &mut reader,
+                    transaction,
+                    &partial_path,
+                    options,
+                    strict,
+                    &selector,
+                    &mut report,
+                )?;
+                check_signature(&reader, options)?;
+                Ok(removed)
+            })
+        })
+        .and_then(|()| {
+            rename(&partial_path, out_path)
+                .with_context(|| format!("Failed to create directory: {}", out_path.display()))
+        });
+
+    if let Err(e) = result {
+        let _ = remove_dir_all(&partial_path);
+        // Files in the partial tree stand for the same files in the base tree
+        return Err(PatchError::from(e).relocate(&partial_path, base_path));
+    }
+    Ok(report)
+}
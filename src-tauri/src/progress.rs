@@ -0,0 +1,70 @@
+//! Progress reporting and cancellation for patch operations that can take a while.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::ensure;
+
+use crate::error::PatchError;
+
+/// Receives the progress of [`create_patch_with_options`](crate::create_patch_with_options)
+/// and [`apply_patch_with_options`](crate::apply_patch_with_options).
+///
+/// Creating a patch compares small files in parallel, so methods may be called from several
+/// threads at once and files may finish in a different order than they started.
+pub trait ProgressObserver: Send + Sync {
+    /// Called once before the first entry, with the number of entries that will be reported if
+    /// it is known. Creating a patch reports every file of both trees, changed or not. Applying
+    /// one reports every entry of the package.
+    fn started(&self, _total: Option<u64>) {}
+
+    /// Called when work on the entry for `rel_path` starts.
+    fn entry_started(&self, _rel_path: &str) {}
+
+    /// Called when the entry for `rel_path` is done. `bytes` is the size of the files compared
+    /// for it when creating a patch, or of the file written for it when applying one.
+    fn entry_finished(&self, _rel_path: &str, _bytes: u64) {}
+}
+
+/// Calls `f` with the observer in `progress`, if there is one.
+pub(crate) fn report(
+    progress: &Option<Arc<dyn ProgressObserver>>,
+    f: impl FnOnce(&dyn ProgressObserver),
+) {
+    if let Some(progress) = progress {
+        f(progress.as_ref());
+    }
+}
+
+/// Cancels a patch operation from another thread. Clones share the same state.
+///
+/// The token is checked between entries, so an entry that is being written finishes first. A
+/// cancelled operation fails: a partially created package is removed and nothing of a
+/// cancelled apply is committed to the target.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the operations using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails if the token was cancelled.
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        ensure!(!self.is_cancelled(), PatchError::Cancelled);
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+//! Detection of files that were renamed or moved between two trees.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{diff_chunks, stream::ChunkChange, tree::FileTree, DiffStrategy};
+
+/// Largest size of the diff between a removed and an added file, relative to the size of the
+/// added file, for the two to be considered the same file.
+const SIMILARITY_THRESHOLD: f64 = 0.5;
+
+/// Number of removed files an added file is diffed against when looking for a similar one.
+const MAX_SIMILARITY_CANDIDATES: usize = 4;
+
+/// A file removed from the first tree that shows up, possibly modified, in the second.
+pub(crate) struct DetectedRename {
+    pub from: PathBuf,       // Path in the first tree, relative to it
+    pub before_hash: String, // Hash of the file in the first tree
+    pub after_hash: String,  // Hash of the file in the second tree
+}
+
+struct FileInfo {
+    rel_path: PathBuf,
+    hash: String,
+    size: u64,
+}
+
+impl FileInfo {
+    fn read(tree: &dyn FileTree, rel_path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            rel_path: rel_path.to_path_buf(),
+            hash: tree.hash(rel_path)?,
+            size: tree.size(rel_path)?,
+        })
+    }
+
+    fn same_name(&self, other: &FileInfo) -> bool {
+        self.rel_path.file_name() == other.rel_path.file_name()
+    }
+
+    /// Whether `other` is worth diffing against when looking for a similar file.
+    fn may_be_similar(&self, other: &FileInfo) -> bool {
+        let similar_size = self.size.min(other.size) * 2 >= self.size.max(other.size);
+        self.same_name(other)
+            || (self.rel_path.extension() == other.rel_path.extension() && similar_size)
+    }
+}
+
+/// Total size of the chunk patches turning the file at `from` in `tree1` into the one at `to` in
+/// `tree2`.
+fn delta_size(
+    tree1: &dyn FileTree,
+    from: &Path,
+    tree2: &dyn FileTree,
+    to: &Path,
+) -> anyhow::Result<u64> {
+    let mut size = 0;
+    diff_chunks(
+        &mut tree1.open(from)?,
+        &mut tree2.open(to)?,
+        to,
+        DiffStrategy::default(),
+        |change| {
+            size += match change {
+                ChunkChange::Delta(patch) => patch.patch.len(),
+                ChunkChange::Replace(chunk) => chunk.len(),
+            } as u64;
+            Ok(())
+        },
+    )?;
+    Ok(size)
+}
+
+/// Pairs files removed between `tree1` and `tree2` with files added in their place.
+///
+/// Files with identical contents are paired first, preferring ones that kept their file name.
+/// The remaining added files are diffed against a few removed files with the same name or
+/// extension and paired with the closest one if the diff is small enough. Empty files are never
+/// paired. `removed` and `added` must be sorted so the result does not depend on walk order.
+/// Returns the renames keyed by their path in the second tree.
+pub(crate) fn detect_renames(
+    tree1: &dyn FileTree,
+    tree2: &dyn FileTree,
+    removed: &[PathBuf],
+    added: &[PathBuf],
+) -> anyhow::Result<HashMap<PathBuf, DetectedRename>> {
+    let removed = removed
+        .par_iter()
+        .map(|rel_path| FileInfo::read(tree1, rel_path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let added = added
+        .par_iter()
+        .map(|rel_path| FileInfo::read(tree2, rel_path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut used = vec![false; removed.len()];
+    let mut renames = HashMap::new();
+    let mut pair = |file: &FileInfo, index: usize, used: &mut Vec<bool>| {
+        used[index] = true;
+        renames.insert(
+            file.rel_path.clone(),
+            DetectedRename {
+                from: removed[index].rel_path.clone(),
+                before_hash: removed[index].hash.clone(),
+                after_hash: file.hash.clone(),
+            },
+        );
+    };
+
+    // Identical contents
+    let mut by_hash: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, file) in removed.iter().enumerate().filter(|(_, file)| file.size > 0) {
+        by_hash.entry(&file.hash).or_default().push(index);
+    }
+    let mut unpaired = Vec::new();
+    for file in added.iter().filter(|file| file.size > 0) {
+        let best = by_hash.get(file.hash.as_str()).and_then(|candidates| {
+            candidates
+                .iter()
+                .copied()
+                .filter(|&index| !used[index])
+                .min_by_key(|&index| !removed[index].same_name(file))
+        });
+
+        match best {
+            Some(index) => pair(file, index, &mut used),
+            None => unpaired.push(file),
+        }
+    }
+
+    // Similar contents
+    for file in unpaired {
+        let mut candidates: Vec<usize> = (0..removed.len())
+            .filter(|&index| {
+                !used[index] && removed[index].size > 0 && removed[index].may_be_similar(file)
+            })
+            .collect();
+        candidates.sort_by_key(|&index| {
+            (
+                !removed[index].same_name(file),
+                removed[index].size.abs_diff(file.size),
+            )
+        });
+        candidates.truncate(MAX_SIMILARITY_CANDIDATES);
+
+        // Diff against all candidates at once, the first smallest one wins
+        let sizes = candidates
+            .par_iter()
+            .map(|&index| delta_size(tree1, &removed[index].rel_path, tree2, &file.rel_path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut best: Option<(usize, u64)> = None;
+        for (index, size) in candidates.into_iter().zip(sizes) {
+            if best.is_none_or(|(_, best_size)| size < best_size) {
+                best = Some((index, size));
+            }
+        }
+
+        if let Some((index, _)) =
+            best.filter(|&(_, size)| size as f64 <= file.size as f64 * SIMILARITY_THRESHOLD)
+        {
+            pair(file, index, &mut used);
+        }
+    }
+
+    Ok(renames)
+}
@@ -0,0 +1,104 @@
+//! Reports returned by patch operations.
+
+use crate::VerifyingKey;
+
+/// Outcome of creating a patch package.
+#[derive(Debug, Default)]
+pub struct CreateReport {
+    pub ignored: Vec<String>, // Paths left out by the ignore rules, relative to either tree
+}
+
+/// Outcome of checking a patch package against a target directory without applying it.
+/// Paths are relative to the target directory.
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    pub added: Vec<String>,             // Files that would be created or replaced
+    pub overwritten: Vec<String>,       // Added files that would replace an existing file
+    pub modified: Vec<String>,          // Files that would be modified
+    pub removed: Vec<String>,           // Files that would be removed
+    pub missing: Vec<String>,           // Files marked for removal that don't exist
+    pub already_applied: Vec<String>,   // Files that already hold what the patch leaves them with
+    pub renamed: Vec<(String, String)>, // Files that would be moved, as (from, to)
+    pub added_dirs: Vec<String>,        // Directories that would be created
+    pub removed_dirs: Vec<String>,      // Directories that would be removed
+    pub failures: Vec<EntryFailure>,    // Entries that would make the patch fail
+    pub skipped: Vec<String>,           // Paths of the entries left out by `ApplyOptions::filter`
+}
+
+impl DryRunReport {
+    /// Returns whether applying the patch would succeed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A patch entry that can't be applied.
+#[derive(Debug)]
+pub struct EntryFailure {
+    pub index: usize,     // Position of the entry in the package
+    pub rel_path: String, // Relative file path of the entry
+    pub reason: String,   // Why the entry would fail
+}
+
+/// A single entry of a patch package, see [`read_patch_entry`](crate::read_patch_entry).
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryInfo {
+    pub rel_path: String,            // Relative path of the entry
+    pub change: EntryChange,         // What the entry does to the path
+    pub before_hash: Option<String>, // Hash of a modified or renamed file before the patch
+    pub after_hash: Option<String>,  // Hash of an added, modified or renamed file after the patch
+}
+
+/// What a patch entry does to its path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryChange {
+    Added,
+    Removed,
+    Modified,
+    Renamed { from: String }, // Moved from another path, and modified if the hashes differ
+    AddedDir,
+    RemovedDir,
+}
+
+/// Outcome of verifying a patch package, see [`verify_patch`](crate::verify_patch).
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub version: u32,                    // Format version of the package
+    pub entries: u64,                    // Number of entries checked
+    pub checksum: bool,                  // Whether the package ends with a checksum
+    pub signed_by: Option<VerifyingKey>, // Key the package is validly signed with, trusted or not
+}
+
+/// Outcome of applying a patch package.
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub fuzzy: Vec<FuzzyFile>, // Modified files patched from their text hunks, see `ApplyOptions::fuzzy`
+    pub skipped: Vec<String>,  // Paths of the entries left out by `ApplyOptions::filter`
+    pub already_applied: Vec<String>, // Paths of the entries whose changes were already in place
+}
+
+/// A modified file whose contents didn't match the patch and was patched hunk by hunk instead.
+#[derive(Debug)]
+pub struct FuzzyFile {
+    pub rel_path: String,       // Relative file path of the entry
+    pub hunks: Vec<HunkStatus>, // Outcome of every hunk, in order
+}
+
+impl FuzzyFile {
+    /// Returns the number of hunks that were left out.
+    pub fn rejected(&self) -> usize {
+        self.hunks
+            .iter()
+            .filter(|status| **status == HunkStatus::Rejected)
+            .count()
+    }
+}
+
+/// Outcome of applying a single text hunk, like `patch(1)` reports it. Offsets and fuzz are in
+/// lines, or in tokens for minified files split into tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HunkStatus {
+    Applied { fuzz: usize }, // Applied where it was recorded, with `fuzz` lines of context left out
+    Offset { offset: i64, fuzz: usize }, // Applied `offset` lines away from where it was recorded
+    Rejected,                // Context not found, the hunk was left out
+}
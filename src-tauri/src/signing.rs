@@ -0,0 +1,209 @@
+//! Ed25519 signatures for patch packages.
+//!
+//! A package is signed over the SHA-512 digest of every byte up to and including its end frame,
+//! and the signature is stored in a frame after it (and after the checksum frame, if there is
+//! one) together with the public key. Signing an
+//! existing package only appends (or replaces) that frame, the entries are left as they are.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{read_to_string, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, ensure, Context};
+use ed25519_dalek::{Signature, Signer};
+
+use crate::{
+    error::{PatchError, PatchResult},
+    open_patch,
+    stream::{write_checksum, write_signature, PatchReader, SignatureRecord, Trailer},
+    ApplyOptions,
+};
+
+/// Secret key used to sign patch packages.
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+/// Public key used to check the signature of patch packages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+/// Decodes a hex encoded key of 32 bytes.
+fn decode_key(hex_key: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = hex::decode(hex_key.trim()).context("Key is not valid hex")?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("Key must be 32 bytes long, got {}", bytes.len()))
+}
+
+/// Reads a hex encoded key from a file.
+fn read_key_file(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Failed to read key file: {}", path.display()))?;
+    decode_key(&contents).with_context(|| format!("Invalid key file: {}", path.display()))
+}
+
+/// Writes a hex encoded key to a new file, refusing to overwrite an existing one.
+fn write_key_file(path: &Path, key: &[u8; 32], secret: bool) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", hex::encode(key)))
+        .with_context(|| format!("Failed to write key file: {}", path.display()))
+}
+
+impl SigningKey {
+    /// Generates a new random key.
+    pub fn generate() -> PatchResult<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret)
+            .map_err(|e| anyhow!("Failed to generate signing key: {}", e))?;
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(&secret)))
+    }
+
+    /// Reads a key written by [`SigningKey::write`].
+    pub fn read(path: &Path) -> PatchResult<Self> {
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(&read_key_file(
+            path,
+        )?)))
+    }
+
+    /// Writes the key to a new file only readable by its owner.
+    pub fn write(&self, path: &Path) -> PatchResult<()> {
+        Ok(write_key_file(path, &self.0.to_bytes(), true)?)
+    }
+
+    /// Returns the public key matching this key.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.verifying_key())
+    }
+}
+
+impl VerifyingKey {
+    /// Parses a hex encoded public key.
+    pub fn from_hex(hex_key: &str) -> PatchResult<Self> {
+        Ok(Self::from_bytes(&decode_key(hex_key)?)?)
+    }
+
+    fn from_bytes(bytes: &[u8; 32]) -> anyhow::Result<Self> {
+        ed25519_dalek::VerifyingKey::from_bytes(bytes)
+            .map(Self)
+            .map_err(|_| anyhow!("Invalid public key"))
+    }
+
+    /// Reads a key written by [`VerifyingKey::write`].
+    pub fn read(path: &Path) -> PatchResult<Self> {
+        Ok(Self::from_bytes(&read_key_file(path)?)
+            .with_context(|| format!("Invalid key file: {}", path.display()))?)
+    }
+
+    /// Writes the key to a new file.
+    pub fn write(&self, path: &Path) -> PatchResult<()> {
+        Ok(write_key_file(path, self.0.as_bytes(), false)?)
+    }
+}
+
+impl Display for VerifyingKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0.as_bytes()))
+    }
+}
+
+/// Signs the patch package at `patch_loc` with `key`, replacing any previous signature.
+pub fn sign_patch(patch_loc: &Path, key: &SigningKey) -> PatchResult<()> {
+    let mut reader = open_patch(patch_loc)?;
+    if reader.header().version == 1 {
+        return Err(
+            anyhow!("Version 1 patch packages can't be signed, recreate the patch first").into(),
+        );
+    }
+    while reader.next_entry()?.is_some() {}
+    let trailer = reader.trailer().expect("package was read to the end");
+    let signature = key.0.sign(&trailer.digest);
+    let (signed_len, digest, checksum) = (trailer.signed_len, trailer.digest, trailer.checksum);
+    drop(reader);
+
+    let mut file = File::options()
+        .write(true)
+        .open(patch_loc)
+        .with_context(|| format!("Failed to open patch file: {}", patch_loc.display()))?;
+    file.set_len(signed_len)
+        .and_then(|_| file.seek(SeekFrom::End(0)))
+        .with_context(|| format!("Failed to write patch file: {}", patch_loc.display()))?;
+    if checksum {
+        // The checksum covers the same bytes, so it is written back unchanged
+        write_checksum(&mut file, &digest)
+            .with_context(|| format!("Failed to write patch file: {}", patch_loc.display()))?;
+    }
+    write_signature(
+        &mut file,
+        &SignatureRecord {
+            public_key: key.0.verifying_key().to_bytes(),
+            signature: signature.to_bytes(),
+        },
+    )
+    .with_context(|| format!("Failed to write patch file: {}", patch_loc.display()))?;
+    Ok(())
+}
+
+/// Checks the signature of a package that was read to the end against the keys trusted by
+/// `options`.
+///
+/// A package signed with a trusted key must carry a valid signature. Unsigned packages and
+/// packages signed with any other key are refused unless [`ApplyOptions::allow_untrusted`] is set.
+pub(crate) fn check_signature(
+    reader: &PatchReader<impl Read>,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let trailer = reader.trailer().expect("package was read to the end");
+
+    let Some(record) = &trailer.signature else {
+        ensure!(options.allow_untrusted, PatchError::Unsigned);
+        return Ok(());
+    };
+
+    let key = VerifyingKey::from_bytes(&record.public_key)
+        .context("Patch package signature is invalid")?;
+    if !options.trusted_keys.contains(&key) {
+        ensure!(
+            options.allow_untrusted,
+            PatchError::UntrustedKey { key: Box::new(key) }
+        );
+        return Ok(());
+    }
+
+    let signature = Signature::from_bytes(&record.signature);
+    ensure!(
+        key.0.verify_strict(&trailer.digest, &signature).is_ok(),
+        PatchError::InvalidSignature { key: Box::new(key) }
+    );
+    Ok(())
+}
+
+/// Checks that a signed package that was read to the end carries a valid signature for the key
+/// it names, without looking at which keys are trusted. Returns that key, or `None` for an
+/// unsigned package.
+pub(crate) fn check_own_signature(trailer: &Trailer) -> anyhow::Result<Option<VerifyingKey>> {
+    let Some(record) = &trailer.signature else {
+        return Ok(None);
+    };
+
+    let key = VerifyingKey::from_bytes(&record.public_key)
+        .context("Patch package signature is invalid")?;
+    let signature = Signature::from_bytes(&record.signature);
+    ensure!(
+        key.0.verify_strict(&trailer.digest, &signature).is_ok(),
+        PatchError::InvalidSignature { key: Box::new(key) }
+    );
+    Ok(Some(key))
+}
@@ -0,0 +1,430 @@
+//! Squashing consecutive patch packages into a single one.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{read, symlink_metadata},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, ensure, Context};
+use files_diff::hash;
+
+use crate::{
+    error::PatchResult,
+    file_size,
+    hunks::{TextHunks, MAX_TEXT_SIZE},
+    info::PackageInfo,
+    metadata::FileMetadata,
+    open_file, open_patch, recorded_metadata, resolve_entry_path,
+    stream::{PatchReader, PatchWriter},
+    tree::DirTree,
+    write_added_file, write_chunk_deltas, write_data_chunks, write_modified_file, write_patch_file,
+    CreateOptions, PatchEntry, PatchOperation, CHUNK_SIZE,
+};
+
+/// State of a file touched by at least one of the squashed packages.
+struct SquashedFile {
+    base_hash: Option<String>, // Hash of the file in the base tree, `None` if it doesn't exist
+    contents: Option<Vec<u8>>, // Current contents, `None` if the file is currently removed
+    metadata: Option<FileMetadata>, // Metadata recorded for the current contents, if any
+}
+
+/// State of a directory touched by at least one of the squashed packages.
+struct SquashedDir {
+    in_base: bool, // Whether the directory exists in the base tree
+    exists: bool,  // Whether the directory currently exists
+}
+
+/// In-memory view of the base tree with the packages applied on top of it. Only files touched
+/// by a package are held in memory, everything else is read from the base tree.
+struct SquashedTree<'a> {
+    base_path: &'a Path,
+    files: BTreeMap<String, SquashedFile>,
+    dirs: BTreeMap<String, SquashedDir>,
+}
+
+impl<'a> SquashedTree<'a> {
+    fn new(base_path: &'a Path) -> Self {
+        Self {
+            base_path,
+            files: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the state of a file, loading it from the base tree the first time it is touched.
+    fn file(&mut self, rel_path: &str) -> anyhow::Result<&mut SquashedFile> {
+        if !self.files.contains_key(rel_path) {
+            let file_path = resolve_entry_path(self.base_path, rel_path)?;
+            let file = match symlink_metadata(&file_path) {
+                Ok(meta) => {
+                    ensure!(
+                        meta.is_file(),
+                        "Refusing to read non-file from base tree: {}",
+                        file_path.display()
+                    );
+                    let contents = read(&file_path)
+                        .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+                    SquashedFile {
+                        base_hash: Some(hash(&contents)),
+                        contents: Some(contents),
+                        metadata: None,
+                    }
+                }
+                Err(_) => SquashedFile {
+                    base_hash: None,
+                    contents: None,
+                    metadata: None,
+                },
+            };
+            self.files.insert(rel_path.to_string(), file);
+        }
+
+        Ok(self
+            .files
+            .get_mut(rel_path)
+            .expect("file was inserted above"))
+    }
+
+    /// Returns the state of a directory, checking the base tree the first time it is touched.
+    fn dir(&mut self, rel_path: &str) -> anyhow::Result<&mut SquashedDir> {
+        if !self.dirs.contains_key(rel_path) {
+            let dir_path = resolve_entry_path(self.base_path, rel_path)?;
+            let in_base = symlink_metadata(&dir_path).is_ok_and(|meta| meta.is_dir());
+            self.dirs.insert(
+                rel_path.to_string(),
+                SquashedDir {
+                    in_base,
+                    exists: in_base,
+                },
+            );
+        }
+
+        Ok(self
+            .dirs
+            .get_mut(rel_path)
+            .expect("directory was inserted above"))
+    }
+
+    /// Applies every entry of a package to the view.
+    fn apply(&mut self, reader: &mut PatchReader<impl Read>) -> anyhow::Result<()> {
+        let chunk_size = reader.header().chunk_size;
+
+        while let Some(entry) = reader.next_entry()? {
+            let file_path = Path::new(&entry.rel_path);
+
+            match entry.operation {
+                PatchOperation::Add { hash } => {
+                    let file = self.file(&entry.rel_path)?;
+                    let mut contents = Vec::new();
+                    write_added_file(reader, file_path, &mut contents, &hash)?;
+                    file.contents = Some(contents);
+                    file.metadata = entry.metadata;
+                }
+                PatchOperation::Remove => {
+                    let file = self.file(&entry.rel_path)?;
+                    file.contents = None;
+                    file.metadata = None;
+                }
+                PatchOperation::Modify {
+                    before_hash,
+                    after_hash,
+                } => {
+                    let file = self.file(&entry.rel_path)?;
+                    let original = file
+                        .contents
+                        .as_deref()
+                        .with_context(|| format!("File to modify not found: {}", entry.rel_path))?;
+                    ensure!(
+                        hash(original) == before_hash,
+                        "Hash mismatch before applying patch to file: {}. Patches may not be consecutive.",
+                        entry.rel_path
+                    );
+
+                    if before_hash != after_hash {
+                        let mut contents = Vec::new();
+                        write_modified_file(
+                            reader,
+                            PatchReader::next_change,
+                            file_path,
+                            &mut &original[..],
+                            &mut contents,
+                            chunk_size,
+                            &after_hash,
+                        )?;
+                        file.contents = Some(contents);
+                    }
+                    file.metadata = entry.metadata.or(file.metadata.take());
+                }
+                PatchOperation::Rename {
+                    from,
+                    before_hash,
+                    after_hash,
+                } => {
+                    ensure!(
+                        self.file(&entry.rel_path)?.contents.is_none(),
+                        "Renamed file already exists: {}. Patches may not be consecutive.",
+                        entry.rel_path
+                    );
+                    let source = self.file(&from)?;
+                    let original = source
+                        .contents
+                        .take()
+                        .with_context(|| format!("File to rename not found: {}", from))?;
+                    let metadata = entry.metadata.or(source.metadata.take());
+                    ensure!(
+                        hash(&original) == before_hash,
+                        "Hash mismatch before renaming file: {}. Patches may not be consecutive.",
+                        from
+                    );
+
+                    let contents = if before_hash == after_hash {
+                        original
+                    } else {
+                        let mut contents = Vec::new();
+                        write_modified_file(
+                            reader,
+                            PatchReader::next_change,
+                            file_path,
+                            &mut &original[..],
+                            &mut contents,
+                            chunk_size,
+                            &after_hash,
+                        )?;
+                        contents
+                    };
+                    let file = self.file(&entry.rel_path)?;
+                    file.contents = Some(contents);
+                    file.metadata = metadata;
+                }
+                PatchOperation::AddDir => self.dir(&entry.rel_path)?.exists = true,
+                PatchOperation::RemoveDir => self.dir(&entry.rel_path)?.exists = false,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pairs removed base files with added files holding the exact same contents, returning the
+    /// source of every rename keyed by its destination.
+    fn renames(&self) -> HashMap<&str, &str> {
+        let mut removed: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (rel_path, file) in &self.files {
+            if let (Some(base_hash), None) = (&file.base_hash, &file.contents) {
+                removed.entry(base_hash).or_default().push(rel_path);
+            }
+        }
+        // Pair in path order
+        for sources in removed.values_mut() {
+            sources.reverse();
+        }
+
+        let mut renames = HashMap::new();
+        for (rel_path, file) in &self.files {
+            if let (None, Some(contents)) = (&file.base_hash, &file.contents) {
+                if contents.is_empty() {
+                    continue;
+                }
+                if let Some(from) = removed.get_mut(hash(contents).as_str()).and_then(Vec::pop) {
+                    renames.insert(rel_path.as_str(), from);
+                }
+            }
+        }
+        renames
+    }
+
+    /// Writes the entries that turn the base tree into the current view.
+    fn write_entries(
+        &self,
+        writer: &mut PatchWriter<impl Write>,
+        options: &CreateOptions,
+    ) -> anyhow::Result<()> {
+        let renames = if options.detect_renames {
+            self.renames()
+        } else {
+            HashMap::new()
+        };
+        let rename_sources: HashSet<&str> = renames.values().copied().collect();
+        let base_tree = DirTree::new(self.base_path);
+
+        for (rel_path, file) in &self.files {
+            let file_path = Path::new(rel_path);
+            let base_file = self.base_path.join(rel_path);
+            let metadata = file.metadata.clone().filter(|_| options.metadata);
+
+            match (&file.base_hash, &file.contents) {
+                (Some(_), None) if rename_sources.contains(rel_path.as_str()) => {
+                    // Written as part of the rename
+                }
+                (None, Some(contents)) if renames.contains_key(rel_path.as_str()) => {
+                    let from = renames[rel_path.as_str()];
+                    let hash = hash(contents);
+                    writer.write_entry(
+                        &PatchEntry::new(
+                            PatchOperation::Rename {
+                                from: from.to_string(),
+                                before_hash: hash.clone(),
+                                after_hash: hash.clone(),
+                            },
+                            rel_path.clone(),
+                        )
+                        .with_metadata(metadata),
+                    )?;
+
+                    if options.reversible {
+                        let base_metadata =
+                            recorded_metadata(&base_tree, Path::new(from), options)?;
+                        writer.write_inverse(&hash, base_metadata.as_ref())?;
+                    }
+                }
+                (Some(before_hash), Some(contents)) => {
+                    let after_hash = hash(contents);
+                    let base_metadata = recorded_metadata(&base_tree, file_path, options)?;
+                    let strategy = options.strategy_for(file_path, contents.len() as u64);
+
+                    let contents_changed = *before_hash != after_hash;
+                    let mode_changed = match (&metadata, &base_metadata) {
+                        (Some(metadata), Some(base_metadata)) => {
+                            metadata.mode != base_metadata.mode
+                        }
+                        _ => false,
+                    };
+                    if !contents_changed && !mode_changed {
+                        // Changes cancel out, skip
+                        continue;
+                    }
+                    let text_hunks = if contents_changed
+                        && options.text_hunks
+                        && file_size(&base_file)? <= MAX_TEXT_SIZE
+                    {
+                        let base_contents = read(&base_file).with_context(|| {
+                            format!("Failed to read file: {}", base_file.display())
+                        })?;
+                        TextHunks::diff(&base_contents, contents)
+                    } else {
+                        None
+                    };
+
+                    writer.write_entry(
+                        &PatchEntry::new(
+                            PatchOperation::Modify {
+                                before_hash: before_hash.clone(),
+                                after_hash,
+                            },
+                            rel_path.clone(),
+                        )
+                        .with_metadata(metadata)
+                        .with_text_hunks(text_hunks),
+                    )?;
+                    if contents_changed {
+                        write_chunk_deltas(
+                            writer,
+                            &mut open_file(&base_file)?,
+                            &mut &contents[..],
+                            file_path,
+                            strategy,
+                            PatchWriter::write_delta,
+                        )?;
+                    }
+
+                    if options.reversible {
+                        writer.write_inverse(before_hash, base_metadata.as_ref())?;
+                        if contents_changed {
+                            write_chunk_deltas(
+                                writer,
+                                &mut &contents[..],
+                                &mut open_file(&base_file)?,
+                                file_path,
+                                strategy,
+                                PatchWriter::write_reverse_delta,
+                            )?;
+                        }
+                    }
+                }
+                (Some(before_hash), None) => {
+                    writer
+                        .write_entry(&PatchEntry::new(PatchOperation::Remove, rel_path.clone()))?;
+
+                    if options.reversible {
+                        let base_metadata = recorded_metadata(&base_tree, file_path, options)?;
+                        writer.write_inverse(before_hash, base_metadata.as_ref())?;
+                        let strategy = options.strategy_for(file_path, file_size(&base_file)?);
+                        write_data_chunks(
+                            writer,
+                            &mut open_file(&base_file)?,
+                            file_path,
+                            strategy.compression,
+                        )?;
+                    }
+                }
+                (None, Some(contents)) => {
+                    writer.write_entry(
+                        &PatchEntry::new(
+                            PatchOperation::Add {
+                                hash: hash(contents),
+                            },
+                            rel_path.clone(),
+                        )
+                        .with_metadata(metadata),
+                    )?;
+                    let strategy = options.strategy_for(file_path, contents.len() as u64);
+                    write_data_chunks(writer, &mut &contents[..], file_path, strategy.compression)?;
+                }
+                (None, None) => {
+                    // Added and removed again, cancels out
+                }
+            }
+        }
+
+        for (rel_path, dir) in &self.dirs {
+            let operation = match (dir.in_base, dir.exists) {
+                (false, true) => PatchOperation::AddDir,
+                (true, false) => PatchOperation::RemoveDir,
+                _ => continue,
+            };
+            writer.write_entry(&PatchEntry::new(operation, rel_path.clone()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Squashes consecutive patch packages into a single equivalent package.
+///
+/// The packages in `patch_locs` are applied in order to an in-memory view of the tree at
+/// `base_path` (which is not modified), and the result is written to `out_loc` as one package
+/// that turns the base tree into the final tree. An added file that is removed again disappears
+/// from the output and multiple modifications of the same file collapse into one. Files that end
+/// up moved unchanged are written as renames, moved files that also changed as a removal and an
+/// addition.
+pub fn squash_patches(
+    base_path: &Path,
+    patch_locs: &[PathBuf],
+    out_loc: &Path,
+    options: &CreateOptions,
+) -> PatchResult<()> {
+    if patch_locs.is_empty() {
+        return Err(anyhow!("No patch files to squash").into());
+    }
+
+    let mut info = PackageInfo::new(&DirTree::new(base_path), options)?;
+    let mut tree = SquashedTree::new(base_path);
+    for patch_loc in patch_locs {
+        let mut reader = open_patch(patch_loc)?;
+        if let Some(patch_info) = reader.info() {
+            // Keep what the packages were made for unless told otherwise
+            info.polytrack_version = info
+                .polytrack_version
+                .or_else(|| patch_info.polytrack_version.clone());
+            info.description = info.description.or_else(|| patch_info.description.clone());
+        }
+        tree.apply(&mut reader)
+            .with_context(|| format!("Failed to squash patch file: {}", patch_loc.display()))?;
+    }
+
+    write_patch_file(out_loc, &info, CHUNK_SIZE, |writer| {
+        tree.write_entries(writer, options)
+    })?;
+    Ok(())
+}
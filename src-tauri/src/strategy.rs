@@ -0,0 +1,60 @@
+//! Choosing how the contents of each file are stored in a patch package.
+
+use std::path::Path;
+
+pub use files_diff::{CompressAlgorithm, DiffAlgorithm};
+
+/// How the contents of a file are diffed and compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiffStrategy {
+    pub algorithm: DiffAlgorithm, // Algorithm chunk patches of modified files are computed with
+    pub compression: CompressAlgorithm, // Compression of chunk patches and file contents
+}
+
+impl Default for DiffStrategy {
+    fn default() -> Self {
+        Self {
+            algorithm: DiffAlgorithm::Rsync020,
+            compression: CompressAlgorithm::Zstd,
+        }
+    }
+}
+
+/// Rule picking the strategy of the files it matches, by extension and size of the new file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffRule {
+    pub extensions: Vec<String>, // Extensions matched without the dot, case insensitive, any if empty
+    pub min_size: Option<u64>,   // Smallest file size in bytes matched
+    pub max_size: Option<u64>,   // Largest file size in bytes matched
+    pub strategy: DiffStrategy,  // Strategy of the matched files
+}
+
+impl DiffRule {
+    /// Returns whether the rule applies to the file at `rel_path` of `size` bytes.
+    pub fn matches(&self, rel_path: &Path, size: u64) -> bool {
+        let extension_matches = self.extensions.is_empty()
+            || rel_path.extension().is_some_and(|extension| {
+                self.extensions
+                    .iter()
+                    .any(|rule_extension| extension.eq_ignore_ascii_case(rule_extension))
+            });
+
+        extension_matches
+            && self.min_size.is_none_or(|min_size| size >= min_size)
+            && self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+}
+
+/// Picks the strategy of the first rule matching the file at `rel_path` of `size` bytes, or
+/// `default` if none does.
+pub(crate) fn pick_strategy(
+    rules: &[DiffRule],
+    default: DiffStrategy,
+    rel_path: &Path,
+    size: u64,
+) -> DiffStrategy {
+    rules
+        .iter()
+        .find(|rule| rule.matches(rel_path, size))
+        .map_or(default, |rule| rule.strategy)
+}
@@ -0,0 +1,1177 @@
+//! Framed on-disk format for patch packages.
+//!
+//! A package file is the [`MAGIC`] prefix followed by a sequence of frames. Every frame is a
+//! 16 byte header (kind + payload length) and a payload padded to a 16 byte boundary, so a
+//! package can be written and consumed one entry (and one chunk) at a time without ever holding
+//! the whole thing in memory.
+//!
+//! Each frame kind has its own payload type. New kinds can be added in later format versions
+//! without changing how existing frames are laid out.
+//!
+//! File contents are stored in data frames, compressed with Zstd if that makes them smaller.
+//! Modified text files may also carry their changes as text hunks, used to patch files whose
+//! contents changed slightly.
+//!
+//! Since version 10 the end frame is followed by a checksum frame, holding the SHA-512 of
+//! everything up to and including the end frame, so a corrupted package is caught before any of
+//! it is applied. A signed package has one more frame after that, holding an Ed25519 signature
+//! over the same digest. Readers that predate signatures stop at the end frame and never see it.
+//!
+//! Package files are memory-mapped when read. Frames are then used in place: records are
+//! accessed straight from their archived form and data chunks are borrowed from the map, and
+//! frames that are skipped are never touched beyond their header.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::{Deref, Range},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use files_diff::{CompressAlgorithm, Patch};
+use memmap2::Mmap;
+use rkyv::{
+    access,
+    api::high::{HighDeserializer, HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    deserialize,
+    rancor::Error,
+    ser::allocator::ArenaHandle,
+    to_bytes,
+    util::AlignedVec,
+    Archive, Deserialize, Serialize,
+};
+use sha2::{Digest, Sha512};
+
+use crate::{
+    hunks::{Granularity, HunkLine, TextHunk, TextHunks},
+    info::{PackageInfo, PackageStats},
+    metadata::FileMetadata,
+    PatchEntry, PatchOperation, CHECKSUM_VERSION, DIR_ENTRIES_VERSION,
+};
+
+/// Bytes every framed patch package starts with.
+pub(crate) const MAGIC: &[u8; 8] = b"PLPATCH\0";
+
+/// Alignment of frame headers and payloads.
+const FRAME_ALIGN: usize = 16;
+
+/// Size of a frame header: kind (1 byte), padding (7 bytes) and payload length (8 bytes).
+const FRAME_HEADER_LEN: usize = 16;
+
+/// Extra room allowed on top of the chunk size for a single frame payload.
+const FRAME_SLACK: u64 = 1024 * 1024;
+
+/// Largest amount of data accepted after the end frame.
+const MAX_TRAILER_LEN: u64 = 1024;
+
+/// Kind of a frame, stored in the first byte of its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum FrameKind {
+    Header = 0,          // Package header, always the first frame
+    Add = 1,             // Start of an added file entry
+    Remove = 2,          // Start of a removed file entry
+    Modify = 3,          // Start of a modified file entry
+    Data = 4,            // Raw chunk of file contents
+    Delta = 5,           // Patch for a single chunk of a file
+    Inverse = 6,         // Start of the data needed to revert the current entry
+    ReverseDelta = 7,    // Patch turning a chunk of the new file back into the old one
+    Rename = 8,          // Start of a renamed file entry
+    Metadata = 9,        // Permissions and modification time of the file before it
+    AddDir = 10,         // Added directory entry
+    RemoveDir = 11,      // Removed directory entry
+    Signature = 12,      // Signature of the package, only ever after the end frame
+    Info = 13,           // Package info, right after the header frame
+    CompressedData = 14, // Zstd compressed chunk of file contents
+    TextHunks = 15,      // Line or token hunks of a modified text file, after its metadata
+    Checksum = 16,       // SHA-512 of the package, only ever right after the end frame
+    End = 255,           // End of the package
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> anyhow::Result<Self> {
+        Ok(match kind {
+            0 => Self::Header,
+            1 => Self::Add,
+            2 => Self::Remove,
+            3 => Self::Modify,
+            4 => Self::Data,
+            5 => Self::Delta,
+            6 => Self::Inverse,
+            7 => Self::ReverseDelta,
+            8 => Self::Rename,
+            9 => Self::Metadata,
+            10 => Self::AddDir,
+            11 => Self::RemoveDir,
+            12 => Self::Signature,
+            13 => Self::Info,
+            14 => Self::CompressedData,
+            15 => Self::TextHunks,
+            16 => Self::Checksum,
+            255 => Self::End,
+            _ => bail!("Unknown frame kind in patch file: {}", kind),
+        })
+    }
+}
+
+/// Frame kinds holding file contents.
+const DATA_FRAMES: [FrameKind; 2] = [FrameKind::Data, FrameKind::CompressedData];
+
+/// Frame kinds that belong to the entry before them.
+const CHUNK_FRAMES: [FrameKind; 7] = [
+    FrameKind::Data,
+    FrameKind::CompressedData,
+    FrameKind::Delta,
+    FrameKind::Inverse,
+    FrameKind::ReverseDelta,
+    FrameKind::Metadata,
+    FrameKind::TextHunks,
+];
+
+/// Header of a patch package, stored in the first frame.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct PackageHeader {
+    pub version: u32,    // Version for future compatibility
+    pub chunk_size: u64, // Size of the chunks file contents are split into
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct InfoRecord {
+    polytrack_version: Option<String>,
+    description: Option<String>,
+    tool_version: String,
+    created_at: u64,
+    base_tree_hash: Option<String>,
+    ignore: Vec<String>,
+    prune_empty_dirs: bool,
+    stats: StatsRecord,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct StatsRecord {
+    added: u64,
+    removed: u64,
+    modified: u64,
+    renamed: u64,
+    added_dirs: u64,
+    removed_dirs: u64,
+    data_size: u64,
+    delta_size: u64,
+    inverse_size: u64,
+}
+
+impl From<&PackageInfo> for InfoRecord {
+    fn from(info: &PackageInfo) -> Self {
+        let stats = &info.stats;
+        Self {
+            polytrack_version: info.polytrack_version.clone(),
+            description: info.description.clone(),
+            tool_version: info.tool_version.clone(),
+            created_at: info.created_at,
+            base_tree_hash: info.base_tree_hash.clone(),
+            ignore: info.ignore.clone(),
+            prune_empty_dirs: info.prune_empty_dirs,
+            stats: StatsRecord {
+                added: stats.added,
+                removed: stats.removed,
+                modified: stats.modified,
+                renamed: stats.renamed,
+                added_dirs: stats.added_dirs,
+                removed_dirs: stats.removed_dirs,
+                data_size: stats.data_size,
+                delta_size: stats.delta_size,
+                inverse_size: stats.inverse_size,
+            },
+        }
+    }
+}
+
+impl From<InfoRecord> for PackageInfo {
+    fn from(record: InfoRecord) -> Self {
+        let stats = record.stats;
+        Self {
+            polytrack_version: record.polytrack_version,
+            description: record.description,
+            tool_version: record.tool_version,
+            created_at: record.created_at,
+            base_tree_hash: record.base_tree_hash,
+            ignore: record.ignore,
+            prune_empty_dirs: record.prune_empty_dirs,
+            stats: PackageStats {
+                added: stats.added,
+                removed: stats.removed,
+                modified: stats.modified,
+                renamed: stats.renamed,
+                added_dirs: stats.added_dirs,
+                removed_dirs: stats.removed_dirs,
+                data_size: stats.data_size,
+                delta_size: stats.delta_size,
+                inverse_size: stats.inverse_size,
+            },
+        }
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct AddRecord {
+    rel_path: String,
+    hash: String,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct RemoveRecord {
+    rel_path: String,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct ModifyRecord {
+    rel_path: String,
+    before_hash: String,
+    after_hash: String,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct RenameRecord {
+    rel_path: String,
+    from: String, // Relative path the file is moved from
+    before_hash: String,
+    after_hash: String,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct DirRecord {
+    rel_path: String,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct MetadataRecord {
+    mode: Option<u32>,
+    mtime: Option<u64>,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct TextHunksRecord {
+    tokens: bool, // Whether the file was split into tokens instead of lines
+    hunks: Vec<HunkRecord>,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct HunkRecord {
+    old_start: u64,
+    new_start: u64,
+    lines: Vec<HunkLineRecord>,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+enum HunkLineRecord {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+impl From<&TextHunks> for TextHunksRecord {
+    fn from(text_hunks: &TextHunks) -> Self {
+        Self {
+            tokens: text_hunks.granularity == Granularity::Tokens,
+            hunks: text_hunks
+                .hunks
+                .iter()
+                .map(|hunk| HunkRecord {
+                    old_start: hunk.old_start,
+                    new_start: hunk.new_start,
+                    lines: hunk
+                        .lines
+                        .iter()
+                        .map(|line| match line {
+                            HunkLine::Context(text) => HunkLineRecord::Context(text.clone()),
+                            HunkLine::Removed(text) => HunkLineRecord::Removed(text.clone()),
+                            HunkLine::Added(text) => HunkLineRecord::Added(text.clone()),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<TextHunksRecord> for TextHunks {
+    fn from(record: TextHunksRecord) -> Self {
+        Self {
+            granularity: if record.tokens {
+                Granularity::Tokens
+            } else {
+                Granularity::Lines
+            },
+            hunks: record
+                .hunks
+                .into_iter()
+                .map(|hunk| TextHunk {
+                    old_start: hunk.old_start,
+                    new_start: hunk.new_start,
+                    lines: hunk
+                        .lines
+                        .into_iter()
+                        .map(|line| match line {
+                            HunkLineRecord::Context(text) => HunkLine::Context(text),
+                            HunkLineRecord::Removed(text) => HunkLine::Removed(text),
+                            HunkLineRecord::Added(text) => HunkLine::Added(text),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct InverseRecord {
+    hash: String, // Hash of the original contents restored by reverting the entry
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+struct EndRecord {
+    entries: u64, // Number of entries written, guards against truncated files
+}
+
+/// Checksum of a package.
+#[derive(Archive, Serialize, Deserialize)]
+struct ChecksumRecord {
+    digest: [u8; 64], // SHA-512 of everything up to and including the end frame
+}
+
+/// Ed25519 signature of a package.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct SignatureRecord {
+    pub public_key: [u8; 32], // Key the package was signed with
+    pub signature: [u8; 64],  // Signature over the digest of the package
+}
+
+/// Change to a single chunk of a modified file.
+pub(crate) enum ChunkChange<T = Vec<u8>> {
+    Delta(Patch), // Patch turning the old chunk into the new one
+    Replace(T),   // New chunk stored as is, used when a patch would be bigger
+}
+
+/// Payload of a frame, read into memory or borrowed from a memory-mapped package.
+pub(crate) enum Payload {
+    Owned(AlignedVec),
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(bytes) => bytes,
+            Self::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+/// What follows the end frame of a package, known once every entry was read.
+pub(crate) struct Trailer {
+    pub digest: [u8; 64], // SHA-512 of everything up to and including the end frame
+    pub signed_len: u64,  // Length of everything up to and including the end frame
+    pub checksum: bool,   // Whether the package carries a checksum, which matched the digest
+    pub signature: Option<SignatureRecord>,
+}
+
+/// Rounds `len` up to the next frame boundary.
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(FRAME_ALIGN) * FRAME_ALIGN
+}
+
+/// Serializes a value into an aligned buffer.
+fn encode<T>(value: &T) -> anyhow::Result<AlignedVec>
+where
+    T: for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>,
+{
+    to_bytes::<Error>(value).map_err(|e| anyhow!("Failed to serialize patch frame: {:?}", e))
+}
+
+/// Validates a value in an aligned buffer and returns its archived form in place. Payloads of
+/// mapped packages are 8 byte aligned, which is enough for every record.
+fn access_record<T>(bytes: &[u8]) -> anyhow::Result<&T::Archived>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, Error>>,
+{
+    access::<T::Archived, Error>(bytes)
+        .map_err(|e| anyhow!("Failed to access archived patch frame: {:?}", e))
+}
+
+/// Validates and deserializes a value from an aligned buffer.
+fn decode<T>(bytes: &[u8]) -> anyhow::Result<T>
+where
+    T: Archive,
+    T::Archived:
+        for<'a> CheckBytes<HighValidator<'a, Error>> + Deserialize<T, HighDeserializer<Error>>,
+{
+    deserialize::<T, Error>(access_record::<T>(bytes)?)
+        .map_err(|e| anyhow!("Failed to deserialize patch frame: {:?}", e))
+}
+
+/// Writes a single frame to `out`.
+fn write_frame(out: &mut impl Write, kind: FrameKind, payload: &[u8]) -> anyhow::Result<()> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[0] = kind as u8;
+    header[8..].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+
+    let padding = padded_len(payload.len()) - payload.len();
+    out.write_all(&header)
+        .and_then(|_| out.write_all(payload))
+        .and_then(|_| out.write_all(&[0u8; FRAME_ALIGN][..padding]))
+        .context("Failed to write patch frame")
+}
+
+/// Writes the checksum frame of a package with the given digest to `out`, which must be
+/// positioned right after the end frame.
+pub(crate) fn write_checksum(out: &mut impl Write, digest: &[u8; 64]) -> anyhow::Result<()> {
+    write_frame(
+        out,
+        FrameKind::Checksum,
+        &encode(&ChecksumRecord { digest: *digest })?,
+    )?;
+    out.flush().context("Failed to flush patch file")
+}
+
+/// Appends the checksum frame to the finished package in `file`. The package is read back to
+/// compute it, since its info frame is only filled in once every entry was written.
+pub(crate) fn append_checksum(file: &mut (impl Read + Write + Seek)) -> anyhow::Result<()> {
+    let mut hasher = Sha512::new();
+    file.rewind()
+        .and_then(|_| io::copy(file, &mut hasher))
+        .context("Failed to read back patch file")?;
+    write_checksum(file, &hasher.finalize().into())
+}
+
+/// Writes the signature frame of a package to `out`, which must be positioned right after the
+/// end frame, or after the checksum frame if the package has one.
+pub(crate) fn write_signature(
+    out: &mut impl Write,
+    signature: &SignatureRecord,
+) -> anyhow::Result<()> {
+    write_frame(out, FrameKind::Signature, &encode(signature)?)?;
+    out.flush().context("Failed to flush patch file")
+}
+
+/// Parses a frame header, refusing payloads larger than `max_len`.
+fn parse_frame_header(
+    header: &[u8; FRAME_HEADER_LEN],
+    max_len: u64,
+) -> anyhow::Result<(FrameKind, usize)> {
+    let kind = FrameKind::from_u8(header[0])?;
+    let len = u64::from_le_bytes(header[8..].try_into().expect("slice is 8 bytes long"));
+    ensure!(len <= max_len, "Patch frame is too large: {} bytes", len);
+    Ok((kind, len as usize))
+}
+
+/// Reads a single frame from `inner`, refusing payloads larger than `max_len`.
+fn read_frame(inner: &mut impl Read, max_len: u64) -> anyhow::Result<(FrameKind, AlignedVec)> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    inner.read_exact(&mut header).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            anyhow!("Patch file is truncated")
+        } else {
+            anyhow!("Failed to read patch frame: {}", e)
+        }
+    })?;
+
+    let (kind, len) = parse_frame_header(&header, max_len)?;
+    let mut payload = AlignedVec::with_capacity(padded_len(len));
+    payload.resize(padded_len(len), 0);
+    inner
+        .read_exact(payload.as_mut_slice())
+        .context("Patch file is truncated")?;
+    payload.resize(len, 0);
+
+    Ok((kind, payload))
+}
+
+/// Writes a patch package frame by frame.
+pub(crate) struct PatchWriter<W: Write> {
+    inner: W,
+    entries: u64,
+    written: u64,                     // Bytes written so far
+    info: Option<(u64, PackageInfo)>, // Info frame offset and the info written to it
+    stats: PackageStats,              // Stats of the entries written so far
+    in_inverse: bool,                 // Whether the current entry is writing its revert data
+}
+
+impl<W: Write> PatchWriter<W> {
+    /// Starts a new package, writing the magic prefix and the header frame.
+    pub fn new(mut inner: W, header: &PackageHeader) -> anyhow::Result<Self> {
+        inner
+            .write_all(MAGIC)
+            .context("Failed to write patch file magic")?;
+
+        let mut writer = Self {
+            inner,
+            entries: 0,
+            written: MAGIC.len() as u64,
+            info: None,
+            stats: PackageStats::default(),
+            in_inverse: false,
+        };
+        writer.write_frame(FrameKind::Header, &encode(header)?)?;
+        Ok(writer)
+    }
+
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.inner, kind, payload)?;
+        self.written += (FRAME_HEADER_LEN + padded_len(payload.len())) as u64;
+        Ok(())
+    }
+
+    /// Writes the package info, which must come right after the header. Its stats are filled in
+    /// by [`PatchWriter::finish_with_stats`].
+    pub fn write_info(&mut self, info: &PackageInfo) -> anyhow::Result<()> {
+        self.info = Some((self.written, info.clone()));
+        self.write_frame(FrameKind::Info, &encode(&InfoRecord::from(info))?)
+    }
+
+    /// Writes the frame that starts a new entry. Its chunks must be written right after it.
+    pub fn write_entry(&mut self, entry: &PatchEntry) -> anyhow::Result<()> {
+        let rel_path = entry.rel_path.clone();
+        let (kind, payload) = match &entry.operation {
+            PatchOperation::Add { hash } => (
+                FrameKind::Add,
+                encode(&AddRecord {
+                    rel_path,
+                    hash: hash.clone(),
+                })?,
+            ),
+            PatchOperation::Remove => (FrameKind::Remove, encode(&RemoveRecord { rel_path })?),
+            PatchOperation::Modify {
+                before_hash,
+                after_hash,
+            } => (
+                FrameKind::Modify,
+                encode(&ModifyRecord {
+                    rel_path,
+                    before_hash: before_hash.clone(),
+                    after_hash: after_hash.clone(),
+                })?,
+            ),
+            PatchOperation::Rename {
+                from,
+                before_hash,
+                after_hash,
+            } => (
+                FrameKind::Rename,
+                encode(&RenameRecord {
+                    rel_path,
+                    from: from.clone(),
+                    before_hash: before_hash.clone(),
+                    after_hash: after_hash.clone(),
+                })?,
+            ),
+            PatchOperation::AddDir => (FrameKind::AddDir, encode(&DirRecord { rel_path })?),
+            PatchOperation::RemoveDir => (FrameKind::RemoveDir, encode(&DirRecord { rel_path })?),
+        };
+
+        let count = match &entry.operation {
+            PatchOperation::Add { .. } => &mut self.stats.added,
+            PatchOperation::Remove => &mut self.stats.removed,
+            PatchOperation::Modify { .. } => &mut self.stats.modified,
+            PatchOperation::Rename { .. } => &mut self.stats.renamed,
+            PatchOperation::AddDir => &mut self.stats.added_dirs,
+            PatchOperation::RemoveDir => &mut self.stats.removed_dirs,
+        };
+        *count += 1;
+        self.entries += 1;
+        self.in_inverse = false;
+        self.write_frame(kind, &payload)?;
+        self.write_metadata(entry.metadata.as_ref())?;
+        self.write_text_hunks(entry.text_hunks.as_ref())
+    }
+
+    fn write_metadata(&mut self, metadata: Option<&FileMetadata>) -> anyhow::Result<()> {
+        let Some(metadata) = metadata else {
+            return Ok(());
+        };
+        let payload = encode(&MetadataRecord {
+            mode: metadata.mode,
+            mtime: metadata.mtime,
+        })?;
+        self.write_frame(FrameKind::Metadata, &payload)
+    }
+
+    fn write_text_hunks(&mut self, text_hunks: Option<&TextHunks>) -> anyhow::Result<()> {
+        let Some(text_hunks) = text_hunks else {
+            return Ok(());
+        };
+        let payload = encode(&TextHunksRecord::from(text_hunks))?;
+        self.stats.delta_size += payload.len() as u64;
+        self.write_frame(FrameKind::TextHunks, &payload)
+    }
+
+    /// Writes a chunk of file contents for the current entry, compressed with `compression` if
+    /// that makes it smaller.
+    pub fn write_data(
+        &mut self,
+        data: &[u8],
+        compression: CompressAlgorithm,
+    ) -> anyhow::Result<()> {
+        let compressed = match compression {
+            CompressAlgorithm::None => None,
+            CompressAlgorithm::Zstd => Some(
+                compression
+                    .compress(data)
+                    .map_err(|e| anyhow!("Failed to compress file contents: {:?}", e))?,
+            ),
+        }
+        .filter(|compressed| compressed.len() < data.len());
+        let (kind, payload) = match &compressed {
+            Some(compressed) => (FrameKind::CompressedData, &compressed[..]),
+            None => (FrameKind::Data, data),
+        };
+
+        if self.in_inverse {
+            self.stats.inverse_size += payload.len() as u64;
+        } else {
+            self.stats.data_size += payload.len() as u64;
+        }
+        self.write_frame(kind, payload)
+    }
+
+    /// Writes the patch for the next chunk of the current entry.
+    pub fn write_delta(&mut self, patch: &Patch) -> anyhow::Result<()> {
+        let payload = encode(patch)?;
+        self.stats.delta_size += payload.len() as u64;
+        self.write_frame(FrameKind::Delta, &payload)
+    }
+
+    /// Starts the data needed to revert the current entry: the old contents of a removed file
+    /// as data chunks, or the reverse chunk patches of a modified file. `metadata` is the
+    /// metadata of the original file, if recorded.
+    pub fn write_inverse(
+        &mut self,
+        hash: &str,
+        metadata: Option<&FileMetadata>,
+    ) -> anyhow::Result<()> {
+        let payload = encode(&InverseRecord {
+            hash: hash.to_string(),
+        })?;
+        self.in_inverse = true;
+        self.write_frame(FrameKind::Inverse, &payload)?;
+        self.write_metadata(metadata)
+    }
+
+    /// Writes the reverse patch for the next chunk of the current entry.
+    pub fn write_reverse_delta(&mut self, patch: &Patch) -> anyhow::Result<()> {
+        let payload = encode(patch)?;
+        self.stats.inverse_size += payload.len() as u64;
+        self.write_frame(FrameKind::ReverseDelta, &payload)
+    }
+
+    /// Appends the entries written to a buffer started with [`PatchWriter::buffer`].
+    pub fn append(&mut self, buffer: PatchWriter<Vec<u8>>) -> anyhow::Result<()> {
+        self.inner
+            .write_all(&buffer.inner)
+            .context("Failed to write patch frame")?;
+        self.written += buffer.written;
+        self.entries += buffer.entries;
+        self.stats.add(&buffer.stats);
+        self.in_inverse = false;
+        Ok(())
+    }
+
+    /// Writes the end frame and flushes the underlying writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let end = encode(&EndRecord {
+            entries: self.entries,
+        })?;
+        self.write_frame(FrameKind::End, &end)?;
+        self.inner.flush().context("Failed to flush patch file")?;
+        Ok(self.inner)
+    }
+}
+
+impl PatchWriter<Vec<u8>> {
+    /// Starts an in-memory buffer of entries that is added to a package later with
+    /// [`PatchWriter::append`]. It has no magic prefix, header or end frame.
+    pub fn buffer() -> Self {
+        Self {
+            inner: Vec::new(),
+            entries: 0,
+            written: 0,
+            info: None,
+            stats: PackageStats::default(),
+            in_inverse: false,
+        }
+    }
+}
+
+impl<W: Write + Seek> PatchWriter<W> {
+    /// Writes the end frame like [`PatchWriter::finish`] and goes back to fill in the stats of
+    /// the info frame, if one was written. Only the numbers change, so the frame keeps its size.
+    pub fn finish_with_stats(mut self) -> anyhow::Result<W> {
+        let info = self.info.take();
+        let stats = self.stats.clone();
+        let mut inner = self.finish()?;
+
+        if let Some((offset, mut info)) = info {
+            let old_len = encode(&InfoRecord::from(&info))?.len();
+            info.stats = stats;
+            let payload = encode(&InfoRecord::from(&info))?;
+            ensure!(
+                payload.len() == old_len,
+                "Package info changed size while writing the patch file"
+            );
+
+            inner
+                .seek(SeekFrom::Start(offset))
+                .context("Failed to seek in patch file")?;
+            write_frame(&mut inner, FrameKind::Info, &payload)?;
+            inner
+                .seek(SeekFrom::End(0))
+                .and_then(|_| inner.flush())
+                .context("Failed to flush patch file")?;
+        }
+
+        Ok(inner)
+    }
+}
+
+/// Reader that hashes and counts everything read through it.
+struct DigestReader<R: Read> {
+    inner: R,
+    hasher: Sha512,
+    len: u64,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+/// Returns the relative path of the entry a frame starts, straight from its archived record.
+/// Returns `None` for frames that don't start an entry.
+fn archived_rel_path(kind: FrameKind, payload: &[u8]) -> anyhow::Result<Option<&str>> {
+    Ok(Some(match kind {
+        FrameKind::Add => access_record::<AddRecord>(payload)?.rel_path.as_str(),
+        FrameKind::Remove => access_record::<RemoveRecord>(payload)?.rel_path.as_str(),
+        FrameKind::Modify => access_record::<ModifyRecord>(payload)?.rel_path.as_str(),
+        FrameKind::Rename => access_record::<RenameRecord>(payload)?.rel_path.as_str(),
+        FrameKind::AddDir | FrameKind::RemoveDir => {
+            access_record::<DirRecord>(payload)?.rel_path.as_str()
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Where a reader takes its frames from.
+enum Source<R: Read> {
+    Stream(Box<DigestReader<R>>),          // Frames read one after the other
+    Mapped { map: Arc<Mmap>, pos: usize }, // Frames used in place, `pos` is where the next starts
+}
+
+/// Reads a patch package frame by frame.
+pub(crate) struct PatchReader<R: Read> {
+    source: Source<R>,
+    header: PackageHeader,
+    max_frame_len: u64,
+    peeked: Option<(FrameKind, Payload)>,
+    entries: u64,
+    finished: bool,
+    info: Option<PackageInfo>,
+    trailer: Option<Trailer>,
+}
+
+impl<R: Read> PatchReader<R> {
+    /// Opens a package read from `inner`, checking the magic prefix and reading the header
+    /// frame.
+    pub fn new(inner: R) -> anyhow::Result<Self> {
+        let mut inner = DigestReader {
+            inner,
+            hasher: Sha512::new(),
+            len: 0,
+        };
+        let mut magic = [0u8; MAGIC.len()];
+        inner
+            .read_exact(&mut magic)
+            .context("Failed to read patch file magic")?;
+        ensure!(&magic == MAGIC, "Not a patch file: invalid magic bytes");
+
+        Self::open(Source::Stream(Box::new(inner)))
+    }
+
+    /// Opens a memory-mapped package like [`PatchReader::new`]. Its frames are used in place.
+    pub fn mapped(map: Mmap) -> anyhow::Result<Self> {
+        ensure!(
+            map.starts_with(MAGIC),
+            "Not a patch file: invalid magic bytes"
+        );
+        Self::open(Source::Mapped {
+            map: Arc::new(map),
+            pos: MAGIC.len(),
+        })
+    }
+
+    fn open(source: Source<R>) -> anyhow::Result<Self> {
+        let mut reader = Self {
+            source,
+            header: PackageHeader {
+                version: 0,
+                chunk_size: 0,
+            },
+            max_frame_len: FRAME_SLACK,
+            peeked: None,
+            entries: 0,
+            finished: false,
+            info: None,
+            trailer: None,
+        };
+
+        let (kind, payload) = reader.read_frame()?;
+        ensure!(
+            kind == FrameKind::Header,
+            "Patch file does not start with a header frame"
+        );
+        reader.header = decode(&payload)?;
+        reader.max_frame_len = reader
+            .header
+            .chunk_size
+            .saturating_mul(2)
+            .saturating_add(FRAME_SLACK);
+
+        Ok(reader)
+    }
+
+    /// Returns the package header.
+    pub fn header(&self) -> &PackageHeader {
+        &self.header
+    }
+
+    /// Reads the info frame if the package has one. Must be called before reading any entry.
+    pub fn read_info(&mut self) -> anyhow::Result<()> {
+        if self.peek_frame()? == FrameKind::Info {
+            let record: InfoRecord = decode(&self.take_frame()?.1)?;
+            self.info = Some(record.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the package info read by [`PatchReader::read_info`], if any.
+    pub fn info(&self) -> Option<&PackageInfo> {
+        self.info.as_ref()
+    }
+
+    /// Returns whether directories emptied by removed files are pruned, since the package
+    /// doesn't record removed directories. Also true for packages upgraded from such a version.
+    pub fn prunes_empty_dirs(&self) -> bool {
+        self.header.version < DIR_ENTRIES_VERSION
+            || self.info.as_ref().is_some_and(|info| info.prune_empty_dirs)
+    }
+
+    fn read_frame(&mut self) -> anyhow::Result<(FrameKind, Payload)> {
+        match &mut self.source {
+            Source::Stream(inner) => read_frame(inner, self.max_frame_len)
+                .map(|(kind, payload)| (kind, Payload::Owned(payload))),
+            Source::Mapped { map, pos } => {
+                let header = map
+                    .get(*pos..*pos + FRAME_HEADER_LEN)
+                    .context("Patch file is truncated")?;
+                let (kind, len) = parse_frame_header(
+                    header.try_into().expect("slice is a frame header"),
+                    self.max_frame_len,
+                )?;
+
+                let start = *pos + FRAME_HEADER_LEN;
+                ensure!(
+                    start + padded_len(len) <= map.len(),
+                    "Patch file is truncated"
+                );
+                *pos = start + padded_len(len);
+                Ok((kind, Payload::Mapped(map.clone(), start..start + len)))
+            }
+        }
+    }
+
+    /// Reads what follows the end frame: the checksum frame of packages since version 10, then
+    /// the signature frame of a signed package. Fails if the checksum doesn't match.
+    fn read_trailer(&mut self) -> anyhow::Result<Trailer> {
+        let (digest, signed_len, rest) = match &mut self.source {
+            Source::Stream(inner) => {
+                let digest = inner.hasher.clone().finalize().into();
+                let mut rest = Vec::new();
+                inner
+                    .take(MAX_TRAILER_LEN + 1)
+                    .read_to_end(&mut rest)
+                    .context("Failed to read patch file")?;
+                (digest, inner.len - rest.len() as u64, rest)
+            }
+            Source::Mapped { map, pos } => {
+                let (signed, rest) = map.split_at(*pos);
+                let rest = &rest[..rest.len().min(MAX_TRAILER_LEN as usize + 1)];
+                (Sha512::digest(signed).into(), *pos as u64, rest.to_vec())
+            }
+        };
+        ensure!(
+            rest.len() as u64 <= MAX_TRAILER_LEN,
+            "Unexpected data after the end of the patch file"
+        );
+
+        let mut rest = &rest[..];
+        let mut next_frame = |expected: FrameKind| -> anyhow::Result<Option<AlignedVec>> {
+            if rest.first() != Some(&(expected as u8)) {
+                return Ok(None);
+            }
+            Ok(Some(read_frame(&mut rest, MAX_TRAILER_LEN)?.1))
+        };
+
+        let checksum = next_frame(FrameKind::Checksum)?
+            .map(|payload| decode::<ChecksumRecord>(&payload))
+            .transpose()?;
+        let signature = next_frame(FrameKind::Signature)?
+            .map(|payload| decode::<SignatureRecord>(&payload))
+            .transpose()?;
+        ensure!(
+            rest.is_empty(),
+            "Unexpected data after the end of the patch file"
+        );
+
+        match &checksum {
+            Some(record) => ensure!(
+                record.digest == digest,
+                "Patch file is corrupted: its checksum doesn't match its contents"
+            ),
+            None => ensure!(
+                self.header.version < CHECKSUM_VERSION,
+                "Patch file is corrupted: its checksum is missing"
+            ),
+        }
+
+        Ok(Trailer {
+            digest,
+            signed_len,
+            checksum: checksum.is_some(),
+            signature,
+        })
+    }
+
+    /// Returns the digest and signature of the package, once every entry was read.
+    pub fn trailer(&self) -> Option<&Trailer> {
+        self.trailer.as_ref()
+    }
+
+    fn peek_frame(&mut self) -> anyhow::Result<FrameKind> {
+        if let Some((kind, _)) = &self.peeked {
+            return Ok(*kind);
+        }
+        let frame = self.read_frame()?;
+        let kind = frame.0;
+        self.peeked = Some(frame);
+        Ok(kind)
+    }
+
+    fn take_frame(&mut self) -> anyhow::Result<(FrameKind, Payload)> {
+        match self.peeked.take() {
+            Some(frame) => Ok(frame),
+            None => self.read_frame(),
+        }
+    }
+
+    /// Reads the next entry, skipping any chunks of the previous entry that were not consumed.
+    /// Returns `None` once the end frame is reached.
+    pub fn next_entry(&mut self) -> anyhow::Result<Option<PatchEntry>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        loop {
+            let (kind, payload) = self.take_frame()?;
+            let entry = match kind {
+                FrameKind::Add => {
+                    let record: AddRecord = decode(&payload)?;
+                    PatchEntry::new(PatchOperation::Add { hash: record.hash }, record.rel_path)
+                }
+                FrameKind::Remove => {
+                    let record: RemoveRecord = decode(&payload)?;
+                    PatchEntry::new(PatchOperation::Remove, record.rel_path)
+                }
+                FrameKind::Modify => {
+                    let record: ModifyRecord = decode(&payload)?;
+                    PatchEntry::new(
+                        PatchOperation::Modify {
+                            before_hash: record.before_hash,
+                            after_hash: record.after_hash,
+                        },
+                        record.rel_path,
+                    )
+                }
+                FrameKind::Rename => {
+                    let record: RenameRecord = decode(&payload)?;
+                    PatchEntry::new(
+                        PatchOperation::Rename {
+                            from: record.from,
+                            before_hash: record.before_hash,
+                            after_hash: record.after_hash,
+                        },
+                        record.rel_path,
+                    )
+                }
+                FrameKind::AddDir => {
+                    let record: DirRecord = decode(&payload)?;
+                    PatchEntry::new(PatchOperation::AddDir, record.rel_path)
+                }
+                FrameKind::RemoveDir => {
+                    let record: DirRecord = decode(&payload)?;
+                    PatchEntry::new(PatchOperation::RemoveDir, record.rel_path)
+                }
+                FrameKind::Data
+                | FrameKind::CompressedData
+                | FrameKind::Delta
+                | FrameKind::Inverse
+                | FrameKind::ReverseDelta
+                | FrameKind::Metadata
+                | FrameKind::TextHunks => continue,
+                FrameKind::End => {
+                    let record: EndRecord = decode(&payload)?;
+                    ensure!(
+                        record.entries == self.entries,
+                        "Patch file is corrupted: expected {} entries, found {}",
+                        record.entries,
+                        self.entries
+                    );
+                    self.finished = true;
+                    self.trailer = Some(self.read_trailer()?);
+                    return Ok(None);
+                }
+                FrameKind::Header => bail!("Unexpected header frame in patch file"),
+                FrameKind::Signature => bail!("Unexpected signature frame in patch file"),
+                FrameKind::Checksum => bail!("Unexpected checksum frame in patch file"),
+                FrameKind::Info => bail!("Unexpected info frame in patch file"),
+            };
+
+            self.entries += 1;
+            let entry = entry.with_metadata(self.next_metadata()?);
+            return Ok(Some(entry.with_text_hunks(self.next_text_hunks()?)));
+        }
+    }
+
+    /// Reads entries up to the one at `rel_path`, skipping the others by their archived path
+    /// without decoding them. Returns `None` if the package has no such entry.
+    pub fn find_entry(&mut self, rel_path: &str) -> anyhow::Result<Option<PatchEntry>> {
+        while !self.finished {
+            let kind = self.peek_frame()?;
+            let (_, payload) = self.peeked.as_ref().expect("frame was peeked");
+            match archived_rel_path(kind, payload)? {
+                Some(path) if path == rel_path => return self.next_entry(),
+                Some(_) => self.entries += 1,
+                // Reads the end frame, or fails on frames out of place
+                None if !CHUNK_FRAMES.contains(&kind) => return self.next_entry(),
+                None => {}
+            }
+            self.take_frame()?;
+        }
+        Ok(None)
+    }
+
+    /// Takes the next frame if it belongs to the current entry and is of one of the given kinds.
+    fn next_chunk(
+        &mut self,
+        expected: &[FrameKind],
+    ) -> anyhow::Result<Option<(FrameKind, Payload)>> {
+        if self.finished || !expected.contains(&self.peek_frame()?) {
+            return Ok(None);
+        }
+        Ok(Some(self.take_frame()?))
+    }
+
+    /// Returns the file contents held by a data frame, decompressing them if needed.
+    fn decode_data(&self, kind: FrameKind, payload: Payload) -> anyhow::Result<Payload> {
+        if kind != FrameKind::CompressedData {
+            return Ok(payload);
+        }
+
+        let mut data = AlignedVec::new();
+        let decoder = zstd::Decoder::new(&payload[..])
+            .context("Failed to decompress file contents in patch file")?;
+        data.extend_from_reader(&mut decoder.take(self.max_frame_len + 1))
+            .context("Failed to decompress file contents in patch file")?;
+        ensure!(
+            data.len() as u64 <= self.max_frame_len,
+            "Compressed patch frame is too large"
+        );
+        Ok(Payload::Owned(data))
+    }
+
+    fn next_metadata(&mut self) -> anyhow::Result<Option<FileMetadata>> {
+        self.next_chunk(&[FrameKind::Metadata])?
+            .map(|(_, payload)| {
+                decode::<MetadataRecord>(&payload).map(|record| FileMetadata {
+                    mode: record.mode,
+                    mtime: record.mtime,
+                })
+            })
+            .transpose()
+    }
+
+    fn next_text_hunks(&mut self) -> anyhow::Result<Option<TextHunks>> {
+        self.next_chunk(&[FrameKind::TextHunks])?
+            .map(|(_, payload)| decode::<TextHunksRecord>(&payload).map(TextHunks::from))
+            .transpose()
+    }
+
+    /// Reads the next data chunk of the current entry, if any.
+    pub fn next_data(&mut self) -> anyhow::Result<Option<Payload>> {
+        self.next_chunk(&DATA_FRAMES)?
+            .map(|(kind, payload)| self.decode_data(kind, payload))
+            .transpose()
+    }
+
+    /// Reads the next chunk change of the current entry made of `delta_kind` frames, if any.
+    fn next_chunk_change(
+        &mut self,
+        delta_kind: FrameKind,
+    ) -> anyhow::Result<Option<ChunkChange<Payload>>> {
+        let Some((kind, payload)) =
+            self.next_chunk(&[delta_kind, FrameKind::Data, FrameKind::CompressedData])?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(if kind == delta_kind {
+            ChunkChange::Delta(decode(&payload)?)
+        } else {
+            ChunkChange::Replace(self.decode_data(kind, payload)?)
+        }))
+    }
+
+    /// Reads the change to the next chunk of the current entry, if any.
+    pub fn next_change(&mut self) -> anyhow::Result<Option<ChunkChange<Payload>>> {
+        self.next_chunk_change(FrameKind::Delta)
+    }
+
+    /// Skips the remaining chunk changes of the current entry without decoding them.
+    pub fn skip_changes(&mut self) -> anyhow::Result<()> {
+        while self
+            .next_chunk(&[FrameKind::Delta, FrameKind::Data, FrameKind::CompressedData])?
+            .is_some()
+        {}
+        Ok(())
+    }
+
+    /// Reads the start of the revert data of the current entry, returning the hash and, if
+    /// recorded, the metadata of the original file. Returns `None` if the entry can't be reverted.
+    pub fn next_inverse(&mut self) -> anyhow::Result<Option<(String, Option<FileMetadata>)>> {
+        let Some((_, payload)) = self.next_chunk(&[FrameKind::Inverse])? else {
+            return Ok(None);
+        };
+        let record: InverseRecord = decode(&payload)?;
+        Ok(Some((record.hash, self.next_metadata()?)))
+    }
+
+    /// Reads the change turning the next chunk of the new file back into the old one, if any.
+    pub fn next_reverse_change(&mut self) -> anyhow::Result<Option<ChunkChange<Payload>>> {
+        self.next_chunk_change(FrameKind::ReverseDelta)
+    }
+}
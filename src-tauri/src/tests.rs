@@ -0,0 +1,1241 @@
+//! Round trip tests of creating and applying patch packages.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use files_diff::{diff, Patch};
+use rkyv::{rancor::Error, Archive, Serialize};
+use tar::{EntryType, Header};
+use tempfile::TempDir;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    apply_patch_to, apply_patch_to_tree, apply_patch_with_options, create_patch,
+    create_patch_from_trees, create_patch_with_options, dry_run_patch, export_unified_diff,
+    import_unified_diff, read_package_info, read_patch_entry, revert_patch, sign_patch,
+    squash_patches,
+    transaction::{recover, Transaction, TRANSACTION_DIR},
+    upgrade_patch, verify_patch, ApplyOptions, ArchiveTree, CancelToken, CompressAlgorithm,
+    CreateOptions, DiffAlgorithm, DiffStrategy, DirTree, EntryChange, EntryFilter, FileTree,
+    HunkStatus, MemoryTree, NodeKind, PatchBuilder, PatchError, ProgressObserver, SigningKey,
+    POLYTRACK_VERSIONS_DIR,
+};
+
+/// Operation of a version 1 entry, laid out like the one `legacy` reads.
+#[derive(Archive, Serialize)]
+enum OperationV1 {
+    Add(Vec<u8>),
+    Remove,
+    Modify(Patch),
+}
+
+/// Entry of a version 1 package.
+#[derive(Archive, Serialize)]
+struct EntryV1 {
+    operation: OperationV1,
+    rel_path: String,
+}
+
+/// Version 1 package, archived as a whole.
+#[derive(Archive, Serialize)]
+struct PackageV1 {
+    version: u32,
+    entries: Vec<EntryV1>,
+}
+
+/// Writes a version 1 package holding `entries` to `patch_loc`.
+fn write_v1_package(patch_loc: &Path, entries: Vec<EntryV1>) {
+    let package = PackageV1 {
+        version: 1,
+        entries,
+    };
+    fs::write(patch_loc, rkyv::to_bytes::<Error>(&package).unwrap()).unwrap();
+}
+
+/// Writes `files` under `root`, creating their parent directories.
+fn write_files(root: &Path, files: &[(&str, &str)]) {
+    for (rel_path, contents) in files {
+        let path = root.join(rel_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+/// Creates the trees `old` and `new` with `old_files` and `new_files` and a package between them,
+/// returning the temporary directory holding them.
+fn package(old_files: &[(&str, &str)], new_files: &[(&str, &str)]) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    write_files(&dir.path().join("old"), old_files);
+    write_files(&dir.path().join("new"), new_files);
+    create_patch(
+        &dir.path().join("patch.plp"),
+        &dir.path().join("old"),
+        &dir.path().join("new"),
+    )
+    .unwrap();
+    dir
+}
+
+/// Copies the tree `old` to `target` to apply the package to.
+fn copy_old(dir: &TempDir) -> PathBuf {
+    let target = dir.path().join("target");
+    for entry in walkdir::WalkDir::new(dir.path().join("old")).min_depth(1) {
+        let entry = entry.unwrap();
+        let rel_path = entry.path().strip_prefix(dir.path().join("old")).unwrap();
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(target.join(rel_path)).unwrap();
+        } else {
+            fs::create_dir_all(target.join(rel_path).parent().unwrap()).unwrap();
+            fs::copy(entry.path(), target.join(rel_path)).unwrap();
+        }
+    }
+    fs::create_dir_all(&target).unwrap();
+    target
+}
+
+/// Options accepting unsigned packages, which every package here is.
+fn untrusted() -> ApplyOptions {
+    ApplyOptions {
+        allow_untrusted: true,
+        ..Default::default()
+    }
+}
+
+/// Options for applying to targets that aren't the base tree, e.g. to apply a package again.
+fn ignoring_base_tree() -> ApplyOptions {
+    ApplyOptions {
+        ignore_base_tree: true,
+        ..untrusted()
+    }
+}
+
+/// Builds a tree in memory holding `files`.
+fn memory_tree(files: &[(&str, &str)]) -> MemoryTree {
+    let mut tree = MemoryTree::new();
+    for (rel_path, contents) in files {
+        tree.insert_file(rel_path, *contents).unwrap();
+    }
+    tree
+}
+
+/// Returns every path of `tree` with the contents of its files, `None` for directories.
+fn snapshot(tree: &dyn FileTree) -> BTreeMap<PathBuf, Option<Vec<u8>>> {
+    tree.list(&|_, _| false)
+        .unwrap()
+        .into_iter()
+        .map(|(rel_path, kind)| {
+            let contents = (kind == NodeKind::File).then(|| tree.read(&rel_path).unwrap());
+            (rel_path, contents)
+        })
+        .collect()
+}
+
+/// Starts a transaction on `target`, a copy of [`OLD`], that changes `a.txt`, adds a file in a
+/// new directory and removes `data/c.txt`.
+fn stage_changes(target: &Path) -> Transaction {
+    let mut transaction = Transaction::begin(target).unwrap();
+    let staged_path = transaction.stage_write(&target.join("a.txt")).unwrap();
+    fs::write(staged_path, "first\nchanged\n").unwrap();
+    let staged_path = transaction
+        .stage_write(&target.join("data/new/e.txt"))
+        .unwrap();
+    fs::write(staged_path, "added\n").unwrap();
+    transaction
+        .stage_remove(&target.join("data/c.txt"))
+        .unwrap();
+    transaction
+}
+
+/// Records the progress reported to it, cancelling `cancel` once `cancel_after` entries are done.
+#[derive(Default)]
+struct Recorder {
+    total: Mutex<Option<u64>>,
+    finished: Mutex<Vec<String>>,
+    cancel: CancelToken,
+    cancel_after: Option<usize>,
+}
+
+impl ProgressObserver for Recorder {
+    fn started(&self, total: Option<u64>) {
+        assert!(self.finished.lock().unwrap().is_empty());
+        *self.total.lock().unwrap() = total;
+    }
+
+    fn entry_finished(&self, rel_path: &str, _bytes: u64) {
+        let mut finished = self.finished.lock().unwrap();
+        finished.push(rel_path.to_string());
+        if Some(finished.len()) == self.cancel_after {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// Files of the tree most packages here are created from.
+const OLD: &[(&str, &str)] = &[
+    ("a.txt", "first\nsecond\n"),
+    ("data/b.txt", "kept\n"),
+    ("data/c.txt", "removed\n"),
+];
+/// Files of the tree most packages here are created to.
+const NEW: &[(&str, &str)] = &[
+    ("a.txt", "first\nchanged\n"),
+    ("data/b.txt", "kept\n"),
+    ("data/d.txt", "added\n"),
+];
+
+#[test]
+fn applying_twice_reports_already_applied() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+
+    let report = apply_patch_with_options(&patch, &target, &untrusted()).unwrap();
+    assert!(report.already_applied.is_empty());
+    let err = apply_patch_with_options(&patch, &target, &untrusted()).unwrap_err();
+    assert!(matches!(err, PatchError::BaseTreeMismatch { .. }));
+    let report = apply_patch_with_options(&patch, &target, &ignoring_base_tree()).unwrap();
+    assert_eq!(report.already_applied.len(), 3);
+
+    assert_eq!(
+        fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first\nchanged\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target.join("data/d.txt")).unwrap(),
+        "added\n"
+    );
+    assert!(!target.join("data/c.txt").exists());
+}
+
+#[test]
+fn applying_the_rest_after_a_partial_apply() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+
+    let options = ApplyOptions {
+        filter: EntryFilter {
+            include: vec!["data/".to_string()],
+            ..Default::default()
+        },
+        ..untrusted()
+    };
+    let report = apply_patch_with_options(&patch, &target, &options).unwrap();
+    assert_eq!(report.skipped, ["a.txt"]);
+    assert_eq!(
+        fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first\nsecond\n"
+    );
+
+    let report = apply_patch_with_options(&patch, &target, &ignoring_base_tree()).unwrap();
+    assert_eq!(report.already_applied.len(), 2);
+    assert_eq!(
+        fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first\nchanged\n"
+    );
+}
+
+#[test]
+fn added_file_does_not_replace_unknown_contents() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    write_files(
+        &target,
+        &[("data/d.txt", "someone else's\n"), ("save.dat", "1")],
+    );
+    let patch = dir.path().join("patch.plp");
+
+    let err = apply_patch_with_options(&patch, &target, &untrusted()).unwrap_err();
+    assert!(matches!(err, PatchError::BaseTreeMismatch { .. }));
+
+    let err = apply_patch_with_options(&patch, &target, &ignoring_base_tree()).unwrap_err();
+    assert!(matches!(err, PatchError::HashMismatch { .. }));
+    assert_eq!(err.path(), Some(target.join("data/d.txt").as_path()));
+    assert_eq!(
+        fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first\nsecond\n"
+    );
+    assert_eq!(
+        fs::read_to_string(target.join("data/d.txt")).unwrap(),
+        "someone else's\n"
+    );
+}
+
+#[test]
+fn ignored_paths_are_left_out_of_the_base_tree_hash() {
+    let dir = package(OLD, NEW);
+    write_files(&dir.path().join("old"), &[("save.dat", "1")]);
+    let options = CreateOptions {
+        ignore: vec!["*.dat".to_string()],
+        ..Default::default()
+    };
+    let patch = dir.path().join("ignored.plp");
+    create_patch_with_options(
+        &patch,
+        &dir.path().join("old"),
+        &dir.path().join("new"),
+        &options,
+    )
+    .unwrap();
+
+    let target = copy_old(&dir);
+    write_files(&target, &[("save.dat", "2"), ("other.dat", "3")]);
+    let report = dry_run_patch(&patch, &target, &untrusted()).unwrap();
+    assert!(report.is_ok());
+    apply_patch_with_options(&patch, &target, &untrusted()).unwrap();
+    assert_eq!(fs::read_to_string(target.join("save.dat")).unwrap(), "2");
+}
+
+#[test]
+fn signed_packages_only_apply_with_a_trusted_key() {
+    let dir = package(OLD, NEW);
+    let patch = dir.path().join("patch.plp");
+    let old = snapshot(&DirTree::new(dir.path().join("old")));
+    let key = SigningKey::generate().unwrap();
+    let trusted = ApplyOptions {
+        trusted_keys: vec![key.verifying_key()],
+        ..Default::default()
+    };
+
+    // Unsigned packages are refused when a trusted signature is required
+    let target = copy_old(&dir);
+    let err = apply_patch_with_options(&patch, &target, &trusted).unwrap_err();
+    assert!(matches!(err, PatchError::Unsigned));
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+
+    sign_patch(&patch, &key).unwrap();
+    let signed = fs::read(&patch).unwrap();
+
+    // Keys that aren't trusted are refused unless untrusted packages are allowed
+    let other = ApplyOptions {
+        trusted_keys: vec![SigningKey::generate().unwrap().verifying_key()],
+        ..Default::default()
+    };
+    let err = apply_patch_with_options(&patch, &target, &other).unwrap_err();
+    assert!(matches!(err, PatchError::UntrustedKey { .. }));
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+
+    // Tampering with the contents or the signature is caught before anything is committed
+    let mut tampered = signed.clone();
+    tampered[signed.len() / 2] ^= 0xff;
+    fs::write(&patch, &tampered).unwrap();
+    assert!(apply_patch_with_options(&patch, &target, &trusted).is_err());
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+
+    let mut tampered = signed.clone();
+    *tampered.last_mut().unwrap() ^= 0xff;
+    fs::write(&patch, &tampered).unwrap();
+    let err = apply_patch_with_options(&patch, &target, &trusted).unwrap_err();
+    assert!(
+        matches!(err, PatchError::InvalidSignature { key: signed_with } if *signed_with == key.verifying_key())
+    );
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+
+    fs::write(&patch, &signed).unwrap();
+    apply_patch_with_options(&patch, &target, &trusted).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("new")))
+    );
+}
+
+#[test]
+fn dry_run_checks_the_package_like_apply() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+
+    // Unsigned packages are refused without allow_untrusted
+    let options = ApplyOptions::default();
+    let err = apply_patch_with_options(&patch, &target, &options).unwrap_err();
+    assert_eq!(
+        dry_run_patch(&patch, &target, &options)
+            .unwrap_err()
+            .to_string(),
+        err.to_string()
+    );
+
+    // So are targets that aren't the base tree
+    write_files(&target, &[("save.dat", "1")]);
+    let err = dry_run_patch(&patch, &target, &untrusted()).unwrap_err();
+    assert!(matches!(err, PatchError::BaseTreeMismatch { .. }));
+
+    let options = ApplyOptions {
+        filter: EntryFilter {
+            include: vec!["data/".to_string()],
+            ..Default::default()
+        },
+        ..ignoring_base_tree()
+    };
+    let report = dry_run_patch(&patch, &target, &options).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.skipped, ["a.txt"]);
+    assert_eq!(report.added, ["data/d.txt"]);
+    assert_eq!(report.removed, ["data/c.txt"]);
+    assert_eq!(
+        fs::read_to_string(target.join("a.txt")).unwrap(),
+        "first\nsecond\n"
+    );
+}
+
+#[test]
+fn upgraded_v1_package_keeps_pruning_emptied_directories() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("v1.plp");
+    write_v1_package(
+        &patch,
+        vec![
+            EntryV1 {
+                operation: OperationV1::Remove,
+                rel_path: "data/c.txt".to_string(),
+            },
+            EntryV1 {
+                operation: OperationV1::Add(b"added\n".to_vec()),
+                rel_path: "d.txt".to_string(),
+            },
+        ],
+    );
+    let upgraded = dir.path().join("upgraded.plp");
+    let upgrade = upgrade_patch(&patch, &upgraded).unwrap();
+    assert_eq!(upgrade.from_version, 1);
+
+    for patch in [&patch, &upgraded] {
+        let target = TempDir::new().unwrap();
+        write_files(target.path(), &[("data/c.txt", "removed\n")]);
+        apply_patch_with_options(patch, target.path(), &untrusted()).unwrap();
+        assert!(!target.path().join("data").exists());
+        assert_eq!(
+            fs::read_to_string(target.path().join("d.txt")).unwrap(),
+            "added\n"
+        );
+    }
+}
+
+#[test]
+fn polytrack_version_is_read_from_the_version_directory() {
+    let dir = package(OLD, NEW);
+    let options = CreateOptions {
+        polytrack_version: Some("0.5.2".to_string()),
+        ..Default::default()
+    };
+    let patch = dir.path().join("versioned.plp");
+    create_patch_with_options(
+        &patch,
+        &dir.path().join("old"),
+        &dir.path().join("new"),
+        &options,
+    )
+    .unwrap();
+
+    let versions = dir.path().join(POLYTRACK_VERSIONS_DIR);
+    write_files(&versions.join("0.5.1"), OLD);
+    let err = apply_patch_with_options(&patch, &versions.join("0.5.1"), &untrusted()).unwrap_err();
+    assert!(matches!(err, PatchError::PolyTrackMismatch { actual, .. } if actual == "0.5.1"));
+
+    write_files(&versions.join("0.5.2"), OLD);
+    apply_patch_with_options(&patch, &versions.join("0.5.2"), &untrusted()).unwrap();
+}
+
+#[test]
+fn same_trees_give_the_same_package() {
+    let dir = package(OLD, NEW);
+    assert!(
+        read_package_info(&dir.path().join("patch.plp"))
+            .unwrap()
+            .unwrap()
+            .created_at
+            > 0
+    );
+
+    let options = CreateOptions {
+        created_at: Some(1_700_000_000),
+        ..Default::default()
+    };
+    let patches = [dir.path().join("first.plp"), dir.path().join("second.plp")];
+    for patch in &patches {
+        create_patch_with_options(
+            patch,
+            &dir.path().join("old"),
+            &dir.path().join("new"),
+            &options,
+        )
+        .unwrap();
+    }
+    assert!(fs::read(&patches[0]).unwrap() == fs::read(&patches[1]).unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn errors_record_the_entry_they_happened_at() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let moved = dir.path().join("moved");
+    fs::rename(target.join("data"), &moved).unwrap();
+    std::os::unix::fs::symlink(&moved, target.join("data")).unwrap();
+
+    let patch = dir.path().join("patch.plp");
+    let err = apply_patch_with_options(&patch, &target, &ignoring_base_tree()).unwrap_err();
+    assert!(matches!(err, PatchError::Symlink { .. }));
+    assert!(err.entry().is_some());
+}
+
+#[test]
+fn single_entries_are_read_by_path() {
+    let dir = package(OLD, NEW);
+    let patch = dir.path().join("patch.plp");
+
+    let entry = read_patch_entry(&patch, "data/d.txt").unwrap().unwrap();
+    assert_eq!(entry.rel_path, "data/d.txt");
+    assert_eq!(entry.change, EntryChange::Added);
+    assert!(entry.before_hash.is_none());
+    assert!(entry.after_hash.is_some());
+
+    let entry = read_patch_entry(&patch, "a.txt").unwrap().unwrap();
+    assert_eq!(entry.change, EntryChange::Modified);
+    assert_ne!(entry.before_hash, entry.after_hash);
+
+    let entry = read_patch_entry(&patch, "data/c.txt").unwrap().unwrap();
+    assert_eq!(entry.change, EntryChange::Removed);
+
+    // Unchanged files and paths outside of both trees have no entry
+    assert!(read_patch_entry(&patch, "data/b.txt").unwrap().is_none());
+    assert!(read_patch_entry(&patch, "missing.txt").unwrap().is_none());
+}
+
+#[test]
+fn builder_removes_files_without_their_contents() {
+    let dir = TempDir::new().unwrap();
+    write_files(dir.path(), &[("gone.txt", "bye\n")]);
+    let patch = dir.path().join("built.plp");
+
+    let mut builder = PatchBuilder::new(CreateOptions {
+        reversible: true,
+        ..Default::default()
+    });
+    builder.remove("gone.txt", None).unwrap();
+    assert!(builder.write(&patch).is_err());
+
+    PatchBuilder::default()
+        .remove("gone.txt", None)
+        .unwrap()
+        .write(&patch)
+        .unwrap();
+    apply_patch_with_options(&patch, dir.path(), &untrusted()).unwrap();
+    assert!(!dir.path().join("gone.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn special_mode_bits_are_only_restored_when_asked_to() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    write_files(&dir.path().join("old"), OLD);
+    write_files(&dir.path().join("new"), NEW);
+    let new_file = dir.path().join("new/data/d.txt");
+    fs::set_permissions(&new_file, fs::Permissions::from_mode(0o4755)).unwrap();
+    let patch = dir.path().join("patch.plp");
+    let options = CreateOptions {
+        metadata: true,
+        ..Default::default()
+    };
+    create_patch_with_options(
+        &patch,
+        &dir.path().join("old"),
+        &dir.path().join("new"),
+        &options,
+    )
+    .unwrap();
+
+    let mode = |target: &Path| {
+        let meta = fs::metadata(target.join("data/d.txt")).unwrap();
+        meta.permissions().mode() & 0o7777
+    };
+    let target = copy_old(&dir);
+    apply_patch_with_options(&patch, &target, &untrusted()).unwrap();
+    assert_eq!(mode(&target), 0o755);
+
+    let target = dir.path().join("special");
+    write_files(&target, OLD);
+    let options = ApplyOptions {
+        special_mode_bits: true,
+        ..untrusted()
+    };
+    apply_patch_with_options(&patch, &target, &options).unwrap();
+    assert_eq!(mode(&target), 0o4755);
+}
+
+#[test]
+fn memory_trees_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let old = memory_tree(OLD);
+    let mut new = memory_tree(NEW);
+    new.create_dir(Path::new("empty")).unwrap();
+    create_patch_from_trees(&patch, &old, &new, &CreateOptions::default()).unwrap();
+
+    let mut target = old.clone();
+    let report = apply_patch_to_tree(&patch, &mut target, &untrusted()).unwrap();
+    assert!(report.already_applied.is_empty());
+    assert_eq!(snapshot(&target), snapshot(&new));
+}
+
+#[test]
+fn reverting_restores_the_base_tree() {
+    let dir = TempDir::new().unwrap();
+    write_files(&dir.path().join("old"), OLD);
+    write_files(&dir.path().join("new"), NEW);
+    let patch = dir.path().join("patch.plp");
+    let options = CreateOptions {
+        reversible: true,
+        ..Default::default()
+    };
+    create_patch_with_options(
+        &patch,
+        &dir.path().join("old"),
+        &dir.path().join("new"),
+        &options,
+    )
+    .unwrap();
+
+    let target = copy_old(&dir);
+    apply_patch_with_options(&patch, &target, &untrusted()).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("new")))
+    );
+    revert_patch(&patch, &target).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("old")))
+    );
+}
+
+#[test]
+fn moved_files_are_recorded_as_renames() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let contents = "a file long enough to be recognized once it moved\n".repeat(20);
+    let old = memory_tree(&[("assets/logo.svg", &contents), ("kept.txt", "kept\n")]);
+    let new = memory_tree(&[("img/logo.svg", &contents), ("kept.txt", "kept\n")]);
+    create_patch_from_trees(&patch, &old, &new, &CreateOptions::default()).unwrap();
+
+    let stats = read_package_info(&patch).unwrap().unwrap().stats;
+    assert_eq!((stats.renamed, stats.added, stats.removed), (1, 0, 0));
+    let mut target = old.clone();
+    apply_patch_to_tree(&patch, &mut target, &untrusted()).unwrap();
+    assert_eq!(snapshot(&target), snapshot(&new));
+}
+
+#[test]
+fn squashed_package_applies_and_reverts_like_the_series() {
+    let dir = TempDir::new().unwrap();
+    let trees = [
+        ("a", &[("a.txt", "one\n"), ("gone.txt", "gone\n")][..]),
+        ("b", &[("a.txt", "two\n"), ("b.txt", "added\n")][..]),
+        ("c", &[("a.txt", "three\n"), ("c/c.txt", "nested\n")][..]),
+    ];
+    for (name, files) in trees {
+        write_files(&dir.path().join(name), files);
+    }
+    let patches = [dir.path().join("ab.plp"), dir.path().join("bc.plp")];
+    create_patch(&patches[0], &dir.path().join("a"), &dir.path().join("b")).unwrap();
+    create_patch(&patches[1], &dir.path().join("b"), &dir.path().join("c")).unwrap();
+
+    let squashed = dir.path().join("ac.plp");
+    let options = CreateOptions {
+        reversible: true,
+        ..Default::default()
+    };
+    squash_patches(&dir.path().join("a"), &patches, &squashed, &options).unwrap();
+
+    let target = dir.path().join("target");
+    write_files(&target, trees[0].1);
+    apply_patch_with_options(&squashed, &target, &untrusted()).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("c")))
+    );
+    revert_patch(&squashed, &target).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("a")))
+    );
+}
+
+#[test]
+fn upgraded_v1_package_applies_like_the_original() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("v1.plp");
+    let modified = diff(
+        b"first\nsecond\n",
+        b"first\nchanged\n",
+        DiffAlgorithm::Rsync020,
+        CompressAlgorithm::None,
+    )
+    .unwrap();
+    write_v1_package(
+        &patch,
+        vec![
+            EntryV1 {
+                operation: OperationV1::Modify(modified),
+                rel_path: "a.txt".to_string(),
+            },
+            EntryV1 {
+                operation: OperationV1::Add(b"added\n".to_vec()),
+                rel_path: "data/d.txt".to_string(),
+            },
+        ],
+    );
+    let upgraded = dir.path().join("upgraded.plp");
+    upgrade_patch(&patch, &upgraded).unwrap();
+
+    let old = memory_tree(&[("a.txt", "first\nsecond\n")]);
+    let new = memory_tree(&[("a.txt", "first\nchanged\n"), ("data/d.txt", "added\n")]);
+    for patch in [&patch, &upgraded] {
+        let mut target = old.clone();
+        apply_patch_to_tree(patch, &mut target, &untrusted()).unwrap();
+        assert_eq!(snapshot(&target), snapshot(&new));
+    }
+}
+
+#[test]
+fn unified_diff_round_trip() {
+    let dir = package(OLD, NEW);
+    let diff_loc = dir.path().join("patch.diff");
+    export_unified_diff(
+        &dir.path().join("patch.plp"),
+        &dir.path().join("old"),
+        &diff_loc,
+        true,
+    )
+    .unwrap();
+    assert!(fs::read_to_string(&diff_loc).unwrap().contains("+changed"));
+
+    let imported = dir.path().join("imported.plp");
+    import_unified_diff(
+        &diff_loc,
+        &dir.path().join("old"),
+        &imported,
+        &CreateOptions::default(),
+    )
+    .unwrap();
+    let target = copy_old(&dir);
+    apply_patch_with_options(&imported, &target, &untrusted()).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&target)),
+        snapshot(&DirTree::new(dir.path().join("new")))
+    );
+}
+
+#[test]
+fn fuzzy_apply_finds_moved_hunks() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let lines: Vec<String> = (1..=20).map(|line| format!("line {}\n", line)).collect();
+    let before = lines.concat();
+    let after = before.replace("line 10\n", "line ten\n");
+    let options = CreateOptions {
+        text_hunks: true,
+        ..Default::default()
+    };
+    create_patch_from_trees(
+        &patch,
+        &memory_tree(&[("a.txt", &before)]),
+        &memory_tree(&[("a.txt", &after)]),
+        &options,
+    )
+    .unwrap();
+
+    let header = "added 1\nadded 2\nadded 3\n";
+    let mut target = memory_tree(&[("a.txt", &format!("{}{}", header, before))]);
+    let options = ApplyOptions {
+        fuzzy: true,
+        ..ignoring_base_tree()
+    };
+    let report = apply_patch_to_tree(&patch, &mut target, &options).unwrap();
+    assert_eq!(report.fuzzy.len(), 1);
+    assert_eq!(
+        report.fuzzy[0].hunks,
+        [HunkStatus::Offset { offset: 3, fuzz: 0 }]
+    );
+    assert_eq!(
+        target.file("a.txt").unwrap(),
+        format!("{}{}", header, after).as_bytes()
+    );
+}
+
+#[test]
+fn fuzzy_apply_does_not_replace_unknown_contents() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let options = CreateOptions {
+        text_hunks: true,
+        ..Default::default()
+    };
+    create_patch_from_trees(&patch, &memory_tree(OLD), &memory_tree(NEW), &options).unwrap();
+
+    let mut target = memory_tree(&[
+        ("a.txt", "header\nfirst\nsecond\n"),
+        ("data/b.txt", "kept\n"),
+        ("data/c.txt", "removed\n"),
+        ("data/d.txt", "someone else's\n"),
+    ]);
+    let before = snapshot(&target);
+
+    let options = ApplyOptions {
+        fuzzy: true,
+        ..untrusted()
+    };
+    let err = apply_patch_to_tree(&patch, &mut target, &options).unwrap_err();
+    assert!(matches!(err, PatchError::BaseTreeMismatch { .. }));
+
+    let options = ApplyOptions {
+        fuzzy: true,
+        ..ignoring_base_tree()
+    };
+    let err = apply_patch_to_tree(&patch, &mut target, &options).unwrap_err();
+    assert!(matches!(err, PatchError::HashMismatch { .. }));
+    assert_eq!(err.path(), Some(Path::new("data/d.txt")));
+    assert_eq!(snapshot(&target), before);
+}
+
+#[test]
+fn memory_tree_partial_apply_then_reapply() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let old = memory_tree(OLD);
+    let new = memory_tree(NEW);
+    create_patch_from_trees(&patch, &old, &new, &CreateOptions::default()).unwrap();
+
+    let mut target = old.clone();
+    let options = ApplyOptions {
+        filter: EntryFilter {
+            include: vec!["data/".to_string()],
+            ..Default::default()
+        },
+        ..untrusted()
+    };
+    let report = apply_patch_to_tree(&patch, &mut target, &options).unwrap();
+    assert_eq!(report.skipped, ["a.txt"]);
+
+    let report = apply_patch_to_tree(&patch, &mut target, &ignoring_base_tree()).unwrap();
+    assert_eq!(report.already_applied.len(), 2);
+    assert_eq!(snapshot(&target), snapshot(&new));
+
+    let report = apply_patch_to_tree(&patch, &mut target, &ignoring_base_tree()).unwrap();
+    assert_eq!(report.already_applied.len(), 3);
+    assert_eq!(snapshot(&target), snapshot(&new));
+}
+
+#[test]
+fn recover_rolls_back_an_interrupted_commit() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let old = snapshot(&DirTree::new(dir.path().join("old")));
+
+    for ops in 0..=3 {
+        stage_changes(&target).commit_interrupted(ops).unwrap();
+        assert!(target.join(TRANSACTION_DIR).exists());
+
+        assert!(recover(&target).unwrap());
+        assert!(!target.join(TRANSACTION_DIR).exists());
+        assert_eq!(snapshot(&DirTree::new(&target)), old);
+    }
+    assert!(!recover(&target).unwrap());
+}
+
+#[test]
+fn failed_commit_restores_the_backups() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let old = snapshot(&DirTree::new(dir.path().join("old")));
+
+    // Removing `data` fails after every file change is made, as `data/b.txt` is still in it
+    let mut transaction = stage_changes(&target);
+    transaction.stage_remove_dir(&target.join("data")).unwrap();
+    let err = transaction.commit().unwrap_err();
+    assert!(format!("{:#}", err).contains("all changes were rolled back"));
+
+    assert!(!target.join(TRANSACTION_DIR).exists());
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+}
+
+/// Creates a package storing every file with `strategy` and checks it applies and reverts to
+/// the exact trees it was created from.
+fn assert_strategy_round_trips(strategy: DiffStrategy) {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let block: Vec<u8> = (0..64 * 1024).map(|byte| (byte * 7 % 251) as u8).collect();
+    let mut changed = block.clone();
+    changed[1000..1100].fill(0);
+    changed.extend_from_slice(b"appended");
+
+    let mut old = memory_tree(OLD);
+    old.insert_file("assets/block.bin", block.clone()).unwrap();
+    let mut new = memory_tree(NEW);
+    new.insert_file("assets/block.bin", changed).unwrap();
+
+    let options = CreateOptions {
+        reversible: true,
+        default_strategy: strategy,
+        ..Default::default()
+    };
+    create_patch_from_trees(&patch, &old, &new, &options).unwrap();
+
+    let mut target = old.clone();
+    apply_patch_to_tree(&patch, &mut target, &untrusted()).unwrap();
+    assert_eq!(snapshot(&target), snapshot(&new));
+
+    let target = dir.path().join("target");
+    write_files(&target, OLD);
+    fs::create_dir_all(target.join("assets")).unwrap();
+    fs::write(target.join("assets/block.bin"), block).unwrap();
+    apply_patch_with_options(&patch, &target, &untrusted()).unwrap();
+    assert_eq!(snapshot(&DirTree::new(&target)), snapshot(&new));
+    revert_patch(&patch, &target).unwrap();
+    assert_eq!(snapshot(&DirTree::new(&target)), snapshot(&old));
+}
+
+#[test]
+fn rsync_strategy_round_trip() {
+    assert_strategy_round_trips(DiffStrategy {
+        algorithm: DiffAlgorithm::Rsync020,
+        compression: CompressAlgorithm::None,
+    });
+}
+
+#[test]
+fn rsync_zstd_strategy_round_trip() {
+    assert_strategy_round_trips(DiffStrategy {
+        algorithm: DiffAlgorithm::Rsync020,
+        compression: CompressAlgorithm::Zstd,
+    });
+}
+
+#[test]
+fn bidiff_strategy_round_trip() {
+    assert_strategy_round_trips(DiffStrategy {
+        algorithm: DiffAlgorithm::Bidiff1,
+        compression: CompressAlgorithm::None,
+    });
+}
+
+#[test]
+fn bidiff_zstd_strategy_round_trip() {
+    assert_strategy_round_trips(DiffStrategy {
+        algorithm: DiffAlgorithm::Bidiff1,
+        compression: CompressAlgorithm::Zstd,
+    });
+}
+
+#[test]
+fn cancelled_apply_leaves_the_target_untouched() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+    let old = snapshot(&DirTree::new(&target));
+
+    let recorder = Arc::new(Recorder {
+        cancel_after: Some(1),
+        ..Default::default()
+    });
+    let options = ApplyOptions {
+        progress: Some(recorder.clone()),
+        cancel: recorder.cancel.clone(),
+        ..untrusted()
+    };
+    let err = apply_patch_with_options(&patch, &target, &options).unwrap_err();
+    assert!(matches!(err, PatchError::Cancelled));
+    assert_eq!(recorder.finished.lock().unwrap().len(), 1);
+    assert!(!target.join(TRANSACTION_DIR).exists());
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+}
+
+/// Removes the transaction directory of `target` once the first entry is started.
+struct StagingRemover {
+    target: PathBuf,
+}
+
+impl ProgressObserver for StagingRemover {
+    fn entry_started(&self, _rel_path: &str) {
+        let _ = fs::remove_dir_all(self.target.join(TRANSACTION_DIR));
+    }
+}
+
+#[test]
+fn staging_failure_is_an_io_error_at_its_entry() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+    let old = snapshot(&DirTree::new(&target));
+
+    let options = ApplyOptions {
+        progress: Some(Arc::new(StagingRemover {
+            target: target.clone(),
+        })),
+        ..untrusted()
+    };
+    let err = apply_patch_with_options(&patch, &target, &options).unwrap_err();
+    let PatchError::Io { path, entry, .. } = &err else {
+        panic!("expected an IO error, got: {err:?}");
+    };
+    assert!(path.starts_with(target.join(TRANSACTION_DIR)));
+    assert!(entry.is_some());
+    assert_eq!(snapshot(&DirTree::new(&target)), old);
+}
+
+#[test]
+fn progress_reaches_the_total() {
+    let dir = package(OLD, NEW);
+    let target = copy_old(&dir);
+    let patch = dir.path().join("patch.plp");
+
+    let recorder = Arc::new(Recorder::default());
+    let options = ApplyOptions {
+        progress: Some(recorder.clone()),
+        ..untrusted()
+    };
+    apply_patch_with_options(&patch, &target, &options).unwrap();
+
+    // Every entry is reported once, so the count of finished entries only grows up to the total
+    let total = recorder.total.lock().unwrap().unwrap();
+    let finished = recorder.finished.lock().unwrap();
+    let mut unique = finished.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), finished.len());
+    assert_eq!(finished.len() as u64, total);
+}
+
+#[test]
+fn verify_catches_corrupted_and_truncated_packages() {
+    let dir = TempDir::new().unwrap();
+    let patch = dir.path().join("patch.plp");
+    let stored = b"contents stored as they are\n";
+    let options = CreateOptions {
+        default_strategy: DiffStrategy {
+            compression: CompressAlgorithm::None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    create_patch_from_trees(
+        &patch,
+        &memory_tree(&[("a.txt", "first\n")]),
+        &memory_tree(&[
+            ("a.txt", "first\n"),
+            ("b.txt", "contents stored as they are\n"),
+        ]),
+        &options,
+    )
+    .unwrap();
+    let bytes = fs::read(&patch).unwrap();
+    assert_eq!(verify_patch(&patch).unwrap().entries, 1);
+
+    // A flipped byte in the stored contents of the first entry
+    let offset = bytes
+        .windows(stored.len())
+        .position(|window| window == stored)
+        .unwrap();
+    let mut corrupted = bytes.clone();
+    corrupted[offset] ^= 0xff;
+    fs::write(&patch, &corrupted).unwrap();
+    let err = verify_patch(&patch).unwrap_err();
+    assert!(matches!(err, PatchError::CorruptData { .. }));
+    assert_eq!(err.path(), Some(Path::new("b.txt")));
+    assert_eq!(err.entry(), Some(0));
+
+    fs::write(&patch, &bytes[..bytes.len() / 2]).unwrap();
+    let err = verify_patch(&patch).unwrap_err();
+    assert!(err.to_string().contains("truncated"));
+}
+
+/// Changes an archive holding `keep.txt`, `data/b.txt`, `data/c.txt` and `assets/x/y.txt` the
+/// same way whatever its format: adds `new.txt` and empties `data`.
+fn change_archive(archive_loc: &Path, out_loc: &Path) -> ArchiveTree {
+    let mut tree = ArchiveTree::open(archive_loc).unwrap();
+    tree.write(Path::new("new.txt"), &mut &b"new\n"[..], None)
+        .unwrap();
+    tree.remove_file(Path::new("data/b.txt")).unwrap();
+    tree.remove_file(Path::new("data/c.txt")).unwrap();
+    tree.save(out_loc).unwrap();
+    ArchiveTree::open(out_loc).unwrap()
+}
+
+/// Paths and contents every changed archive ends up with, see [`change_archive`].
+fn changed_archive() -> BTreeMap<PathBuf, Option<Vec<u8>>> {
+    let mut tree = memory_tree(&[
+        ("keep.txt", "kept\n"),
+        ("assets/x/y.txt", "y\n"),
+        ("new.txt", "new\n"),
+    ]);
+    tree.create_dir(Path::new("data")).unwrap();
+    snapshot(&tree)
+}
+
+#[test]
+fn zip_archive_tree_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let archive_loc = dir.path().join("build.zip");
+    let mut zip = ZipWriter::new(fs::File::create(&archive_loc).unwrap());
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents, options) in [
+        ("keep.txt", "kept\n", stored),
+        ("data/b.txt", "b\n", deflated),
+        ("data/c.txt", "c\n", deflated),
+        ("assets/x/y.txt", "y\n", deflated),
+    ] {
+        zip.start_file(name, options).unwrap();
+        std::io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+    }
+    zip.add_symlink("link", "keep.txt", stored).unwrap();
+    zip.finish().unwrap();
+
+    let out_loc = dir.path().join("saved.zip");
+    let saved = change_archive(&archive_loc, &out_loc);
+    assert_eq!(snapshot(&saved), changed_archive());
+
+    // Unchanged entries are copied as they were stored, only the emptied implied directory gets
+    // an entry of its own and the link is kept
+    let mut zip = ZipArchive::new(fs::File::open(&out_loc).unwrap()).unwrap();
+    let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["assets/x/y.txt", "data/", "keep.txt", "link", "new.txt"]
+    );
+    assert_eq!(
+        zip.by_name("keep.txt").unwrap().compression(),
+        CompressionMethod::Stored
+    );
+    assert!(zip.by_name("link").unwrap().is_symlink());
+}
+
+#[test]
+fn tar_archive_tree_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let archive_loc = dir.path().join("build.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&archive_loc).unwrap());
+    for (name, contents) in [
+        ("keep.txt", "kept\n"),
+        ("data/b.txt", "b\n"),
+        ("data/c.txt", "c\n"),
+        ("assets/x/y.txt", "y\n"),
+    ] {
+        let mut header = Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(1_700_000_000);
+        builder
+            .append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(0o777);
+    header.set_mtime(1_700_000_000);
+    builder
+        .append_link(&mut header, "link", "keep.txt")
+        .unwrap();
+    builder.finish().unwrap();
+
+    let out_loc = dir.path().join("saved.tar");
+    let saved = change_archive(&archive_loc, &out_loc);
+    assert_eq!(snapshot(&saved), changed_archive());
+
+    // Unchanged entries keep their headers, only the emptied implied directory gets an entry of
+    // its own and the link is kept
+    let mut archive = tar::Archive::new(fs::File::open(&out_loc).unwrap());
+    let mut entries = BTreeMap::new();
+    for entry in archive.entries().unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.path().unwrap().to_string_lossy().to_string();
+        entries.insert(name, entry.header().clone());
+    }
+    let names: Vec<_> = entries.keys().map(String::as_str).collect();
+    assert_eq!(
+        names,
+        ["assets/x/y.txt", "data", "keep.txt", "link", "new.txt"]
+    );
+    assert_eq!(entries["keep.txt"].mode().unwrap(), 0o600);
+    assert_eq!(entries["keep.txt"].mtime().unwrap(), 1_700_000_000);
+    assert_eq!(entries["link"].entry_type(), EntryType::Symlink);
+    assert_eq!(
+        entries["link"].link_name().unwrap().unwrap(),
+        Path::new("keep.txt")
+    );
+}
+
+#[test]
+fn archives_with_escaping_paths_are_refused() {
+    let dir = TempDir::new().unwrap();
+
+    let zip_loc = dir.path().join("evil.zip");
+    let mut zip = ZipWriter::new(fs::File::create(&zip_loc).unwrap());
+    zip.start_file("../evil.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.finish().unwrap();
+    let err = ArchiveTree::open(&zip_loc).err().unwrap();
+    assert!(format!("{:#}", err).contains("unsafe path"));
+
+    // The builder refuses such paths, so the name is written into the header directly
+    let tar_loc = dir.path().join("evil.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&tar_loc).unwrap());
+    let mut header = Header::new_gnu();
+    header.as_gnu_mut().unwrap().name[..11].copy_from_slice(b"../evil.txt");
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, std::io::empty()).unwrap();
+    builder.finish().unwrap();
+    let err = ArchiveTree::open(&tar_loc).err().unwrap();
+    assert!(format!("{:#}", err).contains("unsafe path"));
+}
+
+#[cfg(unix)]
+#[test]
+fn applying_to_a_new_directory_leaves_the_base_untouched() {
+    use std::os::unix::fs::MetadataExt;
+
+    // Contents, inode, mode and modification time of every file under `root`
+    let files = |root: &Path| -> BTreeMap<PathBuf, (Vec<u8>, u64, u32, i64)> {
+        walkdir::WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .map(Result::unwrap)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| {
+                let meta = entry.metadata().unwrap();
+                let rel_path = entry.path().strip_prefix(root).unwrap().to_path_buf();
+                let contents = fs::read(entry.path()).unwrap();
+                (rel_path, (contents, meta.ino(), meta.mode(), meta.mtime()))
+            })
+            .collect()
+    };
+
+    let dir = package(OLD, NEW);
+    let base = copy_old(&dir);
+    let before = files(&base);
+
+    let out = dir.path().join("out");
+    apply_patch_to(&base, &dir.path().join("patch.plp"), &out, &untrusted()).unwrap();
+    assert_eq!(
+        snapshot(&DirTree::new(&out)),
+        snapshot(&DirTree::new(dir.path().join("new")))
+    );
+    assert_eq!(files(&base), before);
+
+    // Unchanged files share the inode of the base, changed ones were replaced
+    let after = files(&out);
+    assert_eq!(
+        after[Path::new("data/b.txt")].1,
+        before[Path::new("data/b.txt")].1
+    );
+    assert_ne!(after[Path::new("a.txt")].1, before[Path::new("a.txt")].1);
+}
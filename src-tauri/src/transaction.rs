@@ -0,0 +1,348 @@
+//! All-or-nothing application of file changes to a directory.
+//!
+//! Changes are first staged inside a transaction directory in the target. Committing writes a
+//! journal, moves the original files into a backup folder and the staged files into place. If
+//! anything fails (or the process dies half way), the journal is used to put every original back.
+
+use std::{
+    collections::HashSet,
+    fs::{
+        create_dir, create_dir_all, remove_dir, remove_dir_all, remove_file, rename,
+        symlink_metadata, File,
+    },
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, ensure, Context};
+use rkyv::{
+    access, deserialize, rancor::Error, to_bytes, util::AlignedVec, Archive, Deserialize, Serialize,
+};
+
+use crate::error::PatchError;
+
+/// Name of the directory, inside the target, that holds a transaction in progress.
+pub(crate) const TRANSACTION_DIR: &str = ".polylauncher-txn";
+
+const JOURNAL_FILE: &str = "journal";
+const STAGED_DIR: &str = "staged";
+const BACKUP_DIR: &str = "backup";
+
+/// A single change recorded in the journal. Its index names the staged and backup files.
+#[derive(Archive, Serialize, Deserialize)]
+enum JournalOp {
+    Write { rel_path: String }, // File is replaced by (or created from) the staged file
+    Remove { rel_path: String }, // File is removed
+    RemoveDir { rel_path: String }, // Empty directory is removed, after all files
+}
+
+/// Journal written before the commit starts, used to roll it back.
+#[derive(Archive, Serialize, Deserialize)]
+struct Journal {
+    pub ops: Vec<JournalOp>,
+    pub created_dirs: Vec<String>, // Directories created by the commit, shallowest first
+}
+
+/// A set of staged changes to a target directory.
+pub(crate) struct Transaction {
+    target: PathBuf,
+    dir: PathBuf,
+    ops: Vec<JournalOp>,
+    paths: HashSet<String>,
+    new_dirs: Vec<String>,
+    removed_dirs: Vec<String>,
+}
+
+impl Transaction {
+    /// Starts a new transaction on `target`. Fails if another one was left behind.
+    pub fn begin(target: &Path) -> anyhow::Result<Self> {
+        let dir = target.join(TRANSACTION_DIR);
+        create_dir(&dir).with_context(|| {
+            format!("Failed to create transaction directory: {}", dir.display())
+        })?;
+        create_dir(dir.join(STAGED_DIR))
+            .and_then(|_| create_dir(dir.join(BACKUP_DIR)))
+            .with_context(|| {
+                format!("Failed to create transaction directory: {}", dir.display())
+            })?;
+
+        Ok(Self {
+            target: target.to_path_buf(),
+            dir,
+            ops: Vec::new(),
+            paths: HashSet::new(),
+            new_dirs: Vec::new(),
+            removed_dirs: Vec::new(),
+        })
+    }
+
+    fn staged_path(dir: &Path, index: usize) -> PathBuf {
+        dir.join(STAGED_DIR).join(index.to_string())
+    }
+
+    fn backup_path(dir: &Path, index: usize) -> PathBuf {
+        dir.join(BACKUP_DIR).join(index.to_string())
+    }
+
+    /// Returns `file_path` relative to the target, rejecting paths that were already staged.
+    fn claim(&mut self, file_path: &Path) -> anyhow::Result<String> {
+        let rel_path = file_path
+            .strip_prefix(&self.target)
+            .with_context(|| format!("Path is outside of the target: {}", file_path.display()))?
+            .to_string_lossy()
+            .to_string();
+        ensure!(
+            self.paths.insert(rel_path.clone()),
+            PatchError::DuplicateEntry {
+                path: file_path.to_path_buf(),
+                entry: None,
+            }
+        );
+        Ok(rel_path)
+    }
+
+    /// Stages a write to `file_path`, returning the path the new contents must be written to.
+    pub fn stage_write(&mut self, file_path: &Path) -> anyhow::Result<PathBuf> {
+        let rel_path = self.claim(file_path)?;
+        self.ops.push(JournalOp::Write { rel_path });
+        Ok(Self::staged_path(&self.dir, self.ops.len() - 1))
+    }
+
+    /// Stages the removal of `file_path`.
+    pub fn stage_remove(&mut self, file_path: &Path) -> anyhow::Result<()> {
+        let rel_path = self.claim(file_path)?;
+        self.ops.push(JournalOp::Remove { rel_path });
+        Ok(())
+    }
+
+    /// Stages the creation of the directory `dir_path` and its missing parents.
+    pub fn stage_create_dir(&mut self, dir_path: &Path) -> anyhow::Result<()> {
+        let rel_path = self.claim(dir_path)?;
+        self.new_dirs.push(rel_path);
+        Ok(())
+    }
+
+    /// Stages the removal of the directory `dir_path`, which must be empty once all staged file
+    /// changes are committed.
+    pub fn stage_remove_dir(&mut self, dir_path: &Path) -> anyhow::Result<()> {
+        let rel_path = self.claim(dir_path)?;
+        self.removed_dirs.push(rel_path);
+        Ok(())
+    }
+
+    /// Discards all staged changes. The target has not been touched yet.
+    pub fn abort(self) {
+        let _ = remove_dir_all(&self.dir);
+    }
+
+    /// Builds the journal for the staged changes and durably writes it, along with the staged
+    /// files, to the transaction directory.
+    fn prepare(&mut self) -> anyhow::Result<Journal> {
+        // Directories that have to be created, for new files or on their own
+        let mut created_dirs = Vec::new();
+        let mut seen = HashSet::new();
+        let wanted_dirs = self
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                JournalOp::Write { rel_path } => Path::new(rel_path).parent(),
+                _ => None,
+            })
+            .chain(self.new_dirs.iter().map(Path::new));
+        for wanted in wanted_dirs {
+            let mut missing = Vec::new();
+            let mut current = Some(wanted);
+            while let Some(dir) = current.filter(|dir| !dir.as_os_str().is_empty()) {
+                if self.target.join(dir).exists() || !seen.insert(dir.to_path_buf()) {
+                    break;
+                }
+                missing.push(dir.to_string_lossy().to_string());
+                current = dir.parent();
+            }
+            created_dirs.extend(missing.into_iter().rev());
+        }
+
+        // Directories are removed after the files in them, deepest first
+        self.removed_dirs.sort_by(|a, b| b.cmp(a));
+        self.ops.extend(
+            self.removed_dirs
+                .drain(..)
+                .map(|rel_path| JournalOp::RemoveDir { rel_path }),
+        );
+
+        let journal = Journal {
+            ops: std::mem::take(&mut self.ops),
+            created_dirs,
+        };
+        sync_staged(&self.dir, &journal)?;
+        write_journal(&self.dir, &journal)?;
+        Ok(journal)
+    }
+
+    /// Moves all staged changes into place. On failure every change is rolled back.
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        let journal = self.prepare()?;
+        if let Err(e) = commit_ops(&self.target, &self.dir, &journal) {
+            return match rollback(&self.target, &self.dir, &journal) {
+                Ok(()) => {
+                    let _ = remove_dir_all(&self.dir);
+                    Err(e.context("Failed to apply changes, all changes were rolled back"))
+                }
+                Err(rollback_error) => Err(e.context(format!(
+                    "Failed to apply changes and failed to roll them back: {:#}",
+                    rollback_error
+                ))),
+            };
+        }
+
+        // Removing the journal is the commit point
+        remove_file(self.dir.join(JOURNAL_FILE)).context("Failed to remove transaction journal")?;
+        let _ = remove_dir_all(&self.dir);
+
+        Ok(())
+    }
+
+    /// Starts a commit and stops after the first `ops` changes, leaving the target as a process
+    /// killed half way through [`Transaction::commit`] would.
+    #[cfg(test)]
+    pub fn commit_interrupted(mut self, ops: usize) -> anyhow::Result<()> {
+        let mut journal = self.prepare()?;
+        journal.ops.truncate(ops);
+        commit_ops(&self.target, &self.dir, &journal)
+    }
+}
+
+/// Flushes every staged file to disk, so none of them can be lost once the journal says they
+/// are there.
+fn sync_staged(dir: &Path, journal: &Journal) -> anyhow::Result<()> {
+    for (index, op) in journal.ops.iter().enumerate() {
+        if let JournalOp::Write { .. } = op {
+            let staged_path = Transaction::staged_path(dir, index);
+            File::open(&staged_path)
+                .and_then(|file| file.sync_all())
+                .map_err(|source| PatchError::io(&staged_path, source))?;
+        }
+    }
+    Ok(())
+}
+
+/// Durably writes the journal into the transaction directory.
+fn write_journal(dir: &Path, journal: &Journal) -> anyhow::Result<()> {
+    let bytes = to_bytes::<Error>(journal)
+        .map_err(|e| anyhow!("Failed to serialize transaction journal: {:?}", e))?;
+
+    let temp_path = dir.join(format!("{}.tmp", JOURNAL_FILE));
+    let mut file = File::create(&temp_path).context("Failed to create transaction journal")?;
+    file.write_all(&bytes)
+        .and_then(|_| file.sync_all())
+        .context("Failed to write transaction journal")?;
+    rename(&temp_path, dir.join(JOURNAL_FILE)).context("Failed to write transaction journal")
+}
+
+/// Performs the journaled changes in order.
+fn commit_ops(target: &Path, dir: &Path, journal: &Journal) -> anyhow::Result<()> {
+    for rel_dir in &journal.created_dirs {
+        let path = target.join(rel_dir);
+        create_dir(&path).map_err(|source| PatchError::io(&path, source))?;
+    }
+
+    for (index, op) in journal.ops.iter().enumerate() {
+        match op {
+            JournalOp::Write { rel_path } => {
+                let path = target.join(rel_path);
+                if path.exists() {
+                    rename(&path, Transaction::backup_path(dir, index))
+                        .map_err(|source| PatchError::io(&path, source))?;
+                }
+                rename(Transaction::staged_path(dir, index), &path)
+                    .map_err(|source| PatchError::io(&path, source))?;
+            }
+            JournalOp::Remove { rel_path } => {
+                let path = target.join(rel_path);
+                rename(&path, Transaction::backup_path(dir, index))
+                    .map_err(|source| PatchError::io(&path, source))?;
+            }
+            JournalOp::RemoveDir { rel_path } => {
+                let path = target.join(rel_path);
+                remove_dir(&path).map_err(|source| PatchError::io(&path, source))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Undoes journaled changes. Only relies on what is on disk, so it is safe to run on a commit
+/// that was interrupted at any point.
+fn rollback(target: &Path, dir: &Path, journal: &Journal) -> anyhow::Result<()> {
+    for (index, op) in journal.ops.iter().enumerate().rev() {
+        let (rel_path, moved_in) = match op {
+            JournalOp::Write { rel_path } => {
+                (rel_path, !Transaction::staged_path(dir, index).exists())
+            }
+            JournalOp::Remove { rel_path } => (rel_path, false),
+            JournalOp::RemoveDir { rel_path } => {
+                let path = target.join(rel_path);
+                create_dir_all(&path).map_err(|source| PatchError::io(&path, source))?;
+                continue;
+            }
+        };
+        let path = target.join(rel_path);
+
+        // The staged file was moved into place, take it out again
+        if moved_in && path.exists() {
+            remove_file(&path).map_err(|source| PatchError::io(&path, source))?;
+        }
+
+        let backup = Transaction::backup_path(dir, index);
+        if backup.exists() {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).map_err(|source| PatchError::io(&path, source))?;
+            }
+            rename(&backup, &path).map_err(|source| PatchError::io(&path, source))?;
+        }
+    }
+
+    for rel_dir in journal.created_dirs.iter().rev() {
+        let _ = remove_dir(target.join(rel_dir));
+    }
+
+    Ok(())
+}
+
+/// Rolls back a transaction left behind in `target` by an interrupted run.
+/// Returns whether anything had to be rolled back.
+pub(crate) fn recover(target: &Path) -> anyhow::Result<bool> {
+    let dir = target.join(TRANSACTION_DIR);
+    let Ok(meta) = symlink_metadata(&dir) else {
+        return Ok(false);
+    };
+    ensure!(
+        !meta.file_type().is_symlink(),
+        "Refusing to recover from symlinked transaction directory: {}",
+        dir.display()
+    );
+
+    // Without a journal the commit never started (or already finished)
+    let journal_path = dir.join(JOURNAL_FILE);
+    let rolled_back = if journal_path.exists() {
+        let mut bytes = AlignedVec::<16>::new();
+        File::open(&journal_path)
+            .and_then(|mut file| bytes.extend_from_reader(&mut file))
+            .context("Failed to read transaction journal")?;
+        let archived = access::<ArchivedJournal, Error>(&bytes)
+            .map_err(|e| anyhow!("Failed to access transaction journal: {:?}", e))?;
+        let journal = deserialize::<Journal, Error>(archived)
+            .map_err(|e| anyhow!("Failed to deserialize transaction journal: {:?}", e))?;
+
+        rollback(target, &dir, &journal).context("Failed to roll back interrupted patch")?;
+        true
+    } else {
+        false
+    };
+
+    remove_dir_all(&dir)
+        .with_context(|| format!("Failed to remove transaction directory: {}", dir.display()))?;
+
+    Ok(rolled_back)
+}
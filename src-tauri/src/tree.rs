@@ -0,0 +1,437 @@
+//! Trees of files that patches are created from and applied to.
+//!
+//! [`create_patch_from_trees`](crate::create_patch_from_trees) and
+//! [`apply_patch_to_tree`](crate::apply_patch_to_tree) work on any [`FileTree`]: a directory on
+//! disk ([`DirTree`]), files held in memory ([`MemoryTree`]) or a zip or tar archive
+//! ([`ArchiveTree`](crate::ArchiveTree)).
+
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, remove_dir, remove_file, symlink_metadata, File},
+    io::{copy, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use walkdir::WalkDir;
+
+use crate::{
+    error::{PatchError, PatchResult},
+    file_size,
+    metadata::FileMetadata,
+    open_file, resolve_entry_path,
+};
+
+/// Whether a path in a tree is a file or a directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Dir,
+}
+
+/// A tree of files and directories, addressed by paths relative to its root.
+///
+/// Trees are read from several threads at once while a patch is created. Symlinks and other
+/// special files are not part of a tree.
+pub trait FileTree: Send + Sync {
+    /// Lists every file and directory in the tree, not including its root, sorted by path.
+    /// Directories `skip` returns `true` for are left out with everything in them, as are files
+    /// it returns `true` for.
+    fn list(&self, skip: &dyn Fn(&Path, NodeKind) -> bool)
+        -> PatchResult<Vec<(PathBuf, NodeKind)>>;
+
+    /// Returns what is at `rel_path`, or `None` if nothing is.
+    fn kind(&self, rel_path: &Path) -> PatchResult<Option<NodeKind>>;
+
+    /// Returns the size of the file at `rel_path` in bytes.
+    fn size(&self, rel_path: &Path) -> PatchResult<u64>;
+
+    /// Returns the permissions and modification time of the file at `rel_path`, or `None` if
+    /// the tree doesn't keep them.
+    fn metadata(&self, rel_path: &Path) -> PatchResult<Option<FileMetadata>>;
+
+    /// Opens the file at `rel_path` for reading.
+    fn open(&self, rel_path: &Path) -> PatchResult<Box<dyn Read + '_>>;
+
+    /// Writes the file at `rel_path` with `metadata` if given, replacing it if it exists and
+    /// creating missing parent directories.
+    fn write(
+        &mut self,
+        rel_path: &Path,
+        contents: &mut dyn Read,
+        metadata: Option<&FileMetadata>,
+    ) -> PatchResult<()>;
+
+    /// Removes the file at `rel_path`.
+    fn remove_file(&mut self, rel_path: &Path) -> PatchResult<()>;
+
+    /// Creates the directory at `rel_path` and its missing parents.
+    fn create_dir(&mut self, rel_path: &Path) -> PatchResult<()>;
+
+    /// Removes the empty directory at `rel_path`.
+    fn remove_dir(&mut self, rel_path: &Path) -> PatchResult<()>;
+
+    /// Computes the hash of the file at `rel_path` without reading it into memory at once.
+    /// Produces the same value as `files_diff::hash` over the whole contents.
+    fn hash(&self, rel_path: &Path) -> PatchResult<String> {
+        let mut context = md5::Context::new();
+        copy(&mut self.open(rel_path)?, &mut context)
+            .with_context(|| format!("Failed to read file: {}", rel_path.display()))?;
+        Ok(format!("{:x}", context.compute()))
+    }
+
+    /// Reads the whole file at `rel_path`.
+    fn read(&self, rel_path: &Path) -> PatchResult<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(rel_path)?
+            .read_to_end(&mut contents)
+            .with_context(|| format!("Failed to read file: {}", rel_path.display()))?;
+        Ok(contents)
+    }
+}
+
+/// Leaves out of a sorted listing what `skip` returns `true` for, and everything in skipped
+/// directories.
+pub(crate) fn prune_listing(
+    listing: impl IntoIterator<Item = (PathBuf, NodeKind)>,
+    skip: &dyn Fn(&Path, NodeKind) -> bool,
+) -> Vec<(PathBuf, NodeKind)> {
+    let mut kept = Vec::new();
+    let mut skipped_dir: Option<PathBuf> = None;
+    for (rel_path, kind) in listing {
+        if skipped_dir
+            .as_ref()
+            .is_some_and(|dir| rel_path.starts_with(dir))
+        {
+            continue;
+        }
+        if skip(&rel_path, kind) {
+            if kind == NodeKind::Dir {
+                skipped_dir = Some(rel_path);
+            }
+            continue;
+        }
+        kept.push((rel_path, kind));
+    }
+    kept
+}
+
+/// A directory on disk. Symlinks are neither listed nor followed.
+///
+/// Changes are written straight to the directory. [`apply_patch`](crate::apply_patch) should be
+/// preferred for directories, it journals its changes so they can be rolled back after a crash.
+#[derive(Clone, Debug)]
+pub struct DirTree {
+    root: PathBuf,
+}
+
+impl DirTree {
+    /// Opens the tree at the directory `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Returns the directory the tree is rooted at.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `rel_path` inside the root for a change, refusing paths that escape it or
+    /// traverse symlinks.
+    fn resolve(&self, rel_path: &Path) -> anyhow::Result<PathBuf> {
+        resolve_entry_path(&self.root, &rel_path.to_string_lossy())
+    }
+}
+
+impl FileTree for DirTree {
+    fn list(
+        &self,
+        skip: &dyn Fn(&Path, NodeKind) -> bool,
+    ) -> PatchResult<Vec<(PathBuf, NodeKind)>> {
+        let mut listing = Vec::new();
+        let mut walker = WalkDir::new(&self.root)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter();
+
+        while let Some(entry) = walker.next() {
+            let entry = entry.with_context(|| {
+                format!("Failed to read directory entry in: {}", self.root.display())
+            })?;
+            let kind = if entry.file_type().is_file() {
+                NodeKind::File
+            } else if entry.file_type().is_dir() {
+                NodeKind::Dir
+            } else {
+                continue;
+            };
+
+            let rel_path = entry
+                .path()
+                .strip_prefix(&self.root)
+                .with_context(|| {
+                    format!(
+                        "Failed to strip prefix from path: {}",
+                        entry.path().display()
+                    )
+                })?
+                .to_path_buf();
+            if skip(&rel_path, kind) {
+                if kind == NodeKind::Dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            listing.push((rel_path, kind));
+        }
+
+        Ok(listing)
+    }
+
+    fn kind(&self, rel_path: &Path) -> PatchResult<Option<NodeKind>> {
+        let path = self.root.join(rel_path);
+        let Ok(meta) = symlink_metadata(&path) else {
+            return Ok(None);
+        };
+        if meta.is_file() {
+            Ok(Some(NodeKind::File))
+        } else if meta.is_dir() {
+            Ok(Some(NodeKind::Dir))
+        } else if meta.file_type().is_symlink() {
+            Err(PatchError::Symlink { path, entry: None })
+        } else {
+            Err(anyhow!("Refusing to use special file: {}", path.display()).into())
+        }
+    }
+
+    fn size(&self, rel_path: &Path) -> PatchResult<u64> {
+        Ok(file_size(&self.root.join(rel_path))?)
+    }
+
+    fn metadata(&self, rel_path: &Path) -> PatchResult<Option<FileMetadata>> {
+        Ok(Some(FileMetadata::read(&self.root.join(rel_path))?))
+    }
+
+    fn open(&self, rel_path: &Path) -> PatchResult<Box<dyn Read + '_>> {
+        Ok(Box::new(open_file(&self.root.join(rel_path))?))
+    }
+
+    fn write(
+        &mut self,
+        rel_path: &Path,
+        contents: &mut dyn Read,
+        metadata: Option<&FileMetadata>,
+    ) -> PatchResult<()> {
+        let path = self.resolve(rel_path)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|source| PatchError::io(parent, source))?;
+        }
+
+        let mut out =
+            BufWriter::new(File::create(&path).map_err(|source| PatchError::io(&path, source))?);
+        copy(contents, &mut out)
+            .and_then(|_| out.flush())
+            .map_err(|source| PatchError::io(&path, source))?;
+        drop(out);
+
+        if let Some(metadata) = metadata {
+            metadata.restore(&path)?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&mut self, rel_path: &Path) -> PatchResult<()> {
+        let path = self.resolve(rel_path)?;
+        remove_file(&path).map_err(|source| PatchError::io(&path, source))?;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        let path = self.resolve(rel_path)?;
+        create_dir_all(&path).map_err(|source| PatchError::io(&path, source))?;
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        let path = self.resolve(rel_path)?;
+        remove_dir(&path).map_err(|source| PatchError::io(&path, source))?;
+        Ok(())
+    }
+}
+
+/// A file or directory of a [`MemoryTree`].
+#[derive(Clone, Debug)]
+enum MemoryNode {
+    File {
+        contents: Vec<u8>,
+        metadata: Option<FileMetadata>,
+    },
+    Dir,
+}
+
+/// Files and directories held in memory, e.g. to create and apply patches in tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryTree {
+    nodes: BTreeMap<PathBuf, MemoryNode>,
+}
+
+impl MemoryTree {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file with `contents` at `rel_path`, and its missing parent directories.
+    pub fn insert_file(
+        &mut self,
+        rel_path: impl AsRef<Path>,
+        contents: impl Into<Vec<u8>>,
+    ) -> PatchResult<()> {
+        Ok(self.insert(rel_path.as_ref(), contents.into(), None)?)
+    }
+
+    /// Returns the contents of the file at `rel_path`, if there is one.
+    pub fn file(&self, rel_path: impl AsRef<Path>) -> Option<&[u8]> {
+        match self.nodes.get(rel_path.as_ref()) {
+            Some(MemoryNode::File { contents, .. }) => Some(contents),
+            _ => None,
+        }
+    }
+
+    /// Adds the parent directories of `rel_path` that are missing.
+    fn insert_parents(&mut self, rel_path: &Path) -> anyhow::Result<()> {
+        for parent in rel_path.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() {
+                break;
+            }
+            match self.nodes.get(parent) {
+                Some(MemoryNode::Dir) => break,
+                Some(MemoryNode::File { .. }) => {
+                    bail!("Parent of {} is a file", rel_path.display())
+                }
+                None => {
+                    self.nodes.insert(parent.to_path_buf(), MemoryNode::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        rel_path: &Path,
+        contents: Vec<u8>,
+        metadata: Option<FileMetadata>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            !matches!(self.nodes.get(rel_path), Some(MemoryNode::Dir)),
+            PatchError::NotAFile {
+                path: rel_path.to_path_buf(),
+                entry: None
+            }
+        );
+        self.insert_parents(rel_path)?;
+        self.nodes.insert(
+            rel_path.to_path_buf(),
+            MemoryNode::File { contents, metadata },
+        );
+        Ok(())
+    }
+
+    fn node(&self, rel_path: &Path) -> anyhow::Result<&MemoryNode> {
+        self.nodes
+            .get(rel_path)
+            .with_context(|| format!("File not found: {}", rel_path.display()))
+    }
+
+    fn file_node(&self, rel_path: &Path) -> anyhow::Result<(&[u8], Option<&FileMetadata>)> {
+        match self.node(rel_path)? {
+            MemoryNode::File { contents, metadata } => Ok((contents, metadata.as_ref())),
+            MemoryNode::Dir => bail!("Not a file: {}", rel_path.display()),
+        }
+    }
+}
+
+impl FileTree for MemoryTree {
+    fn list(
+        &self,
+        skip: &dyn Fn(&Path, NodeKind) -> bool,
+    ) -> PatchResult<Vec<(PathBuf, NodeKind)>> {
+        let listing = self.nodes.iter().map(|(rel_path, node)| {
+            let kind = match node {
+                MemoryNode::File { .. } => NodeKind::File,
+                MemoryNode::Dir => NodeKind::Dir,
+            };
+            (rel_path.clone(), kind)
+        });
+        Ok(prune_listing(listing, skip))
+    }
+
+    fn kind(&self, rel_path: &Path) -> PatchResult<Option<NodeKind>> {
+        Ok(self.nodes.get(rel_path).map(|node| match node {
+            MemoryNode::File { .. } => NodeKind::File,
+            MemoryNode::Dir => NodeKind::Dir,
+        }))
+    }
+
+    fn size(&self, rel_path: &Path) -> PatchResult<u64> {
+        Ok(self.file_node(rel_path)?.0.len() as u64)
+    }
+
+    fn metadata(&self, rel_path: &Path) -> PatchResult<Option<FileMetadata>> {
+        Ok(self.file_node(rel_path)?.1.cloned())
+    }
+
+    fn open(&self, rel_path: &Path) -> PatchResult<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.file_node(rel_path)?.0)))
+    }
+
+    fn write(
+        &mut self,
+        rel_path: &Path,
+        contents: &mut dyn Read,
+        metadata: Option<&FileMetadata>,
+    ) -> PatchResult<()> {
+        let mut buffer = Vec::new();
+        contents
+            .read_to_end(&mut buffer)
+            .with_context(|| format!("Failed to write file: {}", rel_path.display()))?;
+        Ok(self.insert(rel_path, buffer, metadata.cloned())?)
+    }
+
+    fn remove_file(&mut self, rel_path: &Path) -> PatchResult<()> {
+        self.file_node(rel_path)?;
+        self.nodes.remove(rel_path);
+        Ok(())
+    }
+
+    fn create_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        match self.nodes.get(rel_path) {
+            Some(MemoryNode::Dir) => Ok(()),
+            Some(MemoryNode::File { .. }) => Err(PatchError::NotADirectory {
+                path: rel_path.to_path_buf(),
+                entry: None,
+            }),
+            None => {
+                self.insert_parents(rel_path)?;
+                self.nodes.insert(rel_path.to_path_buf(), MemoryNode::Dir);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_dir(&mut self, rel_path: &Path) -> PatchResult<()> {
+        if !matches!(self.node(rel_path)?, MemoryNode::Dir) {
+            return Err(anyhow!("Not a directory: {}", rel_path.display()).into());
+        }
+        if self
+            .nodes
+            .keys()
+            .any(|path| path != rel_path && path.starts_with(rel_path))
+        {
+            return Err(anyhow!("Directory is not empty: {}", rel_path.display()).into());
+        }
+        self.nodes.remove(rel_path);
+        Ok(())
+    }
+}
@@ -0,0 +1,1078 @@
+//! Converting patch packages to and from git style unified diffs, so changes can be reviewed and
+//! made with ordinary git tools.
+//!
+//! Text files are written as line hunks, binary files as `GIT binary patch` literals holding
+//! their new contents or as a `Binary files ... differ` placeholder. Unified diffs don't hold
+//! directories: importing a diff adds and removes the directories git would.
+
+use std::{
+    collections::HashSet,
+    fs::{read, read_to_string, symlink_metadata, File},
+    io::{self, BufWriter, Read, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::from_utf8,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use files_diff::hash;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{
+    error::PatchResult,
+    exclude::ExcludeRules,
+    file_size, hash_file,
+    hunks::{diff_lines, Granularity, HunkLine, TextHunk, TextHunks},
+    info::PackageInfo,
+    metadata::FileMetadata,
+    open_file, open_patch, recorded_metadata, resolve_entry_path,
+    stream::PatchWriter,
+    tree::DirTree,
+    validate_entry, write_chunk_deltas, write_data_chunks, write_entry_contents, write_patch_file,
+    CreateOptions, HunkStatus, PatchEntry, PatchOperation, PlannedChange, CHUNK_SIZE,
+};
+
+/// Blob id of a missing file in index lines.
+const NULL_ID: &str = "0000000000000000000000000000000000000000";
+
+/// Digits of the base 85 encoding used by binary patches, by value.
+const BASE85: &[u8; 85] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+/// Most bytes encoded on a single line of a binary patch.
+const BINARY_LINE_LEN: usize = 52;
+
+/// One side of a file change written to a diff.
+struct DiffSide<'a> {
+    path: &'a str,      // Relative path of the file
+    contents: &'a [u8], // Contents of the file
+    mode: &'static str, // Git file mode
+}
+
+/// Binary contents of a file in a diff.
+enum BinaryPatch {
+    Literal(Vec<u8>), // New contents
+    Delta(Vec<u8>),   // Git delta turning the old contents into the new ones
+}
+
+/// Changes to a single file read from a diff.
+struct FileDiff {
+    old_path: Option<String>,    // Path in the base tree, `None` for added files
+    new_path: Option<String>,    // Path in the new tree, `None` for removed files
+    new_mode: Option<u32>,       // Permissions set by the diff, if it sets any
+    hunks: Vec<TextHunk>,        // Line hunks of a text file
+    binary: Option<BinaryPatch>, // New contents of a binary file
+}
+
+/// Computes the id git gives a blob with `contents`.
+fn blob_id(contents: &[u8]) -> String {
+    let mut message = format!("blob {}\0", contents.len()).into_bytes();
+    message.extend_from_slice(contents);
+    hex::encode(sha1(&message))
+}
+
+/// Computes the SHA-1 digest of `message`, only used for the blob ids git expects in diffs.
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // Pad the last block with a single bit, zeroes and the length in bits
+    let mut tail = message[message.len() / 64 * 64..].to_vec();
+    tail.push(0x80);
+    while tail.len() % 64 != 56 {
+        tail.push(0);
+    }
+    tail.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64).chain(tail.chunks_exact(64)) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().expect("chunk is 4 bytes long"));
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// Returns the git mode of a file with the given permissions.
+fn git_mode(mode: Option<u32>) -> &'static str {
+    if mode.is_some_and(|mode| mode & 0o111 != 0) {
+        "100755"
+    } else {
+        "100644"
+    }
+}
+
+/// Parses a git mode into the permissions of a regular file.
+fn parse_mode(mode: &str) -> anyhow::Result<u32> {
+    match mode.trim() {
+        "100644" => Ok(0o644),
+        "100755" => Ok(0o755),
+        mode => bail!("Unsupported file mode in diff: {}", mode),
+    }
+}
+
+/// Returns `contents` as text if git would show it as text.
+fn as_text(contents: &[u8]) -> Option<&str> {
+    from_utf8(contents).ok().filter(|text| !text.contains('\0'))
+}
+
+/// Writes `prefix` and `path` the way git writes paths in diffs, quoted if they hold
+/// characters that would be ambiguous.
+fn quote_path(prefix: &str, path: &str) -> String {
+    if !path
+        .chars()
+        .any(|c| c == '"' || c == '\\' || c.is_control())
+    {
+        return format!("{}{}", prefix, path);
+    }
+
+    let mut quoted = format!("\"{}", prefix);
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\t' => quoted.push_str("\\t"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => {
+                for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                    quoted.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses a path written by [`quote_path`] at the start of `text`, returning it and the rest of
+/// `text` after it.
+fn parse_quoted(text: &str) -> anyhow::Result<(String, &str)> {
+    let Some(quoted) = text.strip_prefix('"') else {
+        return Ok((text.to_string(), ""));
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let path = String::from_utf8(bytes).context("Path in diff is not UTF-8")?;
+                return Ok((path, &quoted[i + 1..]));
+            }
+            '\\' => {
+                let (_, escaped) = chars.next().context("Unterminated quoted path in diff")?;
+                match escaped {
+                    'n' => bytes.push(b'\n'),
+                    't' => bytes.push(b'\t'),
+                    'r' => bytes.push(b'\r'),
+                    'a' => bytes.push(7),
+                    'b' => bytes.push(8),
+                    'f' => bytes.push(12),
+                    'v' => bytes.push(11),
+                    '0'..='7' => {
+                        let digits = quoted
+                            .get(i + 1..i + 4)
+                            .context("Invalid escape in quoted path in diff")?;
+                        bytes.push(
+                            u8::from_str_radix(digits, 8)
+                                .context("Invalid escape in quoted path in diff")?,
+                        );
+                        chars.nth(1);
+                    }
+                    c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+            c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    bail!("Unterminated quoted path in diff")
+}
+
+/// Strips the `a/` or `b/` prefix git puts in front of paths.
+fn strip_side_prefix(path: String) -> String {
+    match path.strip_prefix("a/").or_else(|| path.strip_prefix("b/")) {
+        Some(stripped) => stripped.to_string(),
+        None => path,
+    }
+}
+
+/// Parses the path of a `---` or `+++` line, `None` for `/dev/null`.
+fn parse_header_path(text: &str) -> anyhow::Result<Option<String>> {
+    // Git ends paths holding spaces with a tab
+    let text = text.trim_end_matches('\t');
+    if text == "/dev/null" {
+        return Ok(None);
+    }
+    Ok(Some(strip_side_prefix(parse_quoted(text)?.0)))
+}
+
+/// Parses the old and new paths of a `diff --git` line. Unquoted paths holding spaces can only be
+/// told apart if they are the same on both sides.
+fn parse_git_paths(text: &str) -> anyhow::Result<(String, String)> {
+    if text.starts_with('"') {
+        let (old, rest) = parse_quoted(text)?;
+        let (new, _) = parse_quoted(rest.trim_start())?;
+        return Ok((strip_side_prefix(old), strip_side_prefix(new)));
+    }
+    if let Some((old, new)) = text.split_once(" \"") {
+        let (new, _) = parse_quoted(&format!("\"{}", new))?;
+        return Ok((strip_side_prefix(old.to_string()), strip_side_prefix(new)));
+    }
+
+    // "a/<path> b/<path>"
+    let len = text.len().saturating_sub(5) / 2;
+    match (text.get(2..2 + len), text.get(2 + len..)) {
+        (Some(path), Some(rest)) if text.starts_with("a/") && rest == format!(" b/{}", path) => {
+            Ok((path.to_string(), path.to_string()))
+        }
+        _ => match text.split_once(' ') {
+            Some((old, new)) if !new.contains(' ') => Ok((
+                strip_side_prefix(old.to_string()),
+                strip_side_prefix(new.to_string()),
+            )),
+            _ => bail!("Can't tell the paths of a diff apart: {}", text),
+        },
+    }
+}
+
+/// Removes the line ending from a line of a diff.
+fn trim_line(line: &str) -> &str {
+    line.trim_end_matches(['\n', '\r'])
+}
+
+/// Encodes `data` as the lines of a binary patch: compressed, then in base 85 with the number of
+/// bytes on every line in front of it.
+fn encode_binary(data: &[u8]) -> io::Result<String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    let mut encoded = String::new();
+    for line in compressed.chunks(BINARY_LINE_LEN) {
+        encoded.push(match line.len() {
+            len @ 1..=26 => (b'A' + len as u8 - 1) as char,
+            len => (b'a' + len as u8 - 27) as char,
+        });
+        for group in line.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..group.len()].copy_from_slice(group);
+            let mut value = u32::from_be_bytes(word);
+
+            let mut digits = [0u8; 5];
+            for digit in digits.iter_mut().rev() {
+                *digit = BASE85[(value % 85) as usize];
+                value /= 85;
+            }
+            encoded.extend(digits.iter().map(|&digit| digit as char));
+        }
+        encoded.push('\n');
+    }
+    Ok(encoded)
+}
+
+/// Decodes a single line written by [`encode_binary`].
+fn decode_binary_line(line: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = line.as_bytes();
+    let len = match bytes.first() {
+        Some(&c @ b'A'..=b'Z') => (c - b'A' + 1) as usize,
+        Some(&c @ b'a'..=b'z') => (c - b'a' + 27) as usize,
+        _ => bail!("Invalid line in binary patch: {}", line),
+    };
+    let digits = &bytes[1..];
+    ensure!(
+        digits.len() == len.div_ceil(4) * 5,
+        "Invalid line in binary patch: {}",
+        line
+    );
+
+    let mut data = Vec::with_capacity(len.div_ceil(4) * 4);
+    for group in digits.chunks(5) {
+        let mut value: u32 = 0;
+        for &c in group {
+            let digit = BASE85
+                .iter()
+                .position(|&d| d == c)
+                .with_context(|| format!("Invalid line in binary patch: {}", line))?;
+            value = value
+                .checked_mul(85)
+                .and_then(|value| value.checked_add(digit as u32))
+                .with_context(|| format!("Invalid line in binary patch: {}", line))?;
+        }
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Reads a variable length integer of a git delta at `pos`.
+fn read_delta_size(delta: &[u8], pos: &mut usize) -> anyhow::Result<usize> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*pos).context("Binary delta is truncated")?;
+        *pos += 1;
+        ensure!(shift < usize::BITS, "Invalid binary delta");
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Applies a git delta to `base`, returning the new contents.
+fn apply_git_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut pos = 0;
+    ensure!(
+        read_delta_size(delta, &mut pos)? == base.len(),
+        "Binary delta does not apply to the base tree"
+    );
+    let new_len = read_delta_size(delta, &mut pos)?;
+
+    let next_byte = |pos: &mut usize| -> anyhow::Result<usize> {
+        let byte = *delta.get(*pos).context("Binary delta is truncated")?;
+        *pos += 1;
+        Ok(byte as usize)
+    };
+    let mut contents = Vec::with_capacity(new_len.min(CHUNK_SIZE as usize));
+    while pos < delta.len() {
+        let op = next_byte(&mut pos)?;
+        if op & 0x80 != 0 {
+            // Copy from the base, offset and size only hold the bytes flagged in `op`
+            let mut offset = 0;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    offset |= next_byte(&mut pos)? << (8 * i);
+                }
+            }
+            let mut size = 0;
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    size |= next_byte(&mut pos)? << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let copied = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .context("Invalid binary delta")?;
+            contents.extend_from_slice(copied);
+        } else {
+            // Insert the next `op` bytes
+            ensure!(op != 0, "Invalid binary delta");
+            let inserted = delta
+                .get(pos..pos + op)
+                .context("Binary delta is truncated")?;
+            contents.extend_from_slice(inserted);
+            pos += op;
+        }
+        ensure!(contents.len() <= new_len, "Invalid binary delta");
+    }
+
+    ensure!(contents.len() == new_len, "Invalid binary delta");
+    Ok(contents)
+}
+
+/// Writes the header and lines of a single hunk.
+fn write_hunk(out: &mut impl Write, hunk: &TextHunk) -> io::Result<()> {
+    let old_len = hunk
+        .lines
+        .iter()
+        .filter(|line| !matches!(line, HunkLine::Added(_)))
+        .count();
+    let new_len = hunk
+        .lines
+        .iter()
+        .filter(|line| !matches!(line, HunkLine::Removed(_)))
+        .count();
+    // Empty ranges start at the line before them
+    let old_start = hunk.old_start + (old_len > 0) as u64;
+    let new_start = hunk.new_start + (new_len > 0) as u64;
+    writeln!(
+        out,
+        "@@ -{},{} +{},{} @@",
+        old_start, old_len, new_start, new_len
+    )?;
+
+    for line in &hunk.lines {
+        let prefix = match line {
+            HunkLine::Context(_) => ' ',
+            HunkLine::Removed(_) => '-',
+            HunkLine::Added(_) => '+',
+        };
+        let text = line.text();
+        write!(out, "{}{}", prefix, text)?;
+        if !text.ends_with('\n') {
+            writeln!(out)?;
+            writeln!(out, "\\ No newline at end of file")?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the diff of a single file. `binary` writes the new contents of binary files instead of
+/// a placeholder.
+fn write_file_diff(
+    out: &mut impl Write,
+    old: Option<&DiffSide>,
+    new: Option<&DiffSide>,
+    binary: bool,
+) -> io::Result<()> {
+    let (Some(a), Some(b)) = (old.or(new), new.or(old)) else {
+        return Ok(());
+    };
+    writeln!(
+        out,
+        "diff --git {} {}",
+        quote_path("a/", a.path),
+        quote_path("b/", b.path)
+    )?;
+    match (old, new) {
+        (None, Some(new)) => writeln!(out, "new file mode {}", new.mode)?,
+        (Some(old), None) => writeln!(out, "deleted file mode {}", old.mode)?,
+        (Some(old), Some(new)) => {
+            if old.mode != new.mode {
+                writeln!(out, "old mode {}", old.mode)?;
+                writeln!(out, "new mode {}", new.mode)?;
+            }
+            if old.path != new.path {
+                writeln!(out, "rename from {}", quote_path("", old.path))?;
+                writeln!(out, "rename to {}", quote_path("", new.path))?;
+            }
+            if old.contents == new.contents {
+                return Ok(());
+            }
+        }
+        (None, None) => {}
+    }
+
+    let old_id = old.map_or(NULL_ID.to_string(), |old| blob_id(old.contents));
+    let new_id = new.map_or(NULL_ID.to_string(), |new| blob_id(new.contents));
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => {
+            writeln!(out, "index {}..{} {}", old_id, new_id, old.mode)?
+        }
+        _ => writeln!(out, "index {}..{}", old_id, new_id)?,
+    }
+
+    let old_path = old.map_or("/dev/null".to_string(), |old| quote_path("a/", old.path));
+    let new_path = new.map_or("/dev/null".to_string(), |new| quote_path("b/", new.path));
+    let old_contents = old.map_or(&[][..], |old| old.contents);
+    let new_contents = new.map_or(&[][..], |new| new.contents);
+    let (Some(old_text), Some(new_text)) = (as_text(old_contents), as_text(new_contents)) else {
+        if binary {
+            writeln!(out, "GIT binary patch")?;
+            writeln!(out, "literal {}", new_contents.len())?;
+            write!(out, "{}", encode_binary(new_contents)?)?;
+            writeln!(out)?;
+        } else {
+            writeln!(out, "Binary files {} and {} differ", old_path, new_path)?;
+        }
+        return Ok(());
+    };
+
+    let hunks = diff_lines(old_text, new_text);
+    if hunks.is_empty() {
+        // Empty file added or removed
+        return Ok(());
+    }
+    writeln!(out, "--- {}", old_path)?;
+    writeln!(out, "+++ {}", new_path)?;
+    for hunk in &hunks {
+        write_hunk(out, hunk)?;
+    }
+    Ok(())
+}
+
+/// Writes a patch package as a git style unified diff against the tree it applies to, so it can
+/// be reviewed like any other change.
+///
+/// Every entry is checked against `base_path` like [`crate::apply_patch`] would and its old and
+/// new contents are diffed line by line. Binary files are written as `GIT binary patch`
+/// literals if `binary` is set, which `git apply` and [`import_unified_diff`] can apply, or as a
+/// `Binary files ... differ` placeholder otherwise. Directories are left out.
+pub fn export_unified_diff(
+    patch_loc: &Path,
+    base_path: &Path,
+    out_loc: &Path,
+    binary: bool,
+) -> PatchResult<()> {
+    let mut reader = open_patch(patch_loc)?;
+    let file = File::create(out_loc)
+        .with_context(|| format!("Failed to create diff file: {}", out_loc.display()))?;
+    let mut out = BufWriter::new(file);
+
+    while let Some(entry) = reader.next_entry()? {
+        let file_path = resolve_entry_path(base_path, &entry.rel_path)?;
+        let change = validate_entry(&entry.operation, base_path, &file_path, None, false)?;
+        let (old_path, original_path) = match (&change, &entry.operation) {
+            (PlannedChange::Rename(from_path), PatchOperation::Rename { from, .. }) => {
+                (Some(from.as_str()), from_path.clone())
+            }
+            (
+                PlannedChange::Overwrite
+                | PlannedChange::Modify
+                | PlannedChange::FuzzyModify(_)
+                | PlannedChange::Remove,
+                _,
+            ) => (Some(entry.rel_path.as_str()), file_path.clone()),
+            (PlannedChange::Create, _) => (None, file_path.clone()),
+            // Nothing to show for directories and changes already in place
+            _ => continue,
+        };
+
+        let old_contents = old_path
+            .map(|_| {
+                read(&original_path)
+                    .with_context(|| format!("Failed to read file: {}", original_path.display()))
+            })
+            .transpose()?;
+        let old_mode = old_path
+            .map(|_| FileMetadata::read(&original_path))
+            .transpose()?
+            .and_then(|metadata| metadata.mode);
+        let new_contents = match entry.operation {
+            PatchOperation::Remove => None,
+            _ => {
+                let mut contents = Vec::new();
+                write_entry_contents(
+                    &mut reader,
+                    &entry.operation,
+                    &file_path,
+                    || open_file(&original_path),
+                    &mut contents,
+                )?;
+                Some(contents)
+            }
+        };
+        let new_mode = entry
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.mode)
+            .or(old_mode);
+
+        let old = old_path
+            .zip(old_contents.as_deref())
+            .map(|(path, contents)| DiffSide {
+                path,
+                contents,
+                mode: git_mode(old_mode),
+            });
+        let new = new_contents.as_deref().map(|contents| DiffSide {
+            path: &entry.rel_path,
+            contents,
+            mode: git_mode(new_mode),
+        });
+        write_file_diff(&mut out, old.as_ref(), new.as_ref(), binary)
+            .and_then(|_| out.flush())
+            .with_context(|| format!("Failed to write diff file: {}", out_loc.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Parses the lines of a hunk starting with its `header`.
+fn parse_hunk<'a>(
+    header: &str,
+    lines: &mut Peekable<impl Iterator<Item = &'a str>>,
+) -> anyhow::Result<TextHunk> {
+    let invalid = || anyhow!("Invalid hunk header in diff: {}", trim_line(header));
+    let (old_range, new_range) = header
+        .strip_prefix("@@ -")
+        .and_then(|rest| rest.split_once(" @@"))
+        .and_then(|(ranges, _)| ranges.split_once(" +"))
+        .ok_or_else(invalid)?;
+    let parse_range = |range: &str| -> Option<(u64, u64)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, mut old_left) = parse_range(old_range).ok_or_else(invalid)?;
+    let (new_start, mut new_left) = parse_range(new_range).ok_or_else(invalid)?;
+
+    // Empty ranges start at the line before them
+    let mut hunk = TextHunk {
+        old_start: if old_left == 0 {
+            old_start
+        } else {
+            old_start.checked_sub(1).ok_or_else(invalid)?
+        },
+        new_start: if new_left == 0 {
+            new_start
+        } else {
+            new_start.checked_sub(1).ok_or_else(invalid)?
+        },
+        lines: Vec::new(),
+    };
+    while old_left > 0 || new_left > 0 {
+        let line = lines.next().context("Diff ends in the middle of a hunk")?;
+        let (kind, text) = match line.chars().next() {
+            // Some tools strip the space of empty context lines
+            Some('\n' | '\r') => (' ', line),
+            Some(kind) => (kind, &line[1..]),
+            None => bail!("Diff ends in the middle of a hunk"),
+        };
+        let text = text.to_string();
+        let hunk_line = match kind {
+            ' ' if old_left > 0 && new_left > 0 => {
+                old_left -= 1;
+                new_left -= 1;
+                HunkLine::Context(text)
+            }
+            '-' if old_left > 0 => {
+                old_left -= 1;
+                HunkLine::Removed(text)
+            }
+            '+' if new_left > 0 => {
+                new_left -= 1;
+                HunkLine::Added(text)
+            }
+            _ => bail!("Invalid line in hunk: {}", trim_line(line)),
+        };
+        hunk.lines.push(hunk_line);
+
+        if lines.next_if(|line| line.starts_with('\\')).is_some() {
+            // "\ No newline at end of file"
+            if let Some(HunkLine::Context(text) | HunkLine::Removed(text) | HunkLine::Added(text)) =
+                hunk.lines.last_mut()
+            {
+                if text.ends_with('\n') {
+                    text.pop();
+                }
+            }
+        }
+    }
+
+    Ok(hunk)
+}
+
+/// Parses a single section of a binary patch, `None` if the next line doesn't start one.
+fn parse_binary_section<'a>(
+    lines: &mut Peekable<impl Iterator<Item = &'a str>>,
+) -> anyhow::Result<Option<BinaryPatch>> {
+    let Some(header) =
+        lines.next_if(|line| line.starts_with("literal ") || line.starts_with("delta "))
+    else {
+        return Ok(None);
+    };
+    let header = trim_line(header);
+    let (kind, len) = header.split_once(' ').context("Invalid binary patch")?;
+    let len: usize = len
+        .parse()
+        .with_context(|| format!("Invalid binary patch header: {}", header))?;
+
+    let mut compressed = Vec::new();
+    for line in lines.by_ref() {
+        let line = trim_line(line);
+        if line.is_empty() {
+            break;
+        }
+        compressed.extend(decode_binary_line(line)?);
+    }
+    let mut data = Vec::with_capacity(len.min(CHUNK_SIZE as usize));
+    ZlibDecoder::new(&compressed[..])
+        .take(len as u64 + 1)
+        .read_to_end(&mut data)
+        .context("Failed to decompress binary patch")?;
+    ensure!(
+        data.len() == len,
+        "Binary patch holds the wrong number of bytes"
+    );
+
+    Ok(Some(match kind {
+        "literal" => BinaryPatch::Literal(data),
+        _ => BinaryPatch::Delta(data),
+    }))
+}
+
+/// Parses a binary patch, keeping the forward section and skipping the reverse one after it.
+fn parse_binary<'a>(
+    lines: &mut Peekable<impl Iterator<Item = &'a str>>,
+) -> anyhow::Result<BinaryPatch> {
+    let forward = parse_binary_section(lines)?.context("Binary patch has no contents")?;
+    parse_binary_section(lines)?;
+    Ok(forward)
+}
+
+/// Parses the file changes of a git style unified diff. Anything before the first
+/// `diff --git` line, like a commit message, is skipped.
+fn parse_diff(text: &str) -> anyhow::Result<Vec<FileDiff>> {
+    let mut lines = text.split_inclusive('\n').peekable();
+    let mut diffs = Vec::new();
+
+    while let Some(line) = lines.next() {
+        let Some(paths) = line.strip_prefix("diff --git ") else {
+            continue;
+        };
+        let (old_path, new_path) = parse_git_paths(trim_line(paths))?;
+        let mut diff = FileDiff {
+            old_path: Some(old_path),
+            new_path: Some(new_path),
+            new_mode: None,
+            hunks: Vec::new(),
+            binary: None,
+        };
+        let (mut added, mut removed) = (false, false);
+
+        // Extended header lines
+        while let Some(line) = lines.next_if(|line| {
+            ![
+                "diff --git ",
+                "--- ",
+                "@@ ",
+                "GIT binary patch",
+                "Binary files ",
+            ]
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+        }) {
+            let line = trim_line(line);
+            if let Some(mode) = line.strip_prefix("new file mode ") {
+                added = true;
+                diff.new_mode = Some(parse_mode(mode)?);
+            } else if line.starts_with("deleted file mode ") {
+                removed = true;
+            } else if let Some(mode) = line.strip_prefix("new mode ") {
+                diff.new_mode = Some(parse_mode(mode)?);
+            } else if let Some(from) = line.strip_prefix("rename from ") {
+                diff.old_path = Some(parse_quoted(from)?.0);
+            } else if let Some(to) = line.strip_prefix("rename to ") {
+                diff.new_path = Some(parse_quoted(to)?.0);
+            } else if line.starts_with("copy from ") || line.starts_with("copy to ") {
+                bail!("Copied files are not supported in diffs: {}", line);
+            }
+        }
+
+        if let Some(old_line) = lines.next_if(|line| line.starts_with("--- ")) {
+            let new_line = lines
+                .next_if(|line| line.starts_with("+++ "))
+                .context("Missing +++ line after --- line in diff")?;
+            match parse_header_path(trim_line(&old_line[4..]))? {
+                Some(path) => diff.old_path = Some(path),
+                None => added = true,
+            }
+            match parse_header_path(trim_line(&new_line[4..]))? {
+                Some(path) => diff.new_path = Some(path),
+                None => removed = true,
+            }
+            while let Some(header) = lines.next_if(|line| line.starts_with("@@ ")) {
+                diff.hunks.push(parse_hunk(header, &mut lines)?);
+            }
+        } else if lines
+            .next_if(|line| line.starts_with("GIT binary patch"))
+            .is_some()
+        {
+            diff.binary = Some(parse_binary(&mut lines)?);
+        } else if lines
+            .next_if(|line| line.starts_with("Binary files "))
+            .is_some()
+        {
+            bail!(
+                "Diff holds no contents for binary file {}, it must be made with binary patches (git diff --binary)",
+                diff.new_path.as_deref().unwrap_or_default()
+            );
+        }
+
+        if added {
+            diff.old_path = None;
+        }
+        if removed {
+            diff.new_path = None;
+        }
+        diffs.push(diff);
+    }
+
+    Ok(diffs)
+}
+
+/// Works out the new contents of the file changed by `diff` in the tree at `base_path`, `None`
+/// if it is removed.
+fn new_contents(base_path: &Path, diff: &FileDiff) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(new_path) = &diff.new_path else {
+        return Ok(None);
+    };
+    let old_contents = match &diff.old_path {
+        Some(old_path) => {
+            let old_file = resolve_entry_path(base_path, old_path)?;
+            read(&old_file)
+                .with_context(|| format!("Failed to read file: {}", old_file.display()))?
+        }
+        None => {
+            let new_file = resolve_entry_path(base_path, new_path)?;
+            ensure!(
+                symlink_metadata(&new_file).is_err(),
+                "File added by the diff already exists: {}",
+                new_file.display()
+            );
+            Vec::new()
+        }
+    };
+
+    match &diff.binary {
+        Some(BinaryPatch::Literal(contents)) => return Ok(Some(contents.clone())),
+        Some(BinaryPatch::Delta(delta)) => return apply_git_delta(&old_contents, delta).map(Some),
+        None => {}
+    }
+    if diff.hunks.is_empty() {
+        return Ok(Some(old_contents));
+    }
+
+    let text_hunks = TextHunks {
+        granularity: Granularity::Lines,
+        hunks: diff.hunks.clone(),
+    };
+    let (contents, statuses) = text_hunks.apply(&old_contents)?;
+    for (i, status) in statuses.iter().enumerate() {
+        ensure!(
+            matches!(
+                status,
+                HunkStatus::Applied { fuzz: 0 } | HunkStatus::Offset { fuzz: 0, .. }
+            ),
+            "Hunk {} of {} does not apply to the base tree",
+            i + 1,
+            new_path
+        );
+    }
+    Ok(Some(contents))
+}
+
+/// Writes the entry for the changes `diff` makes to the tree at `base_path`.
+fn write_file_entry(
+    writer: &mut PatchWriter<impl Write>,
+    base_path: &Path,
+    diff: &FileDiff,
+    options: &CreateOptions,
+) -> anyhow::Result<()> {
+    let contents = new_contents(base_path, diff)?;
+    let metadata = diff
+        .new_mode
+        .filter(|_| options.metadata)
+        .map(|mode| FileMetadata {
+            mode: Some(mode),
+            mtime: None,
+        });
+
+    match (&diff.old_path, &diff.new_path, contents) {
+        (None, Some(new_path), Some(contents)) => {
+            writer.write_entry(
+                &PatchEntry::new(
+                    PatchOperation::Add {
+                        hash: hash(&contents),
+                    },
+                    new_path.clone(),
+                )
+                .with_metadata(metadata),
+            )?;
+            let strategy = options.strategy_for(Path::new(new_path), contents.len() as u64);
+            write_data_chunks(
+                writer,
+                &mut &contents[..],
+                Path::new(new_path),
+                strategy.compression,
+            )?;
+        }
+        (Some(old_path), None, _) => {
+            let old_file = resolve_entry_path(base_path, old_path)?;
+            writer.write_entry(&PatchEntry::new(PatchOperation::Remove, old_path.clone()))?;
+
+            if options.reversible {
+                writer.write_inverse(
+                    &hash_file(&old_file)?,
+                    recorded_metadata(&DirTree::new(base_path), Path::new(old_path), options)?
+                        .as_ref(),
+                )?;
+                let strategy = options.strategy_for(Path::new(old_path), file_size(&old_file)?);
+                write_data_chunks(
+                    writer,
+                    &mut open_file(&old_file)?,
+                    &old_file,
+                    strategy.compression,
+                )?;
+            }
+        }
+        (Some(old_path), Some(new_path), Some(contents)) => {
+            let old_file = resolve_entry_path(base_path, old_path)?;
+            let before_hash = hash_file(&old_file)?;
+            let after_hash = hash(&contents);
+            let contents_changed = before_hash != after_hash;
+            if old_path == new_path && !contents_changed && metadata.is_none() {
+                // Nothing changes
+                return Ok(());
+            }
+
+            let operation = if old_path == new_path {
+                PatchOperation::Modify {
+                    before_hash: before_hash.clone(),
+                    after_hash,
+                }
+            } else {
+                PatchOperation::Rename {
+                    from: old_path.clone(),
+                    before_hash: before_hash.clone(),
+                    after_hash,
+                }
+            };
+            let text_hunks = if options.text_hunks && contents_changed && old_path == new_path {
+                TextHunks::diff(&read(&old_file)?, &contents)
+            } else {
+                None
+            };
+            writer.write_entry(
+                &PatchEntry::new(operation, new_path.clone())
+                    .with_metadata(metadata)
+                    .with_text_hunks(text_hunks),
+            )?;
+
+            let strategy = options.strategy_for(Path::new(new_path), contents.len() as u64);
+            if contents_changed {
+                write_chunk_deltas(
+                    writer,
+                    &mut open_file(&old_file)?,
+                    &mut &contents[..],
+                    Path::new(new_path),
+                    strategy,
+                    PatchWriter::write_delta,
+                )?;
+            }
+            if options.reversible {
+                writer.write_inverse(
+                    &before_hash,
+                    recorded_metadata(&DirTree::new(base_path), Path::new(old_path), options)?
+                        .as_ref(),
+                )?;
+                if contents_changed {
+                    write_chunk_deltas(
+                        writer,
+                        &mut &contents[..],
+                        &mut open_file(&old_file)?,
+                        Path::new(new_path),
+                        strategy,
+                        PatchWriter::write_reverse_delta,
+                    )?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Writes the entries for the directories that appear or empty out when `diffs` are applied to
+/// the tree at `base_path`. Directories that were empty before are left alone.
+fn write_dir_entries(
+    writer: &mut PatchWriter<impl Write>,
+    base_path: &Path,
+    diffs: &[FileDiff],
+) -> anyhow::Result<()> {
+    let listing = ExcludeRules::none().list(&DirTree::new(base_path))?;
+    let base_files: HashSet<PathBuf> = listing.files.into_iter().collect();
+    let base_dirs: HashSet<PathBuf> = listing.dirs.into_iter().collect();
+
+    let mut files = base_files.clone();
+    for diff in diffs {
+        if let Some(old_path) = &diff.old_path {
+            files.remove(Path::new(old_path));
+        }
+        if let Some(new_path) = &diff.new_path {
+            files.insert(PathBuf::from(new_path));
+        }
+    }
+
+    let parents = |paths: &mut dyn Iterator<Item = &Path>| -> HashSet<PathBuf> {
+        paths
+            .flat_map(Path::ancestors)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .collect()
+    };
+    let with_base_files = parents(&mut base_files.iter().filter_map(|file| file.parent()));
+    let mut dirs = parents(&mut files.iter().filter_map(|file| file.parent()));
+    dirs.extend(parents(
+        &mut base_dirs
+            .iter()
+            .filter(|dir| !with_base_files.contains(*dir))
+            .map(PathBuf::as_path),
+    ));
+
+    let mut added: Vec<_> = dirs.difference(&base_dirs).collect();
+    added.sort();
+    for rel_path in added {
+        writer.write_entry(&PatchEntry::new(
+            PatchOperation::AddDir,
+            rel_path.to_string_lossy().to_string(),
+        ))?;
+    }
+
+    let mut removed: Vec<_> = base_dirs.difference(&dirs).collect();
+    removed.sort();
+    for rel_path in removed {
+        writer.write_entry(&PatchEntry::new(
+            PatchOperation::RemoveDir,
+            rel_path.to_string_lossy().to_string(),
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Turns a git style unified diff against the tree at `base_path` into a patch package, so
+/// changes made with git can be shipped like any other patch.
+///
+/// Text hunks must apply to the base tree without fuzz, and binary files need their contents in
+/// the diff (`git diff --binary`). Copies and symlinks are not supported.
+pub fn import_unified_diff(
+    diff_loc: &Path,
+    base_path: &Path,
+    patch_loc: &Path,
+    options: &CreateOptions,
+) -> PatchResult<()> {
+    let text = read_to_string(diff_loc)
+        .with_context(|| format!("Failed to read diff file: {}", diff_loc.display()))?;
+    let diffs = parse_diff(&text)
+        .with_context(|| format!("Failed to parse diff file: {}", diff_loc.display()))?;
+    if diffs.is_empty() {
+        return Err(anyhow!("No file changes found in diff file: {}", diff_loc.display()).into());
+    }
+
+    let info = PackageInfo::new(&DirTree::new(base_path), options)?;
+    write_patch_file(patch_loc, &info, CHUNK_SIZE, |writer| {
+        for diff in &diffs {
+            write_file_entry(writer, base_path, diff, options)?;
+        }
+        write_dir_entries(writer, base_path, &diffs)
+    })?;
+    Ok(())
+}
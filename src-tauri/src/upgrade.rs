@@ -0,0 +1,141 @@
+//! Rewriting patch packages from older versions in the current format.
+
+use std::{
+    fs::{remove_file, rename},
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use files_diff::Patch;
+
+use crate::{
+    error::PatchResult,
+    info::PackageInfo,
+    open_patch,
+    stream::{ChunkChange, PatchReader, PatchWriter, Payload},
+    write_patch_file, DiffStrategy, PatchOperation, CHUNK_SIZE, MAX_CHUNK_SIZE,
+    PATCH_PACKAGE_VERSION,
+};
+
+/// What [`upgrade_patch`] did to a package.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchUpgrade {
+    pub from_version: u32, // Version the package was written in
+    pub to_version: u32,   // Version it was rewritten in
+    pub was_signed: bool,  // Whether the old package carried a signature, which doesn't carry over
+}
+
+/// Copies every entry of `reader` with all of its chunks and revert data to `writer`.
+/// File contents are compressed and split to the current chunk size, chunk changes of modified
+/// files are kept as they are.
+fn copy_entries(
+    reader: &mut PatchReader<impl Read>,
+    writer: &mut PatchWriter<impl Write>,
+) -> anyhow::Result<()> {
+    while let Some(entry) = reader.next_entry()? {
+        writer.write_entry(&entry)?;
+        match entry.operation {
+            PatchOperation::Add { .. } => copy_data(reader, writer)?,
+            _ => copy_changes(
+                reader,
+                writer,
+                PatchReader::next_change,
+                PatchWriter::write_delta,
+            )?,
+        }
+
+        if let Some((hash, metadata)) = reader.next_inverse()? {
+            writer.write_inverse(&hash, metadata.as_ref())?;
+            match entry.operation {
+                PatchOperation::Remove => copy_data(reader, writer)?,
+                _ => copy_changes(
+                    reader,
+                    writer,
+                    PatchReader::next_reverse_change,
+                    PatchWriter::write_reverse_delta,
+                )?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies the data chunks of the current entry, splitting the whole files held by version 1
+/// packages into chunks that fit in a frame.
+fn copy_data(
+    reader: &mut PatchReader<impl Read>,
+    writer: &mut PatchWriter<impl Write>,
+) -> anyhow::Result<()> {
+    let compression = DiffStrategy::default().compression;
+    while let Some(data) = reader.next_data()? {
+        for chunk in data.chunks(CHUNK_SIZE as usize) {
+            writer.write_data(chunk, compression)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the chunk changes returned by `next_change`, patches with `write`.
+fn copy_changes<R: Read, W: Write>(
+    reader: &mut PatchReader<R>,
+    writer: &mut PatchWriter<W>,
+    next_change: fn(&mut PatchReader<R>) -> anyhow::Result<Option<ChunkChange<Payload>>>,
+    write: fn(&mut PatchWriter<W>, &Patch) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let compression = DiffStrategy::default().compression;
+    while let Some(change) = next_change(reader)? {
+        match change {
+            ChunkChange::Delta(patch) => write(writer, &patch)?,
+            ChunkChange::Replace(chunk) => writer.write_data(&chunk, compression)?,
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the patch package at `patch_loc` in the current format and writes it to `out_loc`,
+/// which may be `patch_loc` itself.
+///
+/// Every entry is carried over as it is, with file contents compressed like in new packages.
+/// Packages from before package info was recorded get info without a base tree hash, so the
+/// target isn't checked against it when they are applied. Packages older than version 6 didn't
+/// record directories and had directories emptied by removed files pruned instead, their info
+/// marks them so they keep doing that. Version 1 packages hold whole file diffs, which keep
+/// applying to files of up to 256 MiB. Packages from before version 10 gain a checksum.
+///
+/// The upgraded package is not signed, even if the old one was (see [`crate::sign_patch`]).
+pub fn upgrade_patch(patch_loc: &Path, out_loc: &Path) -> PatchResult<PatchUpgrade> {
+    let mut reader = open_patch(patch_loc)?;
+    let from_version = reader.header().version;
+    // Version 1 packages read as a single chunk per file
+    let chunk_size = reader.header().chunk_size.min(MAX_CHUNK_SIZE);
+    let mut info = reader.info().cloned().unwrap_or_else(PackageInfo::unknown);
+    info.prune_empty_dirs = reader.prunes_empty_dirs();
+
+    // Write next to the output first, so upgrading in place never loses the old package
+    let mut tmp_name = out_loc.as_os_str().to_owned();
+    tmp_name.push(".upgrade");
+    let tmp_loc = Path::new(&tmp_name);
+    write_patch_file(tmp_loc, &info, chunk_size, |writer| {
+        copy_entries(&mut reader, writer)
+    })
+    .with_context(|| format!("Failed to upgrade patch file: {}", patch_loc.display()))?;
+
+    let was_signed = reader
+        .trailer()
+        .is_some_and(|trailer| trailer.signature.is_some());
+    drop(reader);
+
+    if let Err(e) = rename(tmp_loc, out_loc) {
+        let _ = remove_file(tmp_loc);
+        return Err(anyhow::Error::new(e)
+            .context(format!("Failed to write patch file: {}", out_loc.display()))
+            .into());
+    }
+
+    Ok(PatchUpgrade {
+        from_version,
+        to_version: PATCH_PACKAGE_VERSION,
+        was_signed,
+    })
+}
@@ -0,0 +1,142 @@
+//! Checking a patch package for corruption without applying it.
+
+use std::{
+    io::{self, sink, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use files_diff::CompressAlgorithm;
+
+use crate::{
+    error::{at_entry, PatchError, PatchResult},
+    open_patch,
+    report::VerifyReport,
+    signing::check_own_signature,
+    stream::{ChunkChange, PatchReader, Payload},
+    transaction::TRANSACTION_DIR,
+    PatchEntry, PatchOperation,
+};
+
+/// Checks that an entry path is relative and stays inside the directory the package is applied
+/// to, outside of the transaction directory. Returns the path without `.` and `..` components.
+pub(crate) fn check_rel_path(rel_path: &str) -> anyhow::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(rel_path).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => ensure!(
+                normalized.pop(),
+                PatchError::PathEscape {
+                    path: rel_path.to_string(),
+                    entry: None,
+                }
+            ),
+            Component::RootDir | Component::Prefix(_) => bail!(PatchError::PathEscape {
+                path: rel_path.to_string(),
+                entry: None,
+            }),
+        }
+    }
+
+    ensure!(
+        normalized.components().next().is_some(),
+        "Patch entry path {:?} doesn't name anything inside the target directory",
+        rel_path
+    );
+    ensure!(
+        !normalized.starts_with(TRANSACTION_DIR),
+        "Patch entry path {} points into the transaction directory",
+        rel_path
+    );
+    Ok(normalized)
+}
+
+/// Reads the data chunks of the current entry and checks that they hash to `expected_hash`.
+fn check_data(
+    reader: &mut PatchReader<impl Read>,
+    rel_path: &str,
+    expected_hash: &str,
+) -> anyhow::Result<()> {
+    let mut context = md5::Context::new();
+    while let Some(data) = reader.next_data()? {
+        context.consume(&*data);
+    }
+    let actual = format!("{:x}", context.compute());
+    ensure!(
+        actual == expected_hash,
+        PatchError::CorruptData {
+            path: PathBuf::from(rel_path),
+            expected: expected_hash.to_string(),
+            actual,
+            entry: None,
+        }
+    );
+    Ok(())
+}
+
+/// Reads the chunk changes returned by `next_change` and checks that every patch decompresses.
+fn check_changes<R: Read>(
+    reader: &mut PatchReader<R>,
+    next_change: fn(&mut PatchReader<R>) -> anyhow::Result<Option<ChunkChange<Payload>>>,
+    rel_path: &str,
+) -> anyhow::Result<()> {
+    while let Some(change) = next_change(reader)? {
+        if let ChunkChange::Delta(patch) = change {
+            if patch.compress_algorithm == CompressAlgorithm::Zstd {
+                zstd::Decoder::new(&patch.patch[..])
+                    .and_then(|mut decoder| io::copy(&mut decoder, &mut sink()))
+                    .with_context(|| format!("Failed to decompress a patch for {}", rel_path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the path and the stored contents of `entry`, the entry `reader` is at.
+fn check_entry(reader: &mut PatchReader<impl Read>, entry: &PatchEntry) -> anyhow::Result<()> {
+    check_rel_path(&entry.rel_path)?;
+    match &entry.operation {
+        PatchOperation::Add { hash } => check_data(reader, &entry.rel_path, hash)?,
+        PatchOperation::Rename { from, .. } => {
+            check_rel_path(from)?;
+            check_changes(reader, PatchReader::next_change, &entry.rel_path)?;
+        }
+        _ => check_changes(reader, PatchReader::next_change, &entry.rel_path)?,
+    }
+
+    if let Some((hash, _)) = reader.next_inverse()? {
+        match entry.operation {
+            PatchOperation::Remove => check_data(reader, &entry.rel_path, &hash)?,
+            _ => check_changes(reader, PatchReader::next_reverse_change, &entry.rel_path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Checks the patch package at `patch_loc` from start to end without touching any files, so a
+/// corrupted or truncated download is caught before it partly modifies a target directory.
+///
+/// Fails unless the package is in a supported version, every frame in it is well formed, every
+/// entry path is relative and stays inside the target directory, every stored patch decompresses
+/// and the contents of added and removed files match their hashes. Packages since version 10
+/// must also match the checksum over all of their bytes. A signed package must carry a valid
+/// signature for the key it was signed with, whether that key is trusted or not.
+pub fn verify_patch(patch_loc: &Path) -> PatchResult<VerifyReport> {
+    let mut reader = open_patch(patch_loc)?;
+    let mut entries = 0;
+
+    while let Some(entry) = reader.next_entry()? {
+        check_entry(&mut reader, &entry).map_err(|e| at_entry(e, entries as usize))?;
+        entries += 1;
+    }
+
+    let trailer = reader.trailer().expect("package was read to the end");
+    Ok(VerifyReport {
+        version: reader.header().version,
+        entries,
+        checksum: trailer.checksum,
+        signed_by: check_own_signature(trailer)?,
+    })
+}